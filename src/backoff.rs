@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+// Exponential backoff used when reconnecting to Discord or D-Bus
+#[derive(Debug)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Backoff {
+            min,
+            max,
+            current: min,
+        }
+    }
+
+    // Returns the delay before the next attempt and doubles it for the one after
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    // Call after a successful attempt
+    pub fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
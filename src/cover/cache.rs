@@ -0,0 +1,1052 @@
+use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+use rusqlite::{params, Connection, OptionalExtension, Row, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::error::Error;
+
+// Bump when the tables change and add the upgrade step to "migrate_schema"
+const SCHEMA_VERSION: i64 = 4;
+
+// The cover worker, the upload provider and the cache subcommands can write at the same time,
+// wait for the other writer instead of failing with "database is locked"
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const DEFAULT_MAX_ENTRIES: usize = 10000;
+pub const DEFAULT_MISS_TTL_HOURS: u64 = 24;
+pub const DEFAULT_CHECK_INTERVAL_HOURS: u64 = 168;
+
+// Provider of covers set with "cache set", they win over every provider and never expire
+pub const MANUAL_PROVIDER: &str = "manual";
+
+// Cover found for an album and the provider that found it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedCover {
+    pub url: String,
+    pub provider: String,
+    /// Unix time of the lookup
+    #[serde(default)]
+    pub fetched_at: u64,
+    /// Unix time the link was last known to work
+    #[serde(default)]
+    pub checked_at: u64,
+}
+
+// Album without a cover and the providers that were asked
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MissingCover {
+    pub providers: Vec<String>,
+    /// Unix time of the lookup
+    pub fetched_at: u64,
+}
+
+// Format of "cache export" and "cache import"
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CacheExport {
+    #[serde(default)]
+    pub covers: BTreeMap<String, CachedCover>,
+    #[serde(default)]
+    pub missing: BTreeMap<String, MissingCover>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheOptions {
+    pub max_entries: usize,
+    /// Seconds until a found cover is looked up again, None keeps it until it's evicted
+    pub hit_ttl: Option<u64>,
+    /// Seconds until an album without a cover is looked up again
+    pub miss_ttl: u64,
+    /// Seconds until the link of a found cover is checked again, None never checks it
+    pub check_interval: Option<u64>,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions {
+            max_entries: DEFAULT_MAX_ENTRIES,
+            hit_ttl: None,
+            miss_ttl: DEFAULT_MISS_TTL_HOURS * 3600,
+            check_interval: Some(DEFAULT_CHECK_INTERVAL_HOURS * 3600),
+        }
+    }
+}
+
+// Album covers saved on disk, keyed by "<album artist> - <album>". Albums without a cover are
+// saved too, with the providers that were asked. Expired entries are never returned and the
+// least recently used albums are removed when there are more than "max_entries" of them.
+pub struct CoverCache {
+    db: Option<Connection>,
+    options: CacheOptions,
+    path: PathBuf,
+}
+
+impl CoverCache {
+    pub fn disabled() -> Self {
+        CoverCache {
+            db: None,
+            options: CacheOptions::default(),
+            path: PathBuf::new(),
+        }
+    }
+
+    // Errors are printed and the cache is disabled, covers are then fetched every time
+    pub fn open(cache_dir: &Path, options: CacheOptions) -> Self {
+        match Self::load(cache_dir, options) {
+            Ok(cache) => {
+                println!("Cache loaded from file: {}", cache.path.display());
+                cache
+            }
+            Err(err) => {
+                println!("[cache] error, unable to open cache file: {}", err);
+                Self::disabled()
+            }
+        }
+    }
+
+    pub fn load(cache_dir: &Path, options: CacheOptions) -> Result<Self, Error> {
+        fs::create_dir_all(cache_dir)
+            .map_err(|err| Error::Cache(format!("could not create cache directory: {}", err)))?;
+
+        let path = cache_dir.join("cover_cache.sqlite");
+        let db = open_db(&path)?;
+        let mut cache = CoverCache {
+            db: Some(db),
+            options,
+            path,
+        };
+        cache.migrate_pickledb(&cache_dir.join("album_cache.db"));
+        cache.migrate_uploads(&cache_dir.join("upload_cache.db"));
+        Ok(cache)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn options(&self) -> CacheOptions {
+        self.options
+    }
+
+    pub fn cover_expired(&self, cover: &CachedCover) -> bool {
+        cover.provider != MANUAL_PROVIDER && expired(cover.fetched_at, self.options.hit_ttl)
+    }
+
+    pub fn missing_expired(&self, missing: &MissingCover) -> bool {
+        expired(missing.fetched_at, Some(self.options.miss_ttl))
+    }
+
+    // Manual covers are only checked by "cache verify"
+    pub fn check_due(&self, cover: &CachedCover) -> bool {
+        cover.provider != MANUAL_PROVIDER && expired(cover.checked_at, self.options.check_interval)
+    }
+
+    pub fn get(&self, album_id: &str) -> Option<CachedCover> {
+        let db = self.db.as_ref()?;
+        let result = db
+            .query_row(
+                "SELECT url, provider, fetched_at, checked_at FROM covers WHERE album_id = ?1",
+                params![album_id],
+                |row| {
+                    Ok(CachedCover {
+                        url: row.get(0)?,
+                        provider: row.get(1)?,
+                        fetched_at: row.get(2)?,
+                        checked_at: row.get(3)?,
+                    })
+                },
+            )
+            .optional()
+            .and_then(|cover| match cover {
+                Some(cover) if self.cover_expired(&cover) => {
+                    println!("[cache] image url expired for: {}.", album_id);
+                    db.execute("DELETE FROM covers WHERE album_id = ?1", params![album_id])?;
+                    Ok(None)
+                }
+                Some(cover) => {
+                    db.execute(
+                        "UPDATE covers SET used_at = ?1 WHERE album_id = ?2",
+                        params![unix_time(), album_id],
+                    )?;
+                    Ok(Some(cover))
+                }
+                None => Ok(None),
+            });
+
+        match result {
+            Ok(cover) => cover,
+            Err(err) => {
+                println!(
+                    "[cache] error, unable to read cache file: {}",
+                    Error::from(err)
+                );
+                None
+            }
+        }
+    }
+
+    // Providers that had no cover for the album the last time they were asked
+    pub fn get_missing(&self, album_id: &str) -> Vec<String> {
+        let db = match &self.db {
+            Some(db) => db,
+            None => return Vec::new(),
+        };
+        let result = db
+            .query_row(
+                "SELECT providers, fetched_at FROM missing_covers WHERE album_id = ?1",
+                params![album_id],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?)),
+            )
+            .optional()
+            .and_then(|missing| match missing {
+                Some((_, fetched_at)) if expired(fetched_at, Some(self.options.miss_ttl)) => {
+                    db.execute(
+                        "DELETE FROM missing_covers WHERE album_id = ?1",
+                        params![album_id],
+                    )?;
+                    Ok(None)
+                }
+                Some((providers, _)) => {
+                    db.execute(
+                        "UPDATE missing_covers SET used_at = ?1 WHERE album_id = ?2",
+                        params![unix_time(), album_id],
+                    )?;
+                    Ok(Some(providers))
+                }
+                None => Ok(None),
+            });
+
+        match result {
+            Ok(providers) => providers
+                .unwrap_or_default()
+                .split(',')
+                .filter(|provider| !provider.is_empty())
+                .map(String::from)
+                .collect(),
+            Err(err) => {
+                println!(
+                    "[cache] error, unable to read cache file: {}",
+                    Error::from(err)
+                );
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn set(&mut self, album_id: &str, url: &str, provider: &str) {
+        let cover = CachedCover {
+            url: url.to_string(),
+            provider: provider.to_string(),
+            fetched_at: unix_time(),
+            checked_at: unix_time(),
+        };
+        match self.insert(album_id, &cover) {
+            Ok(_) => println!("[cache] saved image url for: {}.", album_id),
+            Err(err) => println!("[cache] error, unable to write to cache file: {}", err),
+        }
+    }
+
+    pub fn set_missing(&mut self, album_id: &str, providers: &[&str]) {
+        let db = match &self.db {
+            Some(db) => db,
+            None => return,
+        };
+
+        let result = db
+            .execute(
+                "INSERT OR REPLACE INTO missing_covers (album_id, providers, fetched_at, used_at)
+                 VALUES (?1, ?2, ?3, ?3)",
+                params![album_id, providers.join(","), unix_time()],
+            )
+            .map_err(Error::from)
+            .and_then(|_| self.evict());
+        match result {
+            Ok(_) => println!("[cache] saved missing cover for: {}.", album_id),
+            Err(err) => println!("[cache] error, unable to write to cache file: {}", err),
+        }
+    }
+
+    fn insert(&mut self, album_id: &str, cover: &CachedCover) -> Result<(), Error> {
+        let db = match &self.db {
+            Some(db) => db,
+            None => return Ok(()),
+        };
+
+        db.execute(
+            "INSERT OR REPLACE INTO covers (album_id, url, provider, fetched_at, used_at, checked_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                album_id,
+                cover.url,
+                cover.provider,
+                cover.fetched_at,
+                unix_time(),
+                cover.checked_at
+            ],
+        )?;
+        db.execute(
+            "DELETE FROM missing_covers WHERE album_id = ?1",
+            params![album_id],
+        )?;
+        self.evict()
+    }
+
+    // Least recently used albums are removed first, found and missing covers count together.
+    // Manual covers are never removed.
+    fn evict(&self) -> Result<(), Error> {
+        let db = match &self.db {
+            Some(db) => db,
+            None => return Ok(()),
+        };
+
+        let recently_used = "SELECT album_id FROM
+            (SELECT album_id, used_at FROM covers UNION ALL SELECT album_id, used_at FROM missing_covers)
+            ORDER BY used_at DESC LIMIT ?1";
+        db.execute(
+            &format!(
+                "DELETE FROM covers WHERE provider != ?2 AND album_id NOT IN ({})",
+                recently_used
+            ),
+            params![self.options.max_entries as i64, MANUAL_PROVIDER],
+        )?;
+        db.execute(
+            &format!(
+                "DELETE FROM missing_covers WHERE album_id NOT IN ({})",
+                recently_used
+            ),
+            params![self.options.max_entries as i64],
+        )?;
+        Ok(())
+    }
+
+    // Cover set by the user with "cache set"
+    pub fn set_manual(&mut self, album_id: &str, url: &str) -> Result<(), Error> {
+        self.insert(
+            album_id,
+            &CachedCover {
+                url: url.to_string(),
+                provider: MANUAL_PROVIDER.to_string(),
+                fetched_at: unix_time(),
+                checked_at: unix_time(),
+            },
+        )
+    }
+
+    // Link of the cover still works
+    pub fn set_checked(&self, album_id: &str) -> Result<(), Error> {
+        self.connection()?.execute(
+            "UPDATE covers SET checked_at = ?1 WHERE album_id = ?2",
+            params![unix_time(), album_id],
+        )?;
+        Ok(())
+    }
+
+    // Link of a local cover uploaded by the "upload" provider, by the SHA-256 of the image
+    pub fn get_upload(&self, hash: &str) -> Option<String> {
+        let db = self.db.as_ref()?;
+        let result = db
+            .query_row(
+                "SELECT url FROM uploads WHERE hash = ?1",
+                params![hash],
+                |row| row.get(0),
+            )
+            .optional();
+        match result {
+            Ok(url) => url,
+            Err(err) => {
+                println!(
+                    "[cache] error, unable to read cache file: {}",
+                    Error::from(err)
+                );
+                None
+            }
+        }
+    }
+
+    pub fn set_upload(&self, hash: &str, url: &str) -> Result<(), Error> {
+        let db = match &self.db {
+            Some(db) => db,
+            None => return Ok(()),
+        };
+        db.execute(
+            "INSERT OR REPLACE INTO uploads (hash, url, uploaded_at) VALUES (?1, ?2, ?3)",
+            params![hash, url, unix_time()],
+        )?;
+        Ok(())
+    }
+
+    pub fn upload_count(&self) -> Result<usize, Error> {
+        let count: i64 =
+            self.connection()?
+                .query_row("SELECT COUNT(*) FROM uploads", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    // Image hosts like 0x0.st remove files after a while, old links are uploaded again
+    pub fn purge_uploads(&mut self, older_than: u64) -> Result<usize, Error> {
+        let uploaded_before = unix_time().saturating_sub(older_than).min(i64::MAX as u64) as i64;
+        Ok(self.connection()?.execute(
+            "DELETE FROM uploads WHERE uploaded_at <= ?1",
+            params![uploaded_before],
+        )?)
+    }
+
+    // Saved cover of one album, including an expired one. Unlike get() it doesn't count as a use.
+    pub fn cover(&self, album_id: &str) -> Result<Option<CachedCover>, Error> {
+        Ok(self
+            .connection()?
+            .query_row(
+                "SELECT album_id, url, provider, fetched_at, checked_at FROM covers
+                 WHERE album_id = ?1",
+                params![album_id],
+                cover_from_row,
+            )
+            .optional()?
+            .map(|(_, cover)| cover))
+    }
+
+    // Saved album without a cover, including an expired one
+    pub fn missing_cover(&self, album_id: &str) -> Result<Option<MissingCover>, Error> {
+        Ok(self
+            .connection()?
+            .query_row(
+                "SELECT album_id, providers, fetched_at FROM missing_covers WHERE album_id = ?1",
+                params![album_id],
+                missing_from_row,
+            )
+            .optional()?
+            .map(|(_, missing)| missing))
+    }
+
+    // All saved covers, including expired ones
+    pub fn covers(&self) -> Result<BTreeMap<String, CachedCover>, Error> {
+        let db = self.connection()?;
+        let mut statement =
+            db.prepare("SELECT album_id, url, provider, fetched_at, checked_at FROM covers")?;
+        let rows = statement.query_map([], cover_from_row)?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    // All saved albums without a cover, including expired ones
+    pub fn missing_covers(&self) -> Result<BTreeMap<String, MissingCover>, Error> {
+        let db = self.connection()?;
+        let mut statement =
+            db.prepare("SELECT album_id, providers, fetched_at FROM missing_covers")?;
+        let rows = statement.query_map([], missing_from_row)?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    // Returns false if the album was not in the cache
+    pub fn delete(&mut self, album_id: &str) -> Result<bool, Error> {
+        let db = self.connection()?;
+        let deleted = db.execute("DELETE FROM covers WHERE album_id = ?1", params![album_id])?
+            + db.execute(
+                "DELETE FROM missing_covers WHERE album_id = ?1",
+                params![album_id],
+            )?;
+        Ok(deleted > 0)
+    }
+
+    // Removes albums without a cover ("missing") and/or entries fetched more than "older_than"
+    // seconds ago. Manual covers are only removed with "delete". Returns the number of removed albums.
+    pub fn purge(&mut self, missing: bool, older_than: Option<u64>) -> Result<usize, Error> {
+        let db = self.connection()?;
+        let fetched_before = match older_than {
+            Some(older_than) => unix_time().saturating_sub(older_than),
+            None => u64::MAX,
+        };
+        let fetched_before = fetched_before.min(i64::MAX as u64) as i64;
+
+        let mut purged = db.execute(
+            "DELETE FROM missing_covers WHERE fetched_at <= ?1",
+            params![fetched_before],
+        )?;
+        if !missing {
+            purged += db.execute(
+                "DELETE FROM covers WHERE fetched_at <= ?1 AND provider != ?2",
+                params![fetched_before, MANUAL_PROVIDER],
+            )?;
+        }
+        Ok(purged)
+    }
+
+    // Entries from the file replace the saved ones, returns the number of imported albums
+    pub fn import(&mut self, data: &CacheExport) -> Result<usize, Error> {
+        let db = self.connection()?;
+        let transaction = db.unchecked_transaction()?;
+        let now = unix_time();
+        for (album_id, cover) in &data.covers {
+            transaction.execute(
+                "INSERT OR REPLACE INTO covers (album_id, url, provider, fetched_at, used_at, checked_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    album_id,
+                    cover.url,
+                    cover.provider,
+                    cover.fetched_at,
+                    now,
+                    cover.checked_at
+                ],
+            )?;
+            transaction.execute(
+                "DELETE FROM missing_covers WHERE album_id = ?1",
+                params![album_id],
+            )?;
+        }
+        for (album_id, missing) in &data.missing {
+            // A found cover is worth more than a miss
+            if data.covers.contains_key(album_id) {
+                continue;
+            }
+            transaction.execute(
+                "INSERT OR REPLACE INTO missing_covers (album_id, providers, fetched_at, used_at)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    album_id,
+                    missing.providers.join(","),
+                    missing.fetched_at,
+                    now
+                ],
+            )?;
+            transaction.execute("DELETE FROM covers WHERE album_id = ?1", params![album_id])?;
+        }
+        transaction.commit()?;
+        self.evict()?;
+        Ok(data.covers.len() + data.missing.len())
+    }
+
+    pub fn export(&self) -> Result<CacheExport, Error> {
+        Ok(CacheExport {
+            covers: self.covers()?,
+            missing: self.missing_covers()?,
+        })
+    }
+
+    fn connection(&self) -> Result<&Connection, Error> {
+        self.db
+            .as_ref()
+            .ok_or_else(|| Error::Cache(String::from("cache is disabled")))
+    }
+
+    // Older versions kept the cache in a JSON file that was rewritten on every change.
+    // Its entries are copied once and the old file is renamed, so it's not imported again.
+    fn migrate_pickledb(&mut self, old_path: &Path) {
+        if self.db.is_none() || !old_path.exists() {
+            return;
+        }
+
+        let old_db = match PickleDb::load(
+            old_path,
+            PickleDbDumpPolicy::NeverDump,
+            SerializationMethod::Json,
+        ) {
+            Ok(old_db) => old_db,
+            Err(err) => {
+                println!(
+                    "[cache] could not read old cache file {}: {}",
+                    old_path.display(),
+                    Error::from(err)
+                );
+                return;
+            }
+        };
+
+        let mut migrated = 0;
+        for entry in old_db.iter() {
+            let cover = match entry.get_value::<CachedCover>() {
+                Some(cover) => CachedCover {
+                    fetched_at: unix_time(),
+                    checked_at: unix_time(),
+                    ..cover
+                },
+                // Older versions only saved the Last.fm url
+                None => match entry.get_value::<String>() {
+                    Some(url) if url.len() > 5 => CachedCover {
+                        url,
+                        provider: String::from("lastfm"),
+                        fetched_at: unix_time(),
+                        checked_at: unix_time(),
+                    },
+                    _ => continue,
+                },
+            };
+            match self.insert(entry.get_key(), &cover) {
+                Ok(_) => migrated += 1,
+                Err(err) => {
+                    println!("[cache] error, unable to migrate old cache file: {}", err);
+                    return;
+                }
+            }
+        }
+
+        let backup_path = old_path.with_extension("db.migrated");
+        if let Err(err) = fs::rename(old_path, &backup_path) {
+            println!(
+                "[cache] could not rename old cache file {}: {}",
+                old_path.display(),
+                err
+            );
+        }
+        println!(
+            "[cache] migrated {} albums from {}",
+            migrated,
+            old_path.display()
+        );
+    }
+
+    // Links of uploaded covers were kept in their own JSON file, moved the same way
+    fn migrate_uploads(&mut self, old_path: &Path) {
+        if self.db.is_none() || !old_path.exists() {
+            return;
+        }
+
+        let old_db = match PickleDb::load(
+            old_path,
+            PickleDbDumpPolicy::NeverDump,
+            SerializationMethod::Json,
+        ) {
+            Ok(old_db) => old_db,
+            Err(err) => {
+                println!(
+                    "[cache] could not read old upload cache file {}: {}",
+                    old_path.display(),
+                    Error::from(err)
+                );
+                return;
+            }
+        };
+
+        let mut migrated = 0;
+        for entry in old_db.iter() {
+            let Some(url) = entry.get_value::<String>() else {
+                continue;
+            };
+            if let Err(err) = self.set_upload(entry.get_key(), &url) {
+                println!(
+                    "[cache] error, unable to migrate old upload cache file: {}",
+                    err
+                );
+                return;
+            }
+            migrated += 1;
+        }
+
+        let backup_path = old_path.with_extension("db.migrated");
+        if let Err(err) = fs::rename(old_path, &backup_path) {
+            println!(
+                "[cache] could not rename old upload cache file {}: {}",
+                old_path.display(),
+                err
+            );
+        }
+        println!(
+            "[cache] migrated {} uploaded covers from {}",
+            migrated,
+            old_path.display()
+        );
+    }
+}
+
+fn open_db(db_path: &Path) -> Result<Connection, Error> {
+    let mut db = Connection::open(db_path)?;
+    db.busy_timeout(BUSY_TIMEOUT)?;
+    migrate_schema(&mut db)?;
+    Ok(db)
+}
+
+// The schema version is kept in SQLite's "user_version", 0 is a new file. All steps and the new
+// version are committed at once, an interrupted upgrade is rolled back and runs again on the next
+// start. The write lock is taken before the version is read, so the daemon and a "cache"
+// subcommand never upgrade the same file twice.
+fn migrate_schema(db: &mut Connection) -> Result<(), Error> {
+    let version: i64 = db.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    let db = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let version: i64 = db.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > SCHEMA_VERSION {
+        return Err(Error::Cache(format!(
+            "cache file was created by a newer version (schema {}), delete it to continue",
+            version
+        )));
+    }
+
+    if version < 1 {
+        db.execute_batch(
+            "CREATE TABLE covers (
+                album_id TEXT PRIMARY KEY NOT NULL,
+                url TEXT NOT NULL,
+                provider TEXT NOT NULL,
+                fetched_at INTEGER NOT NULL,
+                used_at INTEGER NOT NULL
+            );
+            CREATE INDEX covers_used_at ON covers (used_at);",
+        )?;
+    }
+
+    if version < 2 {
+        db.execute_batch(
+            "CREATE TABLE missing_covers (
+                album_id TEXT PRIMARY KEY NOT NULL,
+                providers TEXT NOT NULL,
+                fetched_at INTEGER NOT NULL,
+                used_at INTEGER NOT NULL
+            );
+            CREATE INDEX missing_covers_used_at ON missing_covers (used_at);",
+        )?;
+    }
+
+    // Links of the existing covers count as checked when they were found
+    if version < 3 {
+        db.execute_batch(
+            "ALTER TABLE covers ADD COLUMN checked_at INTEGER NOT NULL DEFAULT 0;
+            UPDATE covers SET checked_at = fetched_at;",
+        )?;
+    }
+
+    if version < 4 {
+        db.execute_batch(
+            "CREATE TABLE uploads (
+                hash TEXT PRIMARY KEY NOT NULL,
+                url TEXT NOT NULL,
+                uploaded_at INTEGER NOT NULL
+            );",
+        )?;
+    }
+
+    db.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    db.commit()?;
+    Ok(())
+}
+
+// Row of "SELECT album_id, url, provider, fetched_at, checked_at FROM covers"
+fn cover_from_row(row: &Row) -> rusqlite::Result<(String, CachedCover)> {
+    Ok((
+        row.get(0)?,
+        CachedCover {
+            url: row.get(1)?,
+            provider: row.get(2)?,
+            fetched_at: row.get(3)?,
+            checked_at: row.get(4)?,
+        },
+    ))
+}
+
+// Row of "SELECT album_id, providers, fetched_at FROM missing_covers"
+fn missing_from_row(row: &Row) -> rusqlite::Result<(String, MissingCover)> {
+    Ok((
+        row.get(0)?,
+        MissingCover {
+            providers: row
+                .get::<_, String>(1)?
+                .split(',')
+                .filter(|provider| !provider.is_empty())
+                .map(String::from)
+                .collect(),
+            fetched_at: row.get(2)?,
+        },
+    ))
+}
+
+fn expired(fetched_at: u64, ttl: Option<u64>) -> bool {
+    match ttl {
+        Some(ttl) => unix_time().saturating_sub(fetched_at) >= ttl,
+        None => false,
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(options: CacheOptions) -> CoverCache {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate_schema(&mut db).unwrap();
+        CoverCache {
+            db: Some(db),
+            options,
+            path: PathBuf::new(),
+        }
+    }
+
+    fn max_entries(max_entries: usize) -> CacheOptions {
+        CacheOptions {
+            max_entries,
+            ..CacheOptions::default()
+        }
+    }
+
+    #[test]
+    fn set_and_get() {
+        let mut cache = cache(max_entries(10));
+        assert_eq!(cache.get("Radiohead - OK Computer"), None);
+
+        cache.set(
+            "Radiohead - OK Computer",
+            "https://example.com/1.jpg",
+            "lastfm",
+        );
+        let cover = cache.get("Radiohead - OK Computer").unwrap();
+        assert_eq!(cover.url, "https://example.com/1.jpg");
+        assert_eq!(cover.provider, "lastfm");
+        assert!(cover.fetched_at > 0);
+
+        cache.set(
+            "Radiohead - OK Computer",
+            "https://example.com/2.jpg",
+            "musicbrainz",
+        );
+        assert_eq!(
+            cache.get("Radiohead - OK Computer").unwrap().provider,
+            "musicbrainz"
+        );
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = cache(max_entries(2));
+        let db = cache.db.as_ref().unwrap();
+        for (album_id, used_at) in [("a", 1), ("b", 2)] {
+            db.execute(
+                "INSERT INTO covers (album_id, url, provider, fetched_at, used_at)
+                 VALUES (?1, 'https://example.com', 'lastfm', 0, ?2)",
+                params![album_id, used_at],
+            )
+            .unwrap();
+        }
+
+        // "a" becomes the most recently used one
+        assert!(cache.get("a").is_some());
+        cache.set("c", "https://example.com/c.jpg", "lastfm");
+
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+    }
+
+    #[test]
+    fn missing_covers() {
+        let mut cache = cache(max_entries(10));
+        assert!(cache.get_missing("a").is_empty());
+
+        cache.set_missing("a", &["lastfm", "musicbrainz"]);
+        assert_eq!(cache.get_missing("a"), vec!["lastfm", "musicbrainz"]);
+
+        // Cover found later, e.g. by a provider added to the config
+        cache.set("a", "https://example.com/a.jpg", "upload");
+        assert!(cache.get_missing("a").is_empty());
+    }
+
+    #[test]
+    fn expired_entries() {
+        let mut cache = cache(CacheOptions {
+            hit_ttl: Some(3600),
+            miss_ttl: 60,
+            ..CacheOptions::default()
+        });
+        cache.set("a", "https://example.com/a.jpg", "lastfm");
+        cache.set_missing("b", &["lastfm"]);
+        assert!(cache.get("a").is_some());
+        assert_eq!(cache.get_missing("b"), vec!["lastfm"]);
+
+        let db = cache.db.as_ref().unwrap();
+        db.execute("UPDATE covers SET fetched_at = fetched_at - 3600", [])
+            .unwrap();
+        db.execute("UPDATE missing_covers SET fetched_at = fetched_at - 60", [])
+            .unwrap();
+        // "cache get" still shows them
+        assert_eq!(
+            cache.cover("a").unwrap().unwrap().url,
+            "https://example.com/a.jpg"
+        );
+        assert_eq!(
+            cache.missing_cover("b").unwrap().unwrap().providers,
+            vec!["lastfm"]
+        );
+        assert_eq!(cache.cover("b").unwrap(), None);
+
+        assert!(cache.get("a").is_none());
+        assert!(cache.get_missing("b").is_empty());
+        assert_eq!(cache.cover("a").unwrap(), None);
+    }
+
+    #[test]
+    fn manual_covers() {
+        let mut cache = cache(CacheOptions {
+            max_entries: 1,
+            hit_ttl: Some(0),
+            ..CacheOptions::default()
+        });
+        cache.set_manual("a", "https://example.com/a.jpg").unwrap();
+        cache.set("b", "https://example.com/b.jpg", "lastfm");
+
+        // Never expired or evicted
+        assert_eq!(cache.get("a").unwrap().provider, MANUAL_PROVIDER);
+        assert_eq!(cache.purge(false, Some(0)).unwrap(), 0);
+        assert!(cache.delete("a").unwrap());
+        assert!(!cache.delete("a").unwrap());
+    }
+
+    #[test]
+    fn purge() {
+        let mut cache = cache(max_entries(10));
+        cache.set("a", "https://example.com/a.jpg", "lastfm");
+        cache.set_missing("b", &["lastfm"]);
+        cache.set_missing("c", &["lastfm"]);
+
+        assert_eq!(cache.purge(true, None).unwrap(), 2);
+        assert!(cache.get("a").is_some());
+        assert_eq!(cache.purge(false, Some(3600)).unwrap(), 0);
+        assert_eq!(cache.purge(false, Some(0)).unwrap(), 1);
+    }
+
+    #[test]
+    fn export_and_import() {
+        let mut source = cache(max_entries(10));
+        source.set("a", "https://example.com/a.jpg", "lastfm");
+        source.set_missing("b", &["lastfm", "musicbrainz"]);
+        let data = source.export().unwrap();
+
+        let mut other = cache(max_entries(10));
+        other.set_missing("a", &["lastfm"]);
+        assert_eq!(other.import(&data).unwrap(), 2);
+        assert_eq!(other.export().unwrap().covers, data.covers);
+        assert_eq!(other.export().unwrap().missing, data.missing);
+    }
+
+    #[test]
+    fn migrates_pickledb() {
+        let cache_dir = std::env::temp_dir().join(format!(
+            "mpris-discord-rpc-cache-test-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&cache_dir).unwrap();
+        let old_path = cache_dir.join("album_cache.db");
+        let mut old_db = PickleDb::new(
+            &old_path,
+            PickleDbDumpPolicy::AutoDump,
+            SerializationMethod::Json,
+        );
+        old_db.set("a", &"https://example.com/a.jpg").unwrap();
+        old_db
+            .set(
+                "b",
+                &CachedCover {
+                    url: String::from("https://example.com/b.jpg"),
+                    provider: String::from("musicbrainz"),
+                    fetched_at: 0,
+                    checked_at: 0,
+                },
+            )
+            .unwrap();
+
+        let old_uploads_path = cache_dir.join("upload_cache.db");
+        let mut old_uploads = PickleDb::new(
+            &old_uploads_path,
+            PickleDbDumpPolicy::AutoDump,
+            SerializationMethod::Json,
+        );
+        old_uploads.set("c0ffee", &"https://0x0.st/c.jpg").unwrap();
+
+        let cache = CoverCache::open(&cache_dir, max_entries(10));
+        assert_eq!(cache.get("a").unwrap().provider, "lastfm");
+        assert_eq!(cache.get("b").unwrap().url, "https://example.com/b.jpg");
+        assert!(!old_path.exists());
+        assert_eq!(
+            cache.get_upload("c0ffee").as_deref(),
+            Some("https://0x0.st/c.jpg")
+        );
+        assert!(!old_uploads_path.exists());
+
+        fs::remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn link_checks() {
+        let mut cache = cache(CacheOptions {
+            check_interval: Some(3600),
+            ..CacheOptions::default()
+        });
+        cache.set("a", "https://example.com/a.jpg", "lastfm");
+        assert!(!cache.check_due(&cache.get("a").unwrap()));
+
+        let db = cache.db.as_ref().unwrap();
+        db.execute("UPDATE covers SET checked_at = checked_at - 3600", [])
+            .unwrap();
+        assert!(cache.check_due(&cache.get("a").unwrap()));
+        cache.set_checked("a").unwrap();
+        assert!(!cache.check_due(&cache.get("a").unwrap()));
+
+        cache.set_manual("b", "https://example.com/b.jpg").unwrap();
+        let db = cache.db.as_ref().unwrap();
+        db.execute("UPDATE covers SET checked_at = 0", []).unwrap();
+        assert!(!cache.check_due(&cache.get("b").unwrap()));
+    }
+
+    #[test]
+    fn uploads() {
+        let mut cache = cache(max_entries(1));
+        assert_eq!(cache.get_upload("c0ffee"), None);
+        cache.set_upload("c0ffee", "https://0x0.st/c.jpg").unwrap();
+        // Uploads don't count as albums
+        cache.set("a", "https://example.com/a.jpg", "lastfm");
+        assert_eq!(
+            cache.get_upload("c0ffee").as_deref(),
+            Some("https://0x0.st/c.jpg")
+        );
+        assert_eq!(cache.upload_count().unwrap(), 1);
+
+        assert_eq!(cache.purge_uploads(3600).unwrap(), 0);
+        assert_eq!(cache.purge_uploads(0).unwrap(), 1);
+        assert_eq!(cache.get_upload("c0ffee"), None);
+    }
+
+    #[test]
+    fn newer_schema() {
+        let mut db = Connection::open_in_memory().unwrap();
+        db.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        assert!(migrate_schema(&mut db).is_err());
+    }
+
+    #[test]
+    fn failed_migration_rolled_back() {
+        let mut db = Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "CREATE TABLE covers (
+                album_id TEXT PRIMARY KEY NOT NULL,
+                url TEXT NOT NULL,
+                provider TEXT NOT NULL,
+                fetched_at INTEGER NOT NULL,
+                used_at INTEGER NOT NULL
+            );
+            CREATE TABLE missing_covers (album_id TEXT PRIMARY KEY NOT NULL);
+            CREATE TABLE uploads (hash TEXT);
+            PRAGMA user_version = 2;",
+        )
+        .unwrap();
+
+        // Step 4 fails, the column added by step 3 must not stay behind
+        assert!(migrate_schema(&mut db).is_err());
+        let version: i64 = db
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, 2);
+        assert!(db.prepare("SELECT checked_at FROM covers").is_err());
+
+        db.execute("DROP TABLE uploads", []).unwrap();
+        migrate_schema(&mut db).unwrap();
+        assert!(db.prepare("SELECT checked_at FROM covers").is_ok());
+    }
+}
@@ -0,0 +1,289 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process;
+use std::thread::sleep;
+use std::time::SystemTime;
+
+use crate::cover::cache::{
+    CacheExport, CacheOptions, CachedCover, CoverCache, MissingCover, MANUAL_PROVIDER,
+};
+use crate::cover::normalize::Normalizer;
+use crate::cover::verify::{self, LinkStatus};
+use crate::error::Error;
+use crate::settings::CacheCommands;
+use crate::utils;
+
+// Runs a "cache" subcommand and exits
+pub fn run(
+    command: &CacheCommands,
+    cache_dir: &Path,
+    options: CacheOptions,
+    normalizer: &Normalizer,
+    client: &reqwest::blocking::Client,
+) -> ! {
+    let mut cache = match CoverCache::load(cache_dir, options) {
+        Ok(cache) => cache,
+        Err(err) => exit_with_error(err),
+    };
+
+    if let Err(err) = run_command(command, &mut cache, normalizer, client) {
+        exit_with_error(err);
+    }
+    process::exit(utils::EXIT_SUCCESS);
+}
+
+// Album names typed by the user are normalized the same way as the names from the players
+fn run_command(
+    command: &CacheCommands,
+    cache: &mut CoverCache,
+    normalizer: &Normalizer,
+    client: &reqwest::blocking::Client,
+) -> Result<(), Error> {
+    match command {
+        CacheCommands::List {} => {
+            let covers = cache.covers()?;
+            let missing = cache.missing_covers()?;
+            for (album_id, cover) in &covers {
+                print_cover(cache, album_id, cover);
+            }
+            for (album_id, missing) in &missing {
+                print_missing(cache, album_id, missing);
+            }
+            println!(
+                "{} albums with a cover, {} without a cover.",
+                covers.len(),
+                missing.len()
+            );
+        }
+        CacheCommands::Get { album } => {
+            let album = &normalizer.parse_album_id(album);
+            if let Some(cover) = cache.cover(album)? {
+                print_cover(cache, album, &cover);
+            } else if let Some(missing) = cache.missing_cover(album)? {
+                print_missing(cache, album, &missing);
+            } else {
+                println!("[cache] \"{}\" is not in the cache.", album);
+            }
+        }
+        CacheCommands::Set { album, url } => {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                return Err(Error::Cache(format!(
+                    "\"{}\" is not a http(s) link, Discord can't show it",
+                    url
+                )));
+            }
+            let album = &normalizer.parse_album_id(album);
+            cache.set_manual(album, url)?;
+            println!("[cache] saved image url for: {}.", album);
+        }
+        CacheCommands::Delete { album } => {
+            let album = &normalizer.parse_album_id(album);
+            if cache.delete(album)? {
+                println!("[cache] removed: {}.", album);
+            } else {
+                println!("[cache] \"{}\" is not in the cache.", album);
+            }
+        }
+        CacheCommands::Purge {
+            missing,
+            older_than,
+        } => {
+            let older_than = match older_than {
+                Some(days) => Some(
+                    days.checked_mul(24 * 3600)
+                        .ok_or_else(|| Error::Cache(String::from("--older-than is too large")))?,
+                ),
+                None => None,
+            };
+            let purged = cache.purge(*missing, older_than)?;
+            println!("[cache] removed {} albums.", purged);
+            if let Some(older_than) = older_than {
+                let purged = cache.purge_uploads(older_than)?;
+                println!("[cache] removed {} uploaded cover links.", purged);
+            }
+        }
+        CacheCommands::Export { file } => {
+            let data = serde_json::to_string_pretty(&cache.export()?)
+                .map_err(|err| Error::Cache(err.to_string()))?;
+            fs::write(file, data + "\n")
+                .map_err(|err| Error::Cache(format!("could not write to {}: {}", file, err)))?;
+            println!("[cache] exported to: {}", file);
+        }
+        CacheCommands::Import { file } => {
+            let data = if file == "-" {
+                let mut data = String::new();
+                io::stdin()
+                    .read_to_string(&mut data)
+                    .map(|_| data)
+                    .map_err(|err| Error::Cache(format!("could not read input: {}", err)))?
+            } else {
+                fs::read_to_string(file)
+                    .map_err(|err| Error::Cache(format!("could not read {}: {}", file, err)))?
+            };
+            let data: CacheExport = serde_json::from_str(&data)
+                .map_err(|err| Error::Cache(format!("invalid cache export: {}", err)))?;
+            let imported = cache.import(&data)?;
+            println!("[cache] imported {} albums.", imported);
+        }
+        CacheCommands::Stats {} => print_stats(cache)?,
+        CacheCommands::Verify { dry_run } => verify_links(cache, client, *dry_run)?,
+    }
+    Ok(())
+}
+
+fn print_cover(cache: &CoverCache, album_id: &str, cover: &CachedCover) {
+    println!("{}", album_id);
+    println!(
+        "    {} ({}, fetched {}{})",
+        cover.url,
+        cover.provider,
+        format_age(cover.fetched_at),
+        if cache.cover_expired(cover) {
+            ", expired"
+        } else {
+            ""
+        }
+    );
+}
+
+fn print_missing(cache: &CoverCache, album_id: &str, missing: &MissingCover) {
+    println!("{}", album_id);
+    println!(
+        "    no cover ({}, checked {}{})",
+        missing.providers.join(", "),
+        format_age(missing.fetched_at),
+        if cache.missing_expired(missing) {
+            ", expired"
+        } else {
+            ""
+        }
+    );
+}
+
+fn print_stats(cache: &CoverCache) -> Result<(), Error> {
+    let covers = cache.covers()?;
+    let missing = cache.missing_covers()?;
+    let options = cache.options();
+
+    let size = fs::metadata(cache.path())
+        .map(|metadata| metadata.len())
+        .unwrap_or_default();
+    println!(
+        "Cache file: {} ({} KiB)",
+        cache.path().display(),
+        size / 1024
+    );
+
+    let mut providers: Vec<(&str, usize)> = Vec::new();
+    for cover in covers.values() {
+        match providers
+            .iter_mut()
+            .find(|(provider, _)| *provider == cover.provider)
+        {
+            Some((_, count)) => *count += 1,
+            None => providers.push((&cover.provider, 1)),
+        }
+    }
+    providers.sort();
+    let expired_covers = covers
+        .values()
+        .filter(|cover| cache.cover_expired(cover))
+        .count();
+    println!("Albums with a cover: {}", covers.len());
+    for (provider, count) in providers {
+        println!("    {}: {}", provider, count);
+    }
+    println!("    expired: {}", expired_covers);
+
+    let expired_missing = missing
+        .values()
+        .filter(|missing| cache.missing_expired(missing))
+        .count();
+    println!("Albums without a cover: {}", missing.len());
+    println!("    expired: {}", expired_missing);
+    println!("Uploaded local covers: {}", cache.upload_count()?);
+
+    println!("Maximum number of albums: {}", options.max_entries);
+    match options.hit_ttl {
+        Some(ttl) => println!("Found covers expire after: {} hours", ttl / 3600),
+        None => println!("Found covers expire after: never"),
+    }
+    println!(
+        "Albums without a cover expire after: {} hours",
+        options.miss_ttl / 3600
+    );
+    match options.check_interval {
+        Some(interval) => println!("Links are checked every: {} hours", interval / 3600),
+        None => println!("Links are checked every: never"),
+    }
+    Ok(())
+}
+
+// Manual covers are only reported, the user has to set a new link
+fn verify_links(
+    cache: &mut CoverCache,
+    client: &reqwest::blocking::Client,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let covers = cache.covers()?;
+    let (mut alive, mut dead, mut removed, mut unknown) = (0, 0, 0, 0);
+    for (index, (album_id, cover)) in covers.iter().enumerate() {
+        if index > 0 {
+            sleep(verify::BULK_CHECK_DELAY);
+        }
+        match verify::check_link(client, &cover.url) {
+            LinkStatus::Alive => {
+                alive += 1;
+                cache.set_checked(album_id)?;
+            }
+            LinkStatus::Dead(reason) => {
+                dead += 1;
+                println!("{}", album_id);
+                println!("    {} ({}, {})", cover.url, cover.provider, reason);
+                if cover.provider == MANUAL_PROVIDER {
+                    println!("    manual cover, set a new link with \"cache set\"");
+                } else if !dry_run {
+                    cache.delete(album_id)?;
+                    removed += 1;
+                }
+            }
+            LinkStatus::Unknown(err) => {
+                unknown += 1;
+                println!("{}", album_id);
+                println!("    {} (could not check: {})", cover.url, err);
+            }
+        }
+    }
+
+    println!(
+        "{} working, {} broken ({} removed), {} could not be checked.",
+        alive, dead, removed, unknown
+    );
+    Ok(())
+}
+
+fn format_age(timestamp: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default();
+    let age = now.saturating_sub(timestamp);
+    let (value, unit) = match age {
+        0..=59 => return String::from("just now"),
+        60..=3599 => (age / 60, "minute"),
+        3600..=86399 => (age / 3600, "hour"),
+        _ => (age / 86400, "day"),
+    };
+    format!(
+        "{} {}{} ago",
+        value,
+        unit,
+        if value == 1 { "" } else { "s" }
+    )
+}
+
+fn exit_with_error(err: Error) -> ! {
+    println!("[cache] {}", err);
+    process::exit(utils::EXIT_CACHE_ERROR);
+}
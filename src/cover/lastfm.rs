@@ -0,0 +1,284 @@
+use crate::cover::normalize::Normalizer;
+use crate::cover::size::CoverSize;
+use crate::cover::CoverProvider;
+use crate::debug_log;
+use crate::error::Error;
+use crate::presence::Track;
+
+const API_URL: &str = "http://ws.audioscrobbler.com/2.0/";
+
+// Last.fm returns this star image for every artist without a photo
+const PLACEHOLDER_IMAGE: &str = "2a96cbd8b46e442fc41c2b86b821562f";
+
+// Names in the "image" arrays of the API, smallest first
+const IMAGE_SIZES: &[&str] = &["small", "medium", "large", "extralarge", "mega"];
+
+// Path of the images on the Last.fm CDN, followed by the size, e.g. "/i/u/300x300/<hash>.jpg"
+const CDN_PATH: &str = "/i/u/";
+
+pub struct LastfmProvider {
+    client: reqwest::blocking::Client,
+    api_key: String,
+    normalizer: Normalizer,
+    size: CoverSize,
+    debug_log: bool,
+}
+
+impl LastfmProvider {
+    pub fn new(
+        client: reqwest::blocking::Client,
+        api_key: &str,
+        normalizer: Normalizer,
+        size: CoverSize,
+        debug_log: bool,
+    ) -> Self {
+        LastfmProvider {
+            client,
+            api_key: api_key.to_string(),
+            normalizer,
+            size,
+            debug_log,
+        }
+    }
+
+    fn get(&self, request_url: &str) -> Result<serde_json::Value, Error> {
+        Ok(self
+            .client
+            .get(request_url)
+            .send()?
+            .json::<serde_json::Value>()?)
+    }
+
+    fn album_image(
+        &self,
+        artist: &str,
+        album: &str,
+        autocorrect: bool,
+    ) -> Result<Option<String>, Error> {
+        let request_url = format!(
+            "{}?method=album.getinfo&api_key={}&artist={}&album={}&autocorrect={}&format=json",
+            API_URL,
+            self.api_key,
+            url_escape::encode_component(artist),
+            url_escape::encode_component(album),
+            autocorrect as u8
+        );
+        let data = self.get(&request_url)?;
+        Ok(image(&data["album"], self.size))
+    }
+
+    fn artist_image(&self, artist: &str) -> Result<Option<String>, Error> {
+        let request_url = format!(
+            "{}?method=artist.getinfo&api_key={}&artist={}&autocorrect=1&format=json",
+            API_URL,
+            self.api_key,
+            url_escape::encode_component(artist)
+        );
+        let data = self.get(&request_url)?;
+        Ok(image(&data["artist"], self.size).filter(|url| !url.contains(PLACEHOLDER_IMAGE)))
+    }
+
+    // Cover of the album the track is on
+    fn track_image(&self, artist: &str, title: &str) -> Result<Option<String>, Error> {
+        let request_url = format!(
+            "{}?method=track.getinfo&api_key={}&artist={}&track={}&autocorrect=1&format=json",
+            API_URL,
+            self.api_key,
+            url_escape::encode_component(artist),
+            url_escape::encode_component(title)
+        );
+        let data = self.get(&request_url)?;
+        Ok(image(&data["track"]["album"], self.size))
+    }
+}
+
+impl CoverProvider for LastfmProvider {
+    fn name(&self) -> &'static str {
+        "lastfm"
+    }
+
+    // Tries the album as sent by the player first, then without edition suffixes, then lets
+    // Last.fm correct the names
+    fn fetch(&mut self, track: &Track) -> Result<Option<String>, Error> {
+        let album = self.normalizer.clean(&track.album);
+        let mut steps = vec![("exact", track.album.as_str(), false)];
+        if album != track.album {
+            steps.push(("normalized", album.as_str(), false));
+        }
+        steps.push(("autocorrect", album.as_str(), true));
+
+        for (step, album, autocorrect) in steps {
+            if let Some(url) = self.album_image(&track.album_artist, album, autocorrect)? {
+                debug_log!(
+                    self.debug_log,
+                    "[last.fm] album cover found by lookup: {}",
+                    step
+                );
+                return Ok(Some(url));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Singles and tracks without album tags, Last.fm often knows the album of the track
+    fn fetch_without_album(&mut self, track: &Track) -> Result<Option<String>, Error> {
+        if let Some(url) = self.track_image(&track.artist, &track.title)? {
+            debug_log!(
+                self.debug_log,
+                "[last.fm] album cover found by lookup: track info"
+            );
+            return Ok(Some(url));
+        }
+
+        Ok(None)
+    }
+
+    fn fetch_artist(&mut self, artist: &str) -> Result<Option<String>, Error> {
+        let url = self.artist_image(artist)?;
+        if url.is_some() {
+            debug_log!(self.debug_log, "[last.fm] using the artist image as cover");
+        }
+        Ok(url)
+    }
+}
+
+// Image of album, track, artist and user info closest to the preferred size. Larger sizes than
+// the API returns are made by changing the size in the CDN link.
+pub fn image(data: &serde_json::Value, size: CoverSize) -> Option<String> {
+    let images: Vec<(usize, &str)> = data["image"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|image| {
+            let url = image["#text"].as_str().filter(|url| !url.is_empty())?;
+            let rank = IMAGE_SIZES
+                .iter()
+                .position(|name| image["size"].as_str() == Some(*name))?;
+            Some((rank, url))
+        })
+        .collect();
+
+    let preferred = match size {
+        CoverSize::Small => 2,
+        CoverSize::Medium => 3,
+        CoverSize::Large | CoverSize::Original => 4,
+    };
+    let url = images
+        .iter()
+        .filter(|(rank, _)| *rank <= preferred)
+        .max_by_key(|(rank, _)| *rank)
+        .or_else(|| images.iter().min_by_key(|(rank, _)| *rank))
+        .map(|(_, url)| *url)?;
+
+    Some(resize(url, size))
+}
+
+// "mega" is rarely larger than 300x300, the CDN serves any size of the same image
+fn resize(url: &str, size: CoverSize) -> String {
+    let dimensions = match size {
+        CoverSize::Large => "770x0/",
+        CoverSize::Original => "",
+        _ => return url.to_string(),
+    };
+    let Some(start) = url.find(CDN_PATH) else {
+        return url.to_string();
+    };
+    let (prefix, path) = url.split_at(start + CDN_PATH.len());
+    match path.split_once('/') {
+        Some((current, file)) if is_dimensions(current) => {
+            format!("{}{}{}", prefix, dimensions, file)
+        }
+        _ => url.to_string(),
+    }
+}
+
+// "300x300", "174s", "770x0"
+fn is_dimensions(segment: &str) -> bool {
+    segment.starts_with(|c: char| c.is_ascii_digit())
+        && segment
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == 'x' || c == 's')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn album(images: &[(&str, &str)]) -> serde_json::Value {
+        let images: Vec<serde_json::Value> = images
+            .iter()
+            .map(|(size, url)| json!({ "size": size, "#text": url }))
+            .collect();
+        json!({ "image": images })
+    }
+
+    const CDN: &str = "https://lastfm.freetls.fastly.net/i/u";
+
+    #[test]
+    fn preferred_size() {
+        let data = album(&[
+            ("small", "https://example.com/34s.jpg"),
+            ("large", "https://example.com/174s.jpg"),
+            ("extralarge", "https://example.com/300x300.jpg"),
+        ]);
+        assert_eq!(
+            image(&data, CoverSize::Small).as_deref(),
+            Some("https://example.com/174s.jpg")
+        );
+        assert_eq!(
+            image(&data, CoverSize::Medium).as_deref(),
+            Some("https://example.com/300x300.jpg")
+        );
+    }
+
+    #[test]
+    fn falls_back_to_other_sizes() {
+        let data = album(&[
+            ("small", "https://example.com/34s.jpg"),
+            ("medium", "https://example.com/64s.jpg"),
+            ("extralarge", ""),
+        ]);
+        assert_eq!(
+            image(&data, CoverSize::Medium).as_deref(),
+            Some("https://example.com/64s.jpg")
+        );
+
+        let data = album(&[("mega", "https://example.com/300x300.jpg")]);
+        assert_eq!(
+            image(&data, CoverSize::Small).as_deref(),
+            Some("https://example.com/300x300.jpg")
+        );
+
+        assert_eq!(
+            image(&album(&[("extralarge", "")]), CoverSize::Medium),
+            None
+        );
+        assert_eq!(image(&serde_json::Value::Null, CoverSize::Medium), None);
+    }
+
+    #[test]
+    fn larger_cdn_sizes() {
+        let data = album(&[("extralarge", &format!("{}/300x300/abc.jpg", CDN))]);
+        assert_eq!(
+            image(&data, CoverSize::Medium),
+            Some(format!("{}/300x300/abc.jpg", CDN))
+        );
+        assert_eq!(
+            image(&data, CoverSize::Large),
+            Some(format!("{}/770x0/abc.jpg", CDN))
+        );
+        assert_eq!(
+            image(&data, CoverSize::Original),
+            Some(format!("{}/abc.jpg", CDN))
+        );
+
+        // Links of other hosts are kept
+        let data = album(&[("extralarge", "https://example.com/i/u/cover/abc.jpg")]);
+        assert_eq!(
+            image(&data, CoverSize::Large).as_deref(),
+            Some("https://example.com/i/u/cover/abc.jpg")
+        );
+    }
+}
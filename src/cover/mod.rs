@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Instant;
+
+use crate::error::Error;
+use crate::presence::Track;
+
+pub mod cache;
+pub mod commands;
+pub mod lastfm;
+pub mod mpris;
+pub mod musicbrainz;
+pub mod normalize;
+pub mod size;
+pub mod upload;
+pub mod verify;
+pub mod worker;
+
+use cache::CoverCache;
+use normalize::Normalizer;
+use size::CoverSize;
+use verify::LinkStatus;
+
+// Names accepted in the "cover_providers" setting, also the default order
+pub const PROVIDERS: &[&str] = &["lastfm", "mpris", "musicbrainz", "upload"];
+
+// Source of album covers, providers run on the cover worker thread
+pub trait CoverProvider: Send {
+    fn name(&self) -> &'static str;
+
+    // Album covers are cached by album, covers that can change with every track are not
+    fn per_track(&self) -> bool {
+        false
+    }
+
+    fn fetch(&mut self, track: &Track) -> Result<Option<String>, Error>;
+
+    // Cover of a track without an album name (singles, radio, music videos), by artist and title
+    fn fetch_without_album(&mut self, _track: &Track) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
+    // Artist photo, only used if no provider has a cover for the album or track
+    fn fetch_artist(&mut self, _artist: &str) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cover {
+    pub url: String,
+    pub provider: String,
+}
+
+// Asks the providers in the configured order and returns the first cover found
+pub struct CoverFinder {
+    providers: Vec<Box<dyn CoverProvider>>,
+    cache: CoverCache,
+    normalizer: Normalizer,
+    client: reqwest::blocking::Client,
+    // Time of the last check of a cached link
+    last_check: Option<Instant>,
+    // Results of the current album, so every provider is asked only once per album
+    album_id: String,
+    album_results: HashMap<&'static str, Option<String>>,
+    // Artist image used for the current album, it's not cached so a real cover found later wins
+    artist_result: Option<Option<Cover>>,
+}
+
+impl CoverFinder {
+    // "cache_dir" is None if the cache is disabled
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        names: &[String],
+        client: &reqwest::blocking::Client,
+        lastfm_api_key: Option<&str>,
+        upload: Option<upload::UploadSettings>,
+        normalizer: Normalizer,
+        size: CoverSize,
+        cache: CoverCache,
+        cache_dir: Option<&Path>,
+        debug_log: bool,
+    ) -> Self {
+        let default_order = names.is_empty();
+        let names: Vec<&str> = if default_order {
+            PROVIDERS.to_vec()
+        } else {
+            names.iter().map(|name| name.as_str()).collect()
+        };
+
+        let mut providers: Vec<Box<dyn CoverProvider>> = Vec::new();
+        for name in names {
+            match name {
+                "lastfm" => match lastfm_api_key {
+                    Some(api_key) => providers.push(Box::new(lastfm::LastfmProvider::new(client.clone(), api_key, normalizer.clone(), size, debug_log))),
+                    None => println!("[last.fm] No API key available, skipping Last.fm album covers. Set \"lastfm_api_key\" in the config file to enable it."),
+                },
+                "mpris" => providers.push(Box::new(mpris::MprisProvider)),
+                "musicbrainz" => providers.push(Box::new(musicbrainz::MusicbrainzProvider::new(client.clone(), size))),
+                // Uploading is opt-in, so it's only mentioned if the provider was listed explicitly
+                "upload" => match &upload {
+                    Some(upload) => providers.push(Box::new(upload::UploadProvider::new(upload.clone(), client.clone(), cache_dir))),
+                    None if default_order => {}
+                    None => println!("[upload] No image host set, skipping uploading local album covers. Set \"cover_upload\" in the config file to enable it."),
+                },
+                _ => println!("[cover] Unknown cover provider: {}", name),
+            }
+        }
+
+        CoverFinder {
+            providers,
+            cache,
+            normalizer,
+            client: client.clone(),
+            last_check: None,
+            album_id: String::new(),
+            album_results: HashMap::new(),
+            artist_result: None,
+        }
+    }
+
+    pub fn has_provider(&self, name: &str) -> bool {
+        self.providers
+            .iter()
+            .any(|provider| provider.name() == name)
+    }
+
+    // "use_art_url" is false if the player profile disables the MPRIS artUrl
+    pub fn find(&mut self, track: &Track, use_art_url: bool) -> Option<Cover> {
+        let known_album = track.album != "Unknown Album";
+        // Tracks without an album are looked up and cached one by one
+        let known_track = known_album || track.artist != "Unknown Artist";
+        let album_id = if known_album {
+            self.normalizer.album_id(track)
+        } else {
+            self.normalizer.track_id(track)
+        };
+        if album_id != self.album_id {
+            self.album_id = album_id.clone();
+            self.album_results.clear();
+            self.artist_result = None;
+            if !known_album {
+                println!("Missing album name or Unknown Album, looking up the cover by artist and title.");
+            }
+        }
+
+        let CoverFinder {
+            providers,
+            cache,
+            client,
+            last_check,
+            album_results,
+            artist_result,
+            ..
+        } = self;
+
+        // Only trust cached covers from providers that are still enabled
+        let legacy_id = if known_album {
+            track.album_id()
+        } else {
+            album_id.clone()
+        };
+        let cached = if known_track {
+            get_cached(cache, &album_id, &legacy_id).filter(|cached| {
+                cached.provider == cache::MANUAL_PROVIDER
+                    || providers
+                        .iter()
+                        .any(|provider| provider.name() == cached.provider)
+            })
+        } else {
+            None
+        };
+
+        // Links stop working when the service removes the image, then the cover is looked up again
+        let cached = match cached {
+            Some(cached) if cache.check_due(&cached) && check_allowed(last_check) => {
+                match verify::check_link(client, &cached.url) {
+                    LinkStatus::Alive => {
+                        if let Err(err) = cache.set_checked(&album_id) {
+                            println!("[cache] error, unable to write to cache file: {}", err);
+                        }
+                        Some(cached)
+                    }
+                    LinkStatus::Dead(reason) => {
+                        println!(
+                            "[cache] cached image url is gone ({}), looking it up again: {}.",
+                            reason, album_id
+                        );
+                        if let Err(err) = cache.delete(&album_id) {
+                            println!("[cache] error, unable to write to cache file: {}", err);
+                        }
+                        None
+                    }
+                    LinkStatus::Unknown(err) => {
+                        println!("[cache] could not check image url of {}: {}", album_id, err);
+                        Some(cached)
+                    }
+                }
+            }
+            cached => cached,
+        };
+
+        // Set by the user with "cache set"
+        if let Some(cached) = &cached {
+            if cached.provider == cache::MANUAL_PROVIDER {
+                return Some(cover(cached.url.clone(), cache::MANUAL_PROVIDER));
+            }
+        }
+        // Providers that had nothing for the album recently are not asked again until it expires
+        let mut missing = match (&cached, known_track) {
+            (None, true) => cache.get_missing(&album_id),
+            _ => Vec::new(),
+        };
+
+        for provider in providers.iter_mut() {
+            let name = provider.name();
+            if provider.per_track() {
+                if name == "mpris" && !use_art_url {
+                    continue;
+                }
+                match provider.fetch(track) {
+                    Ok(Some(url)) => return Some(cover(url, name)),
+                    Ok(None) => {}
+                    Err(err) => println!("[{}] could not fetch album cover: {}", name, err),
+                }
+                continue;
+            }
+
+            if !known_track {
+                continue;
+            }
+
+            if let Some(cached) = &cached {
+                if cached.provider == name {
+                    return Some(cover(cached.url.clone(), name));
+                }
+                // The cover was found by a later provider, so this one has nothing for the album
+                continue;
+            }
+
+            if missing.iter().any(|missing| missing == name) {
+                continue;
+            }
+
+            let url = match album_results.get(name) {
+                Some(url) => url.clone(),
+                None => match fetch(provider.as_mut(), track, known_album) {
+                    Ok(url) => {
+                        album_results.insert(name, url.clone());
+                        match &url {
+                            Some(url) => {
+                                println!("[{}] fetched image link: {}", name, url);
+                                cache.set(&album_id, url, name);
+                            }
+                            None => {
+                                missing.push(name.to_string());
+                                let missing: Vec<&str> =
+                                    missing.iter().map(|name| name.as_str()).collect();
+                                cache.set_missing(&album_id, &missing);
+                            }
+                        }
+                        url
+                    }
+                    Err(err) => {
+                        println!("[{}] could not fetch album cover: {}", name, err);
+                        None
+                    }
+                },
+            };
+
+            if let Some(url) = url {
+                return Some(cover(url, name));
+            }
+        }
+
+        if !known_track {
+            return None;
+        }
+        let artist = if known_album {
+            &track.album_artist
+        } else {
+            &track.artist
+        };
+        artist_result
+            .get_or_insert_with(|| find_artist_image(providers, artist))
+            .clone()
+    }
+}
+
+// Last resort after every provider had no cover
+fn find_artist_image(providers: &mut [Box<dyn CoverProvider>], artist: &str) -> Option<Cover> {
+    for provider in providers
+        .iter_mut()
+        .filter(|provider| !provider.per_track())
+    {
+        let name = provider.name();
+        match provider.fetch_artist(artist) {
+            Ok(Some(url)) => {
+                println!("[{}] no album cover, using the artist image: {}", name, url);
+                return Some(cover(url, name));
+            }
+            Ok(None) => {}
+            Err(err) => println!("[{}] could not fetch artist image: {}", name, err),
+        }
+    }
+    None
+}
+
+fn fetch(
+    provider: &mut dyn CoverProvider,
+    track: &Track,
+    known_album: bool,
+) -> Result<Option<String>, Error> {
+    if known_album {
+        provider.fetch(track)
+    } else {
+        provider.fetch_without_album(track)
+    }
+}
+
+// Cached links are checked one at a time with a pause, so a long playlist doesn't flood the network
+fn check_allowed(last_check: &mut Option<Instant>) -> bool {
+    if let Some(last_check) = last_check {
+        if last_check.elapsed() < verify::PLAYBACK_CHECK_DELAY {
+            return false;
+        }
+    }
+    *last_check = Some(Instant::now());
+    true
+}
+
+// Entries saved before the album names were normalized are moved to the new key
+fn get_cached(
+    cache: &mut CoverCache,
+    album_id: &str,
+    raw_album_id: &str,
+) -> Option<cache::CachedCover> {
+    if let Some(cached) = cache.get(album_id) {
+        return Some(cached);
+    }
+    if album_id == raw_album_id {
+        return None;
+    }
+
+    let cached = cache.get(raw_album_id)?;
+    cache.set(album_id, &cached.url, &cached.provider);
+    if let Err(err) = cache.delete(raw_album_id) {
+        println!("[cache] error, unable to write to cache file: {}", err);
+    }
+    Some(cached)
+}
+
+fn cover(url: String, provider: &str) -> Cover {
+    Cover {
+        url,
+        provider: provider.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Has no album covers, only the artist image
+    struct ArtistOnly;
+
+    impl CoverProvider for ArtistOnly {
+        fn name(&self) -> &'static str {
+            "lastfm"
+        }
+
+        fn fetch(&mut self, _track: &Track) -> Result<Option<String>, Error> {
+            Ok(None)
+        }
+
+        fn fetch_artist(&mut self, _artist: &str) -> Result<Option<String>, Error> {
+            Ok(Some(String::from("https://example.com/artist.jpg")))
+        }
+    }
+
+    struct AlbumCover;
+
+    impl CoverProvider for AlbumCover {
+        fn name(&self) -> &'static str {
+            "musicbrainz"
+        }
+
+        fn fetch(&mut self, _track: &Track) -> Result<Option<String>, Error> {
+            Ok(Some(String::from("https://example.com/album.jpg")))
+        }
+    }
+
+    fn cover_finder(providers: Vec<Box<dyn CoverProvider>>) -> CoverFinder {
+        CoverFinder {
+            providers,
+            cache: CoverCache::disabled(),
+            normalizer: Normalizer::new(&[]),
+            client: reqwest::blocking::Client::new(),
+            last_check: None,
+            album_id: String::new(),
+            album_results: HashMap::new(),
+            artist_result: None,
+        }
+    }
+
+    #[test]
+    fn artist_image_is_last_resort() {
+        let track = Track {
+            title: String::from("Paranoid Android"),
+            artist: String::from("Radiohead"),
+            album: String::from("OK Computer"),
+            album_artist: String::from("Radiohead"),
+            ..Track::default()
+        };
+
+        let mut finder = cover_finder(vec![Box::new(ArtistOnly), Box::new(AlbumCover)]);
+        assert_eq!(
+            finder.find(&track, true),
+            Some(cover(
+                String::from("https://example.com/album.jpg"),
+                "musicbrainz"
+            ))
+        );
+
+        let mut finder = cover_finder(vec![Box::new(ArtistOnly)]);
+        assert_eq!(
+            finder.find(&track, true),
+            Some(cover(
+                String::from("https://example.com/artist.jpg"),
+                "lastfm"
+            ))
+        );
+    }
+}
@@ -0,0 +1,24 @@
+use crate::cover::CoverProvider;
+use crate::error::Error;
+use crate::presence::Track;
+
+// Cover url sent by the player itself, Discord can only show it if it's a http link
+pub struct MprisProvider;
+
+impl CoverProvider for MprisProvider {
+    fn name(&self) -> &'static str {
+        "mpris"
+    }
+
+    // Players often send a different image for every track (e.g. video thumbnails)
+    fn per_track(&self) -> bool {
+        true
+    }
+
+    fn fetch(&mut self, track: &Track) -> Result<Option<String>, Error> {
+        match &track.art_url {
+            Some(url) if url.starts_with("http") => Ok(Some(url.to_string())),
+            _ => Ok(None),
+        }
+    }
+}
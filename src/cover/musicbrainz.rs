@@ -0,0 +1,195 @@
+use crate::cover::size::CoverSize;
+use crate::cover::CoverProvider;
+use crate::error::Error;
+use crate::presence::Track;
+
+const MUSICBRAINZ_URL: &str = "https://musicbrainz.org/ws/2";
+const COVER_ART_ARCHIVE_URL: &str = "https://coverartarchive.org";
+
+// Search results below this score are most likely a different album
+const MIN_SEARCH_SCORE: u64 = 90;
+
+// Front cover from the Cover Art Archive. The release MBID from the file tags is used if the player
+// provides it, otherwise the release group is searched on MusicBrainz by album artist and album.
+pub struct MusicbrainzProvider {
+    client: reqwest::blocking::Client,
+    size: CoverSize,
+}
+
+impl MusicbrainzProvider {
+    // MusicBrainz blocks requests without a meaningful user agent, the shared client sends one
+    pub fn new(client: reqwest::blocking::Client, size: CoverSize) -> Self {
+        MusicbrainzProvider { client, size }
+    }
+
+    fn search_release_group(&self, track: &Track) -> Result<Option<String>, Error> {
+        let query = format!(
+            "releasegroup:\"{}\" AND artist:\"{}\"",
+            escape_query(&track.album),
+            escape_query(&track.album_artist)
+        );
+        let request_url = format!(
+            "{}/release-group/?query={}&limit=5&fmt=json",
+            MUSICBRAINZ_URL,
+            url_escape::encode_component(&query)
+        );
+
+        let data = self
+            .client
+            .get(request_url)
+            .send()?
+            .error_for_status()?
+            .json::<serde_json::Value>()?;
+
+        let release_group = data["release-groups"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|release_group| release_group["score"].as_u64().unwrap_or(0) >= MIN_SEARCH_SCORE)
+            .and_then(|release_group| release_group["id"].as_str());
+
+        Ok(release_group.map(|id| id.to_string()))
+    }
+
+    // Release group of the first release the recording is on
+    fn search_recording(&self, track: &Track) -> Result<Option<String>, Error> {
+        let query = format!(
+            "recording:\"{}\" AND artist:\"{}\"",
+            escape_query(&track.title),
+            escape_query(&track.artist)
+        );
+        let request_url = format!(
+            "{}/recording/?query={}&limit=5&fmt=json",
+            MUSICBRAINZ_URL,
+            url_escape::encode_component(&query)
+        );
+
+        let data = self
+            .client
+            .get(request_url)
+            .send()?
+            .error_for_status()?
+            .json::<serde_json::Value>()?;
+
+        let release_group = data["recordings"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|recording| recording["score"].as_u64().unwrap_or(0) >= MIN_SEARCH_SCORE)
+            .flat_map(|recording| recording["releases"].as_array().into_iter().flatten())
+            .find_map(|release| release["release-group"]["id"].as_str());
+
+        Ok(release_group.map(|id| id.to_string()))
+    }
+
+    // "kind" is "release" or "release-group"
+    fn front_cover(&self, kind: &str, mbid: &str) -> Result<Option<String>, Error> {
+        let response = self
+            .client
+            .get(format!("{}/{}/{}", COVER_ART_ARCHIVE_URL, kind, mbid))
+            .send()?;
+
+        // Release has no cover art
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let data = response.error_for_status()?.json::<serde_json::Value>()?;
+        let image = data["images"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|image| image["front"].as_bool().unwrap_or(false));
+
+        let url = image.and_then(|image| image_url(image, self.size));
+
+        // Discord does not load covers over plain http
+        Ok(url.map(|url| url.replacen("http://", "https://", 1)))
+    }
+}
+
+impl CoverProvider for MusicbrainzProvider {
+    fn name(&self) -> &'static str {
+        "musicbrainz"
+    }
+
+    fn fetch(&mut self, track: &Track) -> Result<Option<String>, Error> {
+        if let Some(release_mbid) = &track.release_mbid {
+            if let Some(url) = self.front_cover("release", release_mbid)? {
+                return Ok(Some(url));
+            }
+        }
+
+        match self.search_release_group(track)? {
+            Some(release_group) => self.front_cover("release-group", &release_group),
+            None => Ok(None),
+        }
+    }
+
+    fn fetch_without_album(&mut self, track: &Track) -> Result<Option<String>, Error> {
+        if let Some(release_mbid) = &track.release_mbid {
+            if let Some(url) = self.front_cover("release", release_mbid)? {
+                return Ok(Some(url));
+            }
+        }
+
+        match self.search_recording(track)? {
+            Some(release_group) => self.front_cover("release-group", &release_group),
+            None => Ok(None),
+        }
+    }
+}
+
+// Thumbnail closest to the preferred size, older images only have "small" (250) and "large" (500)
+fn image_url(image: &serde_json::Value, size: CoverSize) -> Option<&str> {
+    let names: &[&str] = match size {
+        CoverSize::Small => &["250", "small", "500", "large", "1200"],
+        CoverSize::Medium => &["500", "large", "250", "small", "1200"],
+        CoverSize::Large => &["1200", "500", "large", "250", "small"],
+        CoverSize::Original => &[],
+    };
+    let thumbnails = &image["thumbnails"];
+    names
+        .iter()
+        .find_map(|name| thumbnails[*name].as_str().filter(|url| !url.is_empty()))
+        .or_else(|| image["image"].as_str())
+}
+
+// Lucene special characters inside a quoted phrase
+fn escape_query(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn thumbnail_sizes() {
+        let image = json!({
+            "image": "http://coverartarchive.org/release/1/2.jpg",
+            "thumbnails": {
+                "small": "http://coverartarchive.org/release/1/2-250.jpg",
+                "large": "http://coverartarchive.org/release/1/2-500.jpg",
+                "1200": ""
+            }
+        });
+        assert_eq!(
+            image_url(&image, CoverSize::Small),
+            Some("http://coverartarchive.org/release/1/2-250.jpg")
+        );
+        assert_eq!(
+            image_url(&image, CoverSize::Medium),
+            Some("http://coverartarchive.org/release/1/2-500.jpg")
+        );
+        assert_eq!(
+            image_url(&image, CoverSize::Large),
+            Some("http://coverartarchive.org/release/1/2-500.jpg")
+        );
+        assert_eq!(
+            image_url(&image, CoverSize::Original),
+            Some("http://coverartarchive.org/release/1/2.jpg")
+        );
+    }
+}
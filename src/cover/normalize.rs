@@ -0,0 +1,215 @@
+use unicode_normalization::UnicodeNormalization;
+
+use crate::presence::Track;
+
+// Names accepted in the "cover_normalization" setting, all are used by default
+pub const RULES: &[&str] = &["editions", "case", "unicode", "none"];
+
+// Words that mark a release of the same album with the same cover, e.g. "(Deluxe Edition)".
+// "Version" is not here on purpose, "(Taylor's Version)" is a different album.
+const EDITION_KEYWORDS: &[&str] = &[
+    "deluxe",
+    "edition",
+    "remaster",
+    "expanded",
+    "anniversary",
+    "bonus",
+    "reissue",
+    "explicit",
+];
+
+// Makes different spellings of an album end up in the same cache entry and gives the
+// cover providers a cleaner name to search for
+#[derive(Debug, Clone)]
+pub struct Normalizer {
+    editions: bool,
+    case: bool,
+    unicode: bool,
+}
+
+impl Normalizer {
+    pub fn new(rules: &[String]) -> Self {
+        let enabled = |rule: &str| {
+            if rules.is_empty() {
+                return true;
+            }
+            rules.iter().any(|name| name == rule)
+        };
+        Normalizer {
+            editions: enabled("editions"),
+            case: enabled("case"),
+            unicode: enabled("unicode"),
+        }
+    }
+
+    // Cache key of the album
+    pub fn album_id(&self, track: &Track) -> String {
+        self.key(&track.album_artist, &track.album)
+    }
+
+    // Cache key of a track without an album
+    pub fn track_id(&self, track: &Track) -> String {
+        format!("track: {}", self.key(&track.artist, &track.title))
+    }
+
+    // Same as "album_id" for "<album artist> - <album>" typed by the user in the cache subcommands
+    pub fn parse_album_id(&self, album_id: &str) -> String {
+        match album_id.split_once(" - ") {
+            Some((album_artist, album)) => self.key(album_artist, album),
+            None => self.fold(&self.clean(album_id)),
+        }
+    }
+
+    fn key(&self, album_artist: &str, album: &str) -> String {
+        let album_artist = if self.unicode {
+            album_artist.nfkc().collect::<String>()
+        } else {
+            album_artist.to_string()
+        };
+        format!(
+            "{} - {}",
+            self.fold(&collapse_whitespace(&album_artist)),
+            self.fold(&self.clean(album))
+        )
+    }
+
+    fn fold(&self, name: &str) -> String {
+        if self.case {
+            name.to_lowercase()
+        } else {
+            name.to_string()
+        }
+    }
+
+    // Name for searching, the letter case is kept
+    pub fn clean(&self, name: &str) -> String {
+        let mut name = if self.unicode {
+            name.nfkc().collect::<String>()
+        } else {
+            name.to_string()
+        };
+        if self.editions {
+            name = strip_editions(&name);
+        }
+        collapse_whitespace(&name)
+    }
+}
+
+fn collapse_whitespace(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+// Removes suffixes like "(Deluxe Edition)", "[2011 Remaster]" or " - Remastered 2009"
+fn strip_editions(name: &str) -> String {
+    let mut name = name.trim();
+    loop {
+        let suffix_start = if name.ends_with(')') {
+            name.rfind('(')
+        } else if name.ends_with(']') {
+            name.rfind('[')
+        } else {
+            name.rfind(" - ")
+        };
+
+        match suffix_start {
+            // Never remove the whole name
+            Some(start) if start > 0 && is_edition(&name[start..]) => {
+                name = name[..start].trim_end();
+            }
+            _ => return name.to_string(),
+        }
+    }
+}
+
+fn is_edition(suffix: &str) -> bool {
+    let suffix = suffix.to_lowercase();
+    suffix
+        .split(|char: char| !char.is_alphanumeric())
+        .any(|word| {
+            EDITION_KEYWORDS
+                .iter()
+                .any(|keyword| word.starts_with(keyword))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn editions() {
+        let normalizer = Normalizer::new(&[]);
+        assert_eq!(normalizer.clean("Abbey Road (Remastered)"), "Abbey Road");
+        assert_eq!(normalizer.clean("Rumours [2004 Remaster]"), "Rumours");
+        assert_eq!(
+            normalizer.clean("Nevermind (Deluxe Edition) [Remastered]"),
+            "Nevermind"
+        );
+        assert_eq!(
+            normalizer.clean("Pet Sounds - Remastered 2012"),
+            "Pet Sounds"
+        );
+        assert_eq!(
+            normalizer.clean("Red (Taylor's Version)"),
+            "Red (Taylor's Version)"
+        );
+        assert_eq!(normalizer.clean("(Deluxe Edition)"), "(Deluxe Edition)");
+    }
+
+    #[test]
+    fn keys() {
+        let normalizer = Normalizer::new(&[]);
+        assert_eq!(
+            normalizer.parse_album_id("Radiohead - OK Computer OKNOTOK (Remastered)"),
+            "radiohead - ok computer oknotok"
+        );
+        // Full-width letters and the ligature are compatibility characters
+        assert_eq!(
+            normalizer.parse_album_id("Ｂｊörk - ﬁrst"),
+            normalizer.parse_album_id("Björk - first")
+        );
+        // Album named like an edition
+        assert_eq!(
+            normalizer.parse_album_id("Artist - Deluxe"),
+            "artist - deluxe"
+        );
+        let track = Track {
+            album_artist: String::from("Radiohead"),
+            album: String::from("OK Computer OKNOTOK (Remastered)"),
+            ..Track::default()
+        };
+        assert_eq!(
+            normalizer.album_id(&track),
+            normalizer.parse_album_id(&track.album_id())
+        );
+
+        let track = Track {
+            artist: String::from("Daft Punk"),
+            title: String::from("One More Time - Remastered 2021"),
+            ..Track::default()
+        };
+        assert_eq!(
+            normalizer.track_id(&track),
+            "track: daft punk - one more time"
+        );
+        assert_eq!(
+            normalizer.parse_album_id("track: Daft Punk - One More Time"),
+            normalizer.track_id(&track)
+        );
+    }
+
+    #[test]
+    fn rules() {
+        let normalizer = Normalizer::new(&[String::from("none")]);
+        assert_eq!(
+            normalizer.parse_album_id("Radiohead - OK Computer (Deluxe)"),
+            "Radiohead - OK Computer (Deluxe)"
+        );
+
+        let normalizer = Normalizer::new(&[String::from("case")]);
+        assert_eq!(
+            normalizer.parse_album_id("Radiohead - OK Computer (Deluxe)"),
+            "radiohead - ok computer (deluxe)"
+        );
+    }
+}
@@ -0,0 +1,29 @@
+// Names accepted in the "cover_size" setting
+pub const SIZES: &[&str] = &["small", "medium", "large", "original"];
+
+// Preferred resolution of album covers and the Last.fm avatar. Providers without an image in this
+// size return the next smaller one, or the smallest larger one if there is nothing smaller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum CoverSize {
+    /// About 200 px
+    Small,
+    /// About 300-500 px, the size Discord shows
+    #[default]
+    Medium,
+    /// About 800-1200 px
+    Large,
+    /// Image as uploaded to the service
+    Original,
+}
+
+impl CoverSize {
+    // Names are checked when the settings are loaded, None is the default
+    pub fn from_name(name: Option<&str>) -> Self {
+        match name {
+            Some("small") => CoverSize::Small,
+            Some("large") => CoverSize::Large,
+            Some("original") => CoverSize::Original,
+            _ => CoverSize::Medium,
+        }
+    }
+}
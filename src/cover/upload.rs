@@ -0,0 +1,357 @@
+use reqwest::blocking::multipart;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::cover::cache::{CacheOptions, CoverCache};
+use crate::cover::CoverProvider;
+use crate::error::Error;
+use crate::presence::Track;
+
+pub const SERVICES: &[&str] = &["0x0", "catbox", "put"];
+
+// Covers larger than this are most likely not a cover and would be rejected by the hosts anyway
+const MAX_IMAGE_SIZE: usize = 10 * 1024 * 1024;
+
+// Sending a large image takes longer than the usual API request
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct UploadSettings {
+    /// "0x0", "catbox" or "put"
+    pub service: String,
+    /// Endpoint the image is sent to
+    pub url: String,
+    /// Base of the public link for "put", by default the same as the endpoint
+    pub public_url: Option<String>,
+    /// Value of the Authorization header
+    pub authorization: Option<String>,
+}
+
+impl UploadSettings {
+    pub fn default_url(service: &str) -> Option<&'static str> {
+        match service {
+            "0x0" => Some("https://0x0.st"),
+            "catbox" => Some("https://catbox.moe/user/api.php"),
+            _ => None,
+        }
+    }
+}
+
+// Uploads covers of players that only expose local files (file:// artUrl or art embedded in the
+// track). Each image is uploaded once, the links are saved in the cover cache by the SHA-256 of
+// the image.
+pub struct UploadProvider {
+    settings: UploadSettings,
+    client: reqwest::blocking::Client,
+    uploads: CoverCache,
+}
+
+impl UploadProvider {
+    pub fn new(
+        settings: UploadSettings,
+        client: reqwest::blocking::Client,
+        cache_dir: Option<&Path>,
+    ) -> Self {
+        // Own connection to the cache file, the cover finder keeps the other one
+        let uploads = match cache_dir.map(|dir| CoverCache::load(dir, CacheOptions::default())) {
+            Some(Ok(cache)) => cache,
+            Some(Err(err)) => {
+                println!("[cache] error, unable to open cache file: {}", err);
+                CoverCache::disabled()
+            }
+            None => CoverCache::disabled(),
+        };
+
+        UploadProvider {
+            settings,
+            client,
+            uploads,
+        }
+    }
+
+    fn upload(&self, image: &Image, hash: &str) -> Result<String, Error> {
+        let file_name = format!("{}.{}", hash, image.extension);
+        let mut request = match self.settings.service.as_str() {
+            "0x0" => {
+                let part = multipart::Part::bytes(image.data.clone())
+                    .file_name(file_name.clone())
+                    .mime_str(image.mime)?;
+                self.client
+                    .post(&self.settings.url)
+                    .multipart(multipart::Form::new().part("file", part))
+            }
+            "catbox" => {
+                let part = multipart::Part::bytes(image.data.clone())
+                    .file_name(file_name.clone())
+                    .mime_str(image.mime)?;
+                self.client.post(&self.settings.url).multipart(
+                    multipart::Form::new()
+                        .text("reqtype", "fileupload")
+                        .part("fileToUpload", part),
+                )
+            }
+            _ => self
+                .client
+                .put(format!(
+                    "{}/{}",
+                    self.settings.url.trim_end_matches('/'),
+                    file_name
+                ))
+                .header("Content-Type", image.mime)
+                .body(image.data.clone()),
+        };
+        if let Some(authorization) = &self.settings.authorization {
+            request = request.header("Authorization", authorization);
+        }
+
+        let response = request.timeout(UPLOAD_TIMEOUT).send()?.error_for_status()?;
+
+        // Simple hosts answer with the link, for PUT the link is known in advance
+        let url = match self.settings.service.as_str() {
+            "put" => format!(
+                "{}/{}",
+                self.settings
+                    .public_url
+                    .as_deref()
+                    .unwrap_or(&self.settings.url)
+                    .trim_end_matches('/'),
+                file_name
+            ),
+            _ => response.text()?.trim().to_string(),
+        };
+
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            return Err(Error::Http(format!("Unexpected upload response: {}", url)));
+        }
+        Ok(url)
+    }
+}
+
+impl CoverProvider for UploadProvider {
+    fn name(&self) -> &'static str {
+        "upload"
+    }
+
+    fn fetch(&mut self, track: &Track) -> Result<Option<String>, Error> {
+        let image = match local_image(track) {
+            Some(image) => image,
+            None => return Ok(None),
+        };
+
+        let hash = format!("{:x}", Sha256::digest(&image.data));
+        if let Some(url) = self.uploads.get_upload(&hash) {
+            return Ok(Some(url));
+        }
+
+        let url = self.upload(&image, &hash)?;
+        println!("[upload] uploaded cover: {}", url);
+        if let Err(err) = self.uploads.set_upload(&hash, &url) {
+            println!("[cache] error, unable to write to cache file: {}", err);
+        }
+        Ok(Some(url))
+    }
+
+    // Local covers don't depend on the album name
+    fn fetch_without_album(&mut self, track: &Track) -> Result<Option<String>, Error> {
+        self.fetch(track)
+    }
+}
+
+struct Image {
+    data: Vec<u8>,
+    mime: &'static str,
+    extension: &'static str,
+}
+
+impl Image {
+    // Type is recognized by the content, file extensions of cached covers are often missing
+    fn new(data: Vec<u8>) -> Option<Self> {
+        if data.is_empty() || data.len() > MAX_IMAGE_SIZE {
+            return None;
+        }
+        let (mime, extension) = if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            ("image/jpeg", "jpg")
+        } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            ("image/png", "png")
+        } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+            ("image/webp", "webp")
+        } else if data.starts_with(b"GIF8") {
+            ("image/gif", "gif")
+        } else {
+            return None;
+        };
+        Some(Image {
+            data,
+            mime,
+            extension,
+        })
+    }
+}
+
+// Local cover file from artUrl or the picture embedded in the track file
+fn local_image(track: &Track) -> Option<Image> {
+    if let Some(path) = track.art_url.as_deref().and_then(file_path) {
+        if let Some(image) = fs::read(&path).ok().and_then(Image::new) {
+            return Some(image);
+        }
+    }
+
+    let path = track.url.as_deref().and_then(file_path)?;
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    let data = match extension.as_str() {
+        "mp3" => id3::Tag::read_from_path(&path)
+            .ok()
+            .and_then(|tag| front_cover_id3(&tag)),
+        "flac" => File::open(&path)
+            .ok()
+            .and_then(|file| front_cover_flac(BufReader::new(file))),
+        _ => None,
+    };
+    data.and_then(Image::new)
+}
+
+fn file_path(url: &str) -> Option<PathBuf> {
+    let path = url.strip_prefix("file://")?;
+    Some(PathBuf::from(url_escape::decode(path).to_string()))
+}
+
+fn front_cover_id3(tag: &id3::Tag) -> Option<Vec<u8>> {
+    let pictures: Vec<&id3::frame::Picture> = tag.pictures().collect();
+    pictures
+        .iter()
+        .find(|picture| picture.picture_type == id3::frame::PictureType::CoverFront)
+        .or_else(|| pictures.first())
+        .map(|picture| picture.data.clone())
+}
+
+// FLAC stores pictures in METADATA_BLOCK_PICTURE blocks before the audio frames. Only the
+// metadata blocks are read, the audio of hi-res files can be hundreds of MB.
+fn front_cover_flac(mut reader: impl Read + Seek) -> Option<Vec<u8>> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic).ok()?;
+    if &magic != b"fLaC" {
+        return None;
+    }
+
+    let mut pictures: Vec<(u32, Vec<u8>)> = Vec::new();
+    loop {
+        let mut header = [0; 4];
+        reader.read_exact(&mut header).ok()?;
+        let is_last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7F;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+
+        if block_type == 6 {
+            let mut block = vec![0; length as usize];
+            reader.read_exact(&mut block).ok()?;
+            if let Some(picture) = parse_flac_picture(&block) {
+                pictures.push(picture);
+            }
+        } else {
+            reader.seek(SeekFrom::Current(length as i64)).ok()?;
+        }
+
+        if is_last {
+            break;
+        }
+    }
+
+    // Picture type 3 is the front cover
+    let index = pictures
+        .iter()
+        .position(|(picture_type, _)| *picture_type == 3)
+        .unwrap_or(0);
+    if index < pictures.len() {
+        Some(pictures.swap_remove(index).1)
+    } else {
+        None
+    }
+}
+
+fn parse_flac_picture(block: &[u8]) -> Option<(u32, Vec<u8>)> {
+    let read_u32 = |offset: usize| -> Option<u32> {
+        let bytes = block.get(offset..offset + 4)?;
+        Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+
+    let picture_type = read_u32(0)?;
+    let mime_length = read_u32(4)? as usize;
+    let description_length = read_u32(8 + mime_length)? as usize;
+    // Width, height, color depth and number of colors
+    let data_offset = 12 + mime_length + description_length + 16;
+    let data_length = read_u32(data_offset)? as usize;
+    let data = block.get(data_offset + 4..data_offset + 4 + data_length)?;
+    Some((picture_type, data.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const JPEG: &[u8] = &[0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10];
+
+    fn flac_block(block_type: u8, is_last: bool, data: &[u8]) -> Vec<u8> {
+        let length = (data.len() as u32).to_be_bytes();
+        let mut block = vec![block_type | if is_last { 0x80 } else { 0 }];
+        block.extend_from_slice(&length[1..]);
+        block.extend_from_slice(data);
+        block
+    }
+
+    fn flac_picture(picture_type: u32, data: &[u8]) -> Vec<u8> {
+        let mut picture = Vec::new();
+        picture.extend_from_slice(&picture_type.to_be_bytes());
+        picture.extend_from_slice(&10u32.to_be_bytes());
+        picture.extend_from_slice(b"image/jpeg");
+        picture.extend_from_slice(&0u32.to_be_bytes());
+        picture.extend_from_slice(&[0; 16]);
+        picture.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        picture.extend_from_slice(data);
+        picture
+    }
+
+    #[test]
+    fn flac_front_cover() {
+        let mut file = b"fLaC".to_vec();
+        file.extend(flac_block(0, false, &[0; 34]));
+        file.extend(flac_block(6, false, &flac_picture(4, b"back")));
+        file.extend(flac_block(6, true, &flac_picture(3, JPEG)));
+        file.extend_from_slice(&[0xFF, 0xF8]);
+
+        assert_eq!(front_cover_flac(Cursor::new(file)), Some(JPEG.to_vec()));
+    }
+
+    #[test]
+    fn flac_without_pictures() {
+        let mut file = b"fLaC".to_vec();
+        file.extend(flac_block(0, true, &[0; 34]));
+
+        assert_eq!(front_cover_flac(Cursor::new(file)), None);
+        assert_eq!(front_cover_flac(Cursor::new(b"ID3")), None);
+        // Truncated file
+        assert_eq!(front_cover_flac(Cursor::new(b"fLaC\x06\x00")), None);
+    }
+
+    #[test]
+    fn image_type() {
+        assert_eq!(Image::new(JPEG.to_vec()).unwrap().extension, "jpg");
+        assert_eq!(
+            Image::new(b"\x89PNG\r\n\x1a\n".to_vec()).unwrap().mime,
+            "image/png"
+        );
+        assert!(Image::new(b"<html>".to_vec()).is_none());
+    }
+
+    #[test]
+    fn file_urls() {
+        assert_eq!(
+            file_path("file:///home/user/Music/Cover%20Art.jpg"),
+            Some(PathBuf::from("/home/user/Music/Cover Art.jpg"))
+        );
+        assert_eq!(file_path("https://example.com/cover.jpg"), None);
+    }
+}
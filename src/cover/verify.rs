@@ -0,0 +1,85 @@
+use reqwest::StatusCode;
+use std::time::Duration;
+
+// Pause between the checks of "cache verify"
+pub const BULK_CHECK_DELAY: Duration = Duration::from_millis(500);
+
+// Smallest pause between the checks done while playing, one album is checked at a time
+pub const PLAYBACK_CHECK_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkStatus {
+    Alive,
+    /// Image was removed, the reason is the HTTP status
+    Dead(String),
+    /// Timeout, server error or anything else that doesn't prove the image is gone
+    Unknown(String),
+}
+
+// Only "not found" answers remove a cover, so an offline computer or a service outage
+// doesn't empty the cache
+pub fn check_link(client: &reqwest::blocking::Client, url: &str) -> LinkStatus {
+    let status = match client.head(url).send() {
+        // Some image hosts don't answer HEAD requests, the body of GET is never read
+        Ok(response) if response.status() == StatusCode::METHOD_NOT_ALLOWED => {
+            match client.get(url).send() {
+                Ok(response) => response.status(),
+                Err(err) => return LinkStatus::Unknown(err.to_string()),
+            }
+        }
+        Ok(response) => response.status(),
+        Err(err) => return LinkStatus::Unknown(err.to_string()),
+    };
+
+    if status.is_success() {
+        LinkStatus::Alive
+    } else if status == StatusCode::NOT_FOUND || status == StatusCode::GONE {
+        LinkStatus::Dead(status.to_string())
+    } else {
+        LinkStatus::Unknown(format!("unexpected response: {}", status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Answers every request with the given status line
+    fn server(status: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+            }
+        });
+        format!("http://{}/cover.jpg", address)
+    }
+
+    #[test]
+    fn link_status() {
+        let client = reqwest::blocking::Client::new();
+        assert_eq!(check_link(&client, &server("200 OK")), LinkStatus::Alive);
+        assert_eq!(
+            check_link(&client, &server("404 Not Found")),
+            LinkStatus::Dead(String::from("404 Not Found"))
+        );
+        assert!(matches!(
+            check_link(&client, &server("503 Service Unavailable")),
+            LinkStatus::Unknown(_)
+        ));
+    }
+}
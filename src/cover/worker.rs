@@ -0,0 +1,208 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cover::{Cover, CoverFinder};
+use crate::events::Event;
+use crate::presence::Track;
+
+// Cached covers are found almost instantly, waiting a moment for them avoids setting the activity
+// twice for every track
+const QUICK_LOOKUP: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, PartialEq)]
+struct Request {
+    track: Track,
+    use_art_url: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lookup {
+    /// Still being looked up, use a placeholder for now
+    Pending,
+    /// Lookup finished, None if no provider had a cover
+    Done(Option<Cover>),
+}
+
+// Looks up covers on a separate thread, so slow providers don't delay the activity.
+// Finished lookups wake up the main loop through the events channel.
+pub struct CoverWorker {
+    requests: Sender<Request>,
+    results: Receiver<(Request, Option<Cover>)>,
+    // Last requested track and its result
+    current: Option<(Request, Lookup)>,
+    // The player's cover is only a placeholder if the lookup can return it too, otherwise it
+    // would be replaced by "missing-cover" once the lookup finishes
+    art_url_placeholder: bool,
+}
+
+impl CoverWorker {
+    pub fn spawn(mut finder: CoverFinder, events: Sender<Event>) -> Self {
+        let art_url_placeholder = finder.has_provider("mpris");
+        let (requests, request_receiver) = mpsc::channel::<Request>();
+        let (result_sender, results) = mpsc::channel();
+
+        thread::spawn(move || {
+            while let Ok(mut request) = request_receiver.recv() {
+                // Tracks skipped during a slow lookup don't need a cover anymore
+                while let Ok(newer) = request_receiver.try_recv() {
+                    request = newer;
+                }
+
+                let cover = finder.find(&request.track, request.use_art_url);
+                if result_sender.send((request, cover)).is_err() {
+                    return;
+                }
+                let _ = events.send(Event::CoverFound);
+            }
+        });
+
+        CoverWorker {
+            requests,
+            results,
+            current: None,
+            art_url_placeholder,
+        }
+    }
+
+    // Starts the lookup for a new track and returns the result once it's done. Asking again
+    // for the same track doesn't start another lookup.
+    pub fn cover(&mut self, track: &Track, use_art_url: bool) -> Lookup {
+        let request = Request {
+            track: track.clone(),
+            use_art_url,
+        };
+
+        let is_new = match &self.current {
+            Some((current, _)) => *current != request,
+            None => true,
+        };
+        if is_new {
+            if self.requests.send(request.clone()).is_err() {
+                println!("[cover] Cover worker stopped, album covers are not available.");
+                self.current = Some((request, Lookup::Done(None)));
+            } else {
+                self.current = Some((request, Lookup::Pending));
+                self.wait_for_result(QUICK_LOOKUP);
+            }
+        } else {
+            self.wait_for_result(Duration::ZERO);
+        }
+
+        match &self.current {
+            Some((_, lookup)) => lookup.clone(),
+            None => Lookup::Pending,
+        }
+    }
+
+    // Results of tracks that are no longer played are dropped
+    fn wait_for_result(&mut self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let (current, lookup) = match &mut self.current {
+            Some((current, lookup @ Lookup::Pending)) => (current, lookup),
+            _ => return,
+        };
+
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.results.recv_timeout(timeout) {
+                Ok((request, cover)) if request == *current => {
+                    *lookup = Lookup::Done(cover);
+                    return;
+                }
+                Ok(_) => {}
+                Err(_) => return,
+            }
+        }
+    }
+
+    // Shown until the lookup finishes: the player's own cover if Discord can load it
+    pub fn placeholder(&self, track: &Track, use_art_url: bool) -> String {
+        match &track.art_url {
+            Some(url) if self.art_url_placeholder && use_art_url && url.starts_with("http") => {
+                url.clone()
+            }
+            _ => String::from("missing-cover"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cover::cache::CoverCache;
+    use crate::cover::normalize::Normalizer;
+    use crate::cover::size::CoverSize;
+    use crate::events::Events;
+
+    #[test]
+    fn looks_up_in_background() {
+        let finder = CoverFinder::new(
+            &[String::from("mpris")],
+            &reqwest::blocking::Client::new(),
+            None,
+            None,
+            Normalizer::new(&[]),
+            CoverSize::default(),
+            CoverCache::disabled(),
+            None,
+            false,
+        );
+        let events = Events::new();
+        let mut worker = CoverWorker::spawn(finder, events.sender());
+        let track = Track {
+            title: String::from("Paranoid Android"),
+            artist: String::from("Radiohead"),
+            album: String::from("OK Computer"),
+            album_artist: String::from("Radiohead"),
+            art_url: Some(String::from("https://example.com/cover.jpg")),
+            ..Track::default()
+        };
+
+        let mut lookup = worker.cover(&track, true);
+        for _ in 0..50 {
+            if lookup != Lookup::Pending {
+                break;
+            }
+            events.wait(Duration::from_millis(100));
+            lookup = worker.cover(&track, true);
+        }
+        assert_eq!(
+            lookup,
+            Lookup::Done(Some(Cover {
+                url: String::from("https://example.com/cover.jpg"),
+                provider: String::from("mpris"),
+            }))
+        );
+
+        // The player's cover is not used if it's disabled
+        assert_eq!(worker.placeholder(&track, false), "missing-cover");
+        assert_eq!(
+            worker.placeholder(&track, true),
+            "https://example.com/cover.jpg"
+        );
+    }
+
+    #[test]
+    fn placeholder_without_mpris_provider() {
+        let finder = CoverFinder::new(
+            &[String::from("musicbrainz")],
+            &reqwest::blocking::Client::new(),
+            None,
+            None,
+            Normalizer::new(&[]),
+            CoverSize::default(),
+            CoverCache::disabled(),
+            None,
+            false,
+        );
+        let worker = CoverWorker::spawn(finder, Events::new().sender());
+        let track = Track {
+            art_url: Some(String::from("https://example.com/cover.jpg")),
+            ..Track::default()
+        };
+
+        // The lookup never returns the player's cover, "missing-cover" must not replace it later
+        assert_eq!(worker.placeholder(&track, true), "missing-cover");
+    }
+}
@@ -0,0 +1,578 @@
+use discord_rich_presence::activity::Activity;
+use discord_rich_presence::DiscordIpc;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process;
+use std::time::Instant;
+
+use crate::backoff::Backoff;
+use crate::error::Error;
+
+mod response;
+pub mod socket;
+pub mod websocket;
+
+use response::Response;
+use socket::IpcClient;
+use websocket::WebSocketClient;
+
+// Names accepted in the "discord_transport" setting
+pub const TRANSPORTS: &[&str] = &["ipc", "websocket"];
+
+// Default Discord applications for music and video players
+pub const AUDIO_APP_ID: &str = "1129859263741837373";
+pub const VIDEO_APP_ID: &str = "1356756023813210293";
+
+// Discord pings idle IPC connections and expects the payload back as a pong
+const OPCODE_PING: u32 = 3;
+const OPCODE_PONG: u8 = 4;
+
+// Responses to older commands (e.g. one that timed out) that are skipped while waiting
+const MAX_SKIPPED_RESPONSES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connection {
+    /// First connection of the application
+    Connected,
+    /// Application was connected before
+    Reconnected,
+    /// Every link of the application was still open, nothing was reconnected
+    Open,
+}
+
+// Connection to a Discord client. The activity commands of DiscordIpc are the same on every
+// transport, only the way they are delivered differs.
+pub trait Transport: DiscordIpc {
+    // Where the transport is connected, shown after connecting
+    fn name(&self) -> String;
+
+    // Called after "connect_ipc", returns the READY event or the reason the connection was refused
+    fn handshake(&mut self) -> Result<(u32, Value), Box<dyn std::error::Error>> {
+        let handshake = json!({ "v": 1, "client_id": self.get_client_id() });
+        self.send(handshake, 0)?;
+        self.recv()
+    }
+}
+
+// Where the clients connect to, from the "discord_*" settings
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    /// Unix IPC socket of the desktop client, None searches the known locations
+    Ipc(Option<PathBuf>),
+    /// WebSocket of an RPC server like arRPC, None tries the ports Discord uses
+    WebSocket(String, Option<u16>),
+}
+
+// Connection of an application to one Discord client, every link is reconnected on its own
+struct Link {
+    client: Box<dyn Transport>,
+    // Socket this link is bound to in broadcast mode, None uses the first socket found
+    socket: Option<PathBuf>,
+    // Socket was opened once, later connections close the old one first
+    connected: bool,
+    // False after a failed write until the link is connected again
+    open: bool,
+    // Discord user from the READY event
+    user: Option<String>,
+    is_activity_set: bool,
+    backoff: Backoff,
+    retry_at: Option<Instant>,
+}
+
+impl Link {
+    fn new(client: Box<dyn Transport>, socket: Option<PathBuf>) -> Self {
+        Link {
+            client,
+            socket,
+            connected: false,
+            open: false,
+            user: None,
+            is_activity_set: false,
+            backoff: Backoff::new(crate::RECONNECT_MIN_DELAY, crate::RECONNECT_MAX_DELAY),
+            retry_at: None,
+        }
+    }
+
+    fn connect(&mut self) -> Result<Connection, Error> {
+        let connection = if self.connected {
+            Connection::Reconnected
+        } else {
+            Connection::Connected
+        };
+
+        match self.handshake() {
+            Ok(user) => {
+                self.connected = true;
+                self.open = true;
+                self.user = user;
+                self.is_activity_set = false;
+                self.backoff.reset();
+                self.retry_at = None;
+                Ok(connection)
+            }
+            Err(err) => {
+                self.failed();
+                Err(err)
+            }
+        }
+    }
+
+    // Opens the socket (closing the old one first) and returns the user Discord is logged in as
+    fn handshake(&mut self) -> Result<Option<String>, Error> {
+        if self.connected {
+            self.client.close().map_err(Error::discord)?;
+        }
+        self.client.connect_ipc().map_err(Error::discord)?;
+
+        let (opcode, data) = self.client.handshake().map_err(Error::discord)?;
+        match Response::parse(opcode, &data) {
+            Response::Ready(user) => Ok(user),
+            Response::Ok => Ok(None),
+            Response::Error(code, message) => {
+                let _ = self.client.close();
+                Err(Error::Discord(format!(
+                    "connection refused: {} ({})",
+                    message, code
+                )))
+            }
+        }
+    }
+
+    // Sends a command and waits for the response with the same nonce
+    fn command(&mut self, cmd: &str, args: Value) -> Result<Response, Error> {
+        let nonce = format!("{:032x}", fastrand::u128(..));
+        let command = json!({ "cmd": cmd, "args": args, "nonce": nonce });
+        self.client.send(command, 1).map_err(Error::discord)?;
+
+        for _ in 0..MAX_SKIPPED_RESPONSES {
+            let (opcode, data) = self.client.recv().map_err(Error::discord)?;
+            if opcode == OPCODE_PING {
+                self.client
+                    .send(data, OPCODE_PONG)
+                    .map_err(Error::discord)?;
+                continue;
+            }
+            if response::nonce(&data).is_some_and(|other| other != nonce) {
+                continue;
+            }
+            return Ok(Response::parse(opcode, &data));
+        }
+        Err(Error::Discord(format!("no response to {}", cmd)))
+    }
+
+    // Closed links are tried again after their own backoff delay
+    fn due(&self) -> bool {
+        !self.open
+            && self
+                .retry_at
+                .is_none_or(|retry_at| Instant::now() >= retry_at)
+    }
+
+    fn failed(&mut self) {
+        self.open = false;
+        self.retry_at = Some(Instant::now() + self.backoff.next_delay());
+    }
+
+    // Fields Discord rejects (e.g. a small image that is not an asset of the application) are
+    // removed and the activity is sent again. Other rejections are returned as Error::Rejected or
+    // Error::RateLimited and keep the link open.
+    fn set_activity(&mut self, activity: Activity) -> Result<(), Error> {
+        let mut activity =
+            serde_json::to_value(activity).map_err(|err| Error::Discord(err.to_string()))?;
+        self.is_activity_set = false;
+
+        loop {
+            let args = json!({ "pid": process::id(), "activity": activity });
+            match self.command("SET_ACTIVITY", args) {
+                Ok(Response::Error(code, message)) => {
+                    let Some(field) = response::drop_rejected_field(&mut activity, &message) else {
+                        let message = format!("{} ({})", message, code);
+                        return Err(if response::is_rate_limit(&message) {
+                            Error::RateLimited(message)
+                        } else {
+                            Error::Rejected(message)
+                        });
+                    };
+                    println!(
+                        "Discord rejected the {} of the activity at {}, sending it without: {}",
+                        field,
+                        self.name(),
+                        message
+                    );
+                }
+                Ok(_) => {
+                    self.is_activity_set = true;
+                    return Ok(());
+                }
+                Err(err) => {
+                    // Socket is most likely already closed, it's opened again by "connect" or "refresh"
+                    let _ = self.client.close();
+                    self.failed();
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    // Reconnects once if the socket was closed, the activity is gone anyway if that fails
+    fn clear_activity(&mut self) {
+        if !self.is_activity_set {
+            return;
+        }
+
+        let args = json!({ "pid": process::id(), "activity": null });
+        if self.command("SET_ACTIVITY", args.clone()).is_ok()
+            || (self.handshake().is_ok() && self.command("SET_ACTIVITY", args).is_ok())
+        {
+            self.is_activity_set = false;
+        }
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.open = false;
+        self.client.close().map_err(Error::discord)
+    }
+
+    fn name(&self) -> String {
+        self.client.name()
+    }
+
+    // Name with the Discord user, shown after connecting
+    fn label(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{} as {}", self.name(), user),
+            None => self.name(),
+        }
+    }
+}
+
+// Discord IPC clients keyed by application id. Every application needs its own connection and
+// the application name is shown in the activity header, so each player can have its own.
+// Clients are created and connected when a player first needs them, only the active one shows
+// an activity. In broadcast mode the active application connects to every running Discord
+// client (e.g. Stable, Canary and Vesktop at the same time) and the activity is sent to all.
+pub struct ClientPool {
+    apps: HashMap<String, Vec<Link>>,
+    endpoint: Endpoint,
+    // Only used with the IPC socket when no socket is pinned
+    broadcast: bool,
+    // Application of the current player
+    active: String,
+}
+
+impl ClientPool {
+    pub fn new(endpoint: Endpoint, broadcast: bool) -> Self {
+        let broadcast = broadcast && endpoint == Endpoint::Ipc(None);
+        ClientPool {
+            apps: HashMap::new(),
+            endpoint,
+            broadcast,
+            active: String::new(),
+        }
+    }
+
+    // The activity of the previous application is cleared and its connections closed,
+    // otherwise Discord would keep showing the old player
+    pub fn switch(&mut self, app_id: &str) {
+        if self.active == app_id {
+            return;
+        }
+
+        self.clear_activity();
+        for link in self.links() {
+            // Socket may already be closed, it is connected again when the player comes back
+            let _ = link.close();
+            link.connected = false;
+        }
+
+        self.apps.entry(app_id.to_string()).or_default();
+        self.active = app_id.to_string();
+    }
+
+    // Sockets the active application is attached to, shown after connecting
+    pub fn socket_name(&self) -> String {
+        let names: Vec<String> = self
+            .apps
+            .get(&self.active)
+            .into_iter()
+            .flatten()
+            .filter(|link| link.open)
+            .map(|link| link.label())
+            .collect();
+        if names.is_empty() {
+            return String::from("unknown socket");
+        }
+        names.join(", ")
+    }
+
+    // Connects or reconnects every link that is not open, fails only if no Discord client could
+    // be reached. Open links are kept as they are, a new handshake would clear their activity.
+    pub fn connect(&mut self) -> Result<Connection, Error> {
+        self.add_links();
+
+        let mut connection = None;
+        let mut last_error = None;
+        for link in self.links() {
+            if link.open {
+                connection.get_or_insert(Connection::Open);
+                continue;
+            }
+            match link.connect() {
+                Ok(Connection::Connected) => connection = Some(Connection::Connected),
+                Ok(_) if connection != Some(Connection::Connected) => {
+                    connection = Some(Connection::Reconnected)
+                }
+                Ok(_) => {}
+                Err(err) => last_error = Some(err),
+            }
+        }
+
+        connection.ok_or_else(|| {
+            last_error
+                .unwrap_or_else(|| Error::Discord(String::from("no Discord IPC socket found")))
+        })
+    }
+
+    // Broadcast mode only: connects Discord clients started since the last call and the links
+    // whose retry delay passed. Returns the sockets that were connected, they have no activity yet.
+    pub fn refresh(&mut self) -> Vec<String> {
+        if !self.broadcast {
+            return Vec::new();
+        }
+        self.add_links();
+
+        self.links()
+            .filter(|link| link.due())
+            .filter_map(|link| link.connect().ok().map(|_| link.label()))
+            .collect()
+    }
+
+    // Succeeds if at least one Discord client got the activity, the others are retried later
+    pub fn set_activity(&mut self, activity: Activity) -> Result<(), Error> {
+        let mut is_set = false;
+        let mut last_error = None;
+        for link in self.links().filter(|link| link.open) {
+            match link.set_activity(activity.clone()) {
+                Ok(_) => is_set = true,
+                Err(err) => {
+                    println!("Could not set activity at {}: {}", link.name(), err);
+                    last_error = Some(err);
+                }
+            }
+        }
+
+        match (is_set, last_error) {
+            (true, _) => Ok(()),
+            (false, Some(err)) => Err(err),
+            (false, None) => Err(Error::Discord(String::from("not connected to Discord"))),
+        }
+    }
+
+    pub fn clear_activity(&mut self) {
+        for link in self.links() {
+            link.clear_activity();
+        }
+    }
+
+    // Sockets are most likely already closed after a failed write, the next "connect" opens them again
+    pub fn close(&mut self) -> Result<(), Error> {
+        let mut result = Ok(());
+        for link in self.links() {
+            if let Err(err) = link.close() {
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    fn links(&mut self) -> impl Iterator<Item = &mut Link> {
+        self.apps.get_mut(&self.active).into_iter().flatten()
+    }
+
+    // One link to the endpoint, or in broadcast mode one for every socket. Links of Discord
+    // clients that were closed are removed.
+    fn add_links(&mut self) {
+        let Some(links) = self.apps.get_mut(&self.active) else {
+            return;
+        };
+
+        if !self.broadcast {
+            if links.is_empty() {
+                let client: Box<dyn Transport> = match &self.endpoint {
+                    Endpoint::Ipc(socket) => Box::new(IpcClient::new(&self.active, socket.clone())),
+                    Endpoint::WebSocket(host, port) => {
+                        Box::new(WebSocketClient::new(&self.active, host, *port))
+                    }
+                };
+                links.push(Link::new(client, None));
+            }
+            return;
+        }
+
+        let sockets = socket::discover();
+        links.retain(|link| {
+            link.open
+                || link
+                    .socket
+                    .as_ref()
+                    .is_some_and(|socket| sockets.contains(socket))
+        });
+        for socket in sockets {
+            if !links
+                .iter()
+                .any(|link| link.socket.as_ref() == Some(&socket))
+            {
+                let client = IpcClient::new(&self.active, Some(socket.clone()));
+                links.push(Link::new(Box::new(client), Some(socket)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discord_rich_presence::activity::Assets;
+    use std::io::{Read, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::mpsc;
+    use std::{env, fs, thread};
+
+    fn read_frame(stream: &mut UnixStream) -> Option<(u32, Value)> {
+        let mut header = [0; 8];
+        stream.read_exact(&mut header).ok()?;
+        let opcode = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let mut data = vec![0; u32::from_le_bytes(header[4..].try_into().unwrap()) as usize];
+        stream.read_exact(&mut data).ok()?;
+        Some((opcode, serde_json::from_slice(&data).unwrap()))
+    }
+
+    fn write_frame(stream: &mut UnixStream, opcode: u32, data: Value) {
+        let data = data.to_string();
+        stream.write_all(&opcode.to_le_bytes()).unwrap();
+        stream
+            .write_all(&(data.len() as u32).to_le_bytes())
+            .unwrap();
+        stream.write_all(data.as_bytes()).unwrap();
+    }
+
+    // Stand-in for Discord: accepts the handshake and rejects activities with a small image,
+    // the received activities are forwarded
+    fn server(path: PathBuf) -> mpsc::Receiver<Value> {
+        let listener = UnixListener::bind(path).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_frame(&mut stream).unwrap();
+            let ready = json!({
+                "cmd": "DISPATCH",
+                "evt": "READY",
+                "data": {"v": 1, "user": {"username": "patryk", "global_name": "Patryk"}}
+            });
+            write_frame(&mut stream, 1, ready);
+
+            while let Some((_, command)) = read_frame(&mut stream) {
+                let activity = command["args"]["activity"].clone();
+                let response = if activity["assets"].get("small_image").is_some() {
+                    json!({
+                        "cmd": "SET_ACTIVITY",
+                        "evt": "ERROR",
+                        "nonce": command["nonce"],
+                        "data": {"code": 4000, "message": "child \"small_image\" fails"}
+                    })
+                } else {
+                    json!({"cmd": "SET_ACTIVITY", "evt": null, "nonce": command["nonce"]})
+                };
+                // Response to an older command comes first and is skipped
+                write_frame(&mut stream, 1, json!({"nonce": "old", "evt": "ERROR"}));
+                write_frame(&mut stream, 1, response);
+                sender.send(activity).unwrap();
+            }
+        });
+        receiver
+    }
+
+    #[test]
+    fn rejected_small_image() {
+        let path = env::temp_dir().join(format!(
+            "mpris-discord-rpc-test-link-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        let activities = server(path.clone());
+
+        let client = IpcClient::new(AUDIO_APP_ID, Some(path.clone()));
+        let mut link = Link::new(Box::new(client), None);
+        assert_eq!(link.connect().unwrap(), Connection::Connected);
+        assert_eq!(link.label(), format!("{} as Patryk", path.display()));
+
+        let activity = Activity::new()
+            .details("Paranoid Android")
+            .assets(Assets::new().large_image("cover").small_image("missing"));
+        link.set_activity(activity).unwrap();
+        assert!(link.is_activity_set);
+
+        let rejected = activities.recv().unwrap();
+        assert_eq!(rejected["assets"]["small_image"], "missing");
+        let sent = activities.recv().unwrap();
+        assert!(sent["assets"].get("small_image").is_none());
+        assert_eq!(sent["assets"]["large_image"], "cover");
+
+        link.clear_activity();
+        assert!(!link.is_activity_set);
+        assert!(activities.recv().unwrap().is_null());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn connect_keeps_open_links() {
+        let path = env::temp_dir().join(format!(
+            "mpris-discord-rpc-test-pool-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        // The stand-in accepts a single connection, a second handshake would time out
+        let activities = server(path.clone());
+
+        let mut pool = ClientPool::new(Endpoint::Ipc(Some(path.clone())), false);
+        pool.switch(AUDIO_APP_ID);
+        assert_eq!(pool.connect().unwrap(), Connection::Connected);
+        pool.set_activity(Activity::new().details("Paranoid Android"))
+            .unwrap();
+        assert_eq!(activities.recv().unwrap()["details"], "Paranoid Android");
+
+        // Another player of the same application was found
+        pool.switch(AUDIO_APP_ID);
+        assert_eq!(pool.connect().unwrap(), Connection::Open);
+        pool.set_activity(Activity::new().details("Airbag"))
+            .unwrap();
+        assert_eq!(activities.recv().unwrap()["details"], "Airbag");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let path = env::temp_dir().join(format!(
+            "mpris-discord-rpc-test-oversized-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_frame(&mut stream).unwrap();
+            // Header of a 4 GiB frame instead of READY
+            stream.write_all(&1u32.to_le_bytes()).unwrap();
+            stream.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        });
+
+        let client = IpcClient::new(AUDIO_APP_ID, Some(path.clone()));
+        let mut link = Link::new(Box::new(client), None);
+        match link.connect() {
+            Err(Error::Discord(message)) => assert!(message.contains("too large"), "{}", message),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+use serde_json::Value;
+
+// Opcode Discord closes the IPC socket with, e.g. after a handshake with an invalid client id
+const OPCODE_CLOSE: u32 = 2;
+
+// Activity fields that can be left out if Discord rejects them, the first one named in the error
+// is removed. Texts and the cover are required for a useful activity and are kept.
+const OPTIONAL_FIELDS: &[&str] = &["small_image", "small_text", "buttons", "large_text"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// Handshake was accepted, contains the name of the logged in user if Discord sent it
+    Ready(Option<String>),
+    /// Command was executed
+    Ok,
+    /// Handshake or command was rejected
+    Error(i64, String),
+}
+
+impl Response {
+    pub fn parse(opcode: u32, data: &Value) -> Self {
+        if opcode == OPCODE_CLOSE || data["evt"] == "ERROR" {
+            let error = if opcode == OPCODE_CLOSE {
+                data
+            } else {
+                &data["data"]
+            };
+            return Response::Error(
+                error["code"].as_i64().unwrap_or_default(),
+                error["message"]
+                    .as_str()
+                    .unwrap_or("unknown error")
+                    .to_string(),
+            );
+        }
+
+        if data["evt"] == "READY" {
+            let user = &data["data"]["user"];
+            return Response::Ready(
+                user["global_name"]
+                    .as_str()
+                    .or(user["username"].as_str())
+                    .map(String::from),
+            );
+        }
+
+        Response::Ok
+    }
+}
+
+// Nonce of the command this is the response to, READY has none
+pub fn nonce(data: &Value) -> Option<&str> {
+    data["nonce"].as_str()
+}
+
+// Discord has no error code for it, only the message tells
+pub fn is_rate_limit(message: &str) -> bool {
+    let message = message.to_lowercase();
+    message.contains("rate limit") || message.contains("ratelimit")
+}
+
+// Removes the optional field named in Discord's error message (e.g. an asset key that doesn't
+// exist or a text that is too long) and returns its name, None if no known field was rejected
+pub fn drop_rejected_field(activity: &mut Value, message: &str) -> Option<&'static str> {
+    for field in OPTIONAL_FIELDS {
+        if !message.contains(field) {
+            continue;
+        }
+
+        let object = match *field {
+            "buttons" => activity.as_object_mut(),
+            _ => activity.get_mut("assets").and_then(Value::as_object_mut),
+        };
+        if object.and_then(|object| object.remove(*field)).is_some() {
+            return Some(field);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_responses() {
+        let ready = json!({
+            "cmd": "DISPATCH",
+            "evt": "READY",
+            "data": {"v": 1, "user": {"id": "1", "username": "patryk", "global_name": null}}
+        });
+        assert_eq!(
+            Response::parse(1, &ready),
+            Response::Ready(Some(String::from("patryk")))
+        );
+
+        let error = json!({
+            "cmd": "SET_ACTIVITY",
+            "evt": "ERROR",
+            "nonce": "1",
+            "data": {"code": 4000, "message": "child \"small_image\" fails"}
+        });
+        assert_eq!(
+            Response::parse(1, &error),
+            Response::Error(4000, String::from("child \"small_image\" fails"))
+        );
+        assert_eq!(nonce(&error), Some("1"));
+
+        let closed = json!({"code": 4000, "message": "Invalid Client ID"});
+        assert_eq!(
+            Response::parse(2, &closed),
+            Response::Error(4000, String::from("Invalid Client ID"))
+        );
+
+        let set = json!({"cmd": "SET_ACTIVITY", "evt": null, "nonce": "2", "data": {}});
+        assert_eq!(Response::parse(1, &set), Response::Ok);
+    }
+
+    #[test]
+    fn drop_fields() {
+        let mut activity = json!({
+            "details": "Paranoid Android",
+            "assets": {"large_image": "cover", "small_image": "bad", "small_text": "Player"},
+            "buttons": [{"label": "Last.fm", "url": "https://www.last.fm"}]
+        });
+        let message = "child \"activity\" fails because [child \"assets\" fails because \
+                       [child \"small_image\" fails because [\"small_image\" is not allowed]]]";
+
+        assert_eq!(
+            drop_rejected_field(&mut activity, message),
+            Some("small_image")
+        );
+        assert!(activity["assets"].get("small_image").is_none());
+        // Field is gone, the same error can't remove anything else
+        assert_eq!(drop_rejected_field(&mut activity, message), None);
+
+        assert_eq!(
+            drop_rejected_field(&mut activity, "\"buttons\" must contain at most 2 items"),
+            Some("buttons")
+        );
+        assert!(activity.get("buttons").is_none());
+        assert_eq!(drop_rejected_field(&mut activity, "rate limited"), None);
+        assert!(is_rate_limit("You are being rate limited."));
+        assert!(!is_rate_limit(message));
+        assert_eq!(activity["assets"]["large_image"], "cover");
+    }
+}
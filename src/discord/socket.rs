@@ -0,0 +1,280 @@
+use discord_rich_presence::DiscordIpc;
+use serde_json::{json, Value};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::discord::Transport;
+use crate::error;
+
+// Directories Discord creates its socket in, the first one that is set is used by the official client
+const ENV_KEYS: &[&str] = &["XDG_RUNTIME_DIR", "TMPDIR", "TMP", "TEMP"];
+
+// Sandboxed clients put the socket in their own directory below the runtime directory
+const APP_SUBPATHS: &[&str] = &[
+    "",
+    "app/com.discordapp.Discord/",
+    "app/com.discordapp.DiscordCanary/",
+    "app/dev.vencord.Vesktop/",
+    ".flatpak/dev.vencord.Vesktop/xdg-run/",
+    "snap.discord/",
+    "snap.discord-canary/",
+];
+
+// Every running client takes the first free "discord-ipc-<n>"
+const SOCKET_COUNT: u8 = 10;
+
+// Discord answers every command right away, a client that hangs must not block the main loop
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Discord responses are a few KB. The length comes from the frame header, a larger one is a broken
+// or foreign socket and must not be allocated.
+const MAX_FRAME_SIZE: u32 = 1024 * 1024;
+
+// Socket paths in the order they are tried, every directory from ENV_KEYS is searched
+pub fn candidates() -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    for key in ENV_KEYS {
+        if let Ok(dir) = env::var(key) {
+            let dir = PathBuf::from(dir);
+            if !dir.as_os_str().is_empty() && !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+    }
+    if !dirs.contains(&PathBuf::from("/tmp")) {
+        dirs.push(PathBuf::from("/tmp"));
+    }
+    candidates_in(&dirs)
+}
+
+// Sockets that exist right now, one socket linked from several places (e.g. the old Flatpak
+// symlink workaround) is listed once
+pub fn discover() -> Vec<PathBuf> {
+    discover_in(candidates())
+}
+
+fn discover_in(candidates: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut sockets = Vec::new();
+    let mut targets = Vec::new();
+    for path in candidates {
+        let is_socket = fs::metadata(&path).is_ok_and(|metadata| metadata.file_type().is_socket());
+        if !is_socket {
+            continue;
+        }
+        let target = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if !targets.contains(&target) {
+            targets.push(target);
+            sockets.push(path);
+        }
+    }
+    sockets
+}
+
+fn candidates_in(dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for dir in dirs {
+        for subpath in APP_SUBPATHS {
+            for index in 0..SOCKET_COUNT {
+                paths.push(dir.join(subpath).join(format!("discord-ipc-{}", index)));
+            }
+        }
+    }
+    paths
+}
+
+// Unix socket connection to the Discord client. Unlike DiscordIpcClient it also finds the
+// sockets of Flatpak, Snap and Vesktop clients and remembers which one it attached to.
+#[derive(Debug)]
+pub struct IpcClient {
+    client_id: String,
+    // Socket from the "discord_socket" setting, no other socket is tried
+    pinned: Option<PathBuf>,
+    socket: Option<UnixStream>,
+    // Socket of the current connection
+    path: Option<PathBuf>,
+}
+
+impl IpcClient {
+    pub fn new(client_id: &str, pinned: Option<PathBuf>) -> Self {
+        IpcClient {
+            client_id: client_id.to_string(),
+            pinned,
+            socket: None,
+            path: None,
+        }
+    }
+
+    fn socket(&mut self) -> io::Result<&mut UnixStream> {
+        self.socket.as_mut().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                "not connected to the Discord IPC socket",
+            )
+        })
+    }
+}
+
+impl DiscordIpc for IpcClient {
+    fn connect_ipc(&mut self) -> Result<(), Box<dyn Error>> {
+        let candidates = match &self.pinned {
+            Some(path) => vec![path.clone()],
+            None => candidates(),
+        };
+
+        for path in candidates {
+            if let Ok(socket) = UnixStream::connect(&path) {
+                socket.set_read_timeout(Some(READ_TIMEOUT))?;
+                self.socket = Some(socket);
+                self.path = Some(path);
+                return Ok(());
+            }
+        }
+
+        match &self.pinned {
+            Some(path) => Err(format!(
+                "Couldn't connect to the Discord IPC socket {}",
+                path.display()
+            )
+            .into()),
+            None => Err("Couldn't find a Discord IPC socket".into()),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.socket()?.write_all(data)?;
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Box<dyn Error>> {
+        self.socket()?.read_exact(buffer)?;
+        Ok(())
+    }
+
+    // Same as the default, but the frame length is checked before the buffer is allocated
+    fn recv(&mut self) -> Result<(u32, Value), Box<dyn Error>> {
+        let mut header = [0; 8];
+        self.read(&mut header)?;
+        let opcode = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if length > MAX_FRAME_SIZE {
+            // Rest of the frame is never read, the socket can't be used anymore
+            if let Some(socket) = self.socket.take() {
+                socket.shutdown(Shutdown::Both).ok();
+            }
+            return Err(Box::new(error::Error::Discord(format!(
+                "frame from {} is too large ({} bytes)",
+                self.name(),
+                length
+            ))));
+        }
+
+        let mut data = vec![0; length as usize];
+        self.read(&mut data)?;
+        Ok((opcode, serde_json::from_slice(&data)?))
+    }
+
+    // Opcode 2 tells Discord to remove the activity right away. Closing a closed client
+    // succeeds, so "reconnect" works after a failed write.
+    fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.socket.is_none() {
+            return Ok(());
+        }
+        let _ = self.send(json!({}), 2);
+        if let Some(socket) = self.socket.take() {
+            socket.shutdown(Shutdown::Both).ok();
+        }
+        Ok(())
+    }
+
+    fn get_client_id(&self) -> &String {
+        &self.client_id
+    }
+}
+
+impl Transport for IpcClient {
+    fn name(&self) -> String {
+        match &self.path {
+            Some(path) => path.display().to_string(),
+            None => String::from("unknown socket"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::os::unix::net::UnixListener;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "mpris-discord-rpc-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn candidate_order() {
+        let paths = candidates_in(&[PathBuf::from("/run/user/1000")]);
+        assert_eq!(paths[0], PathBuf::from("/run/user/1000/discord-ipc-0"));
+        assert_eq!(paths[9], PathBuf::from("/run/user/1000/discord-ipc-9"));
+        assert_eq!(
+            paths[10],
+            PathBuf::from("/run/user/1000/app/com.discordapp.Discord/discord-ipc-0")
+        );
+        assert!(paths.contains(&PathBuf::from(
+            "/run/user/1000/.flatpak/dev.vencord.Vesktop/xdg-run/discord-ipc-0"
+        )));
+        assert!(paths.contains(&PathBuf::from("/run/user/1000/snap.discord/discord-ipc-3")));
+    }
+
+    #[test]
+    fn pinned_socket() {
+        let dir = temp_dir("pinned");
+        let path = dir.join("discord-ipc-0");
+        let _listener = UnixListener::bind(&path).unwrap();
+
+        let mut client = IpcClient::new("1", Some(path.clone()));
+        client.connect_ipc().unwrap();
+        assert_eq!(client.name(), path.display().to_string());
+        client.close().unwrap();
+        assert!(client.write(b"{}").is_err());
+        // Closing twice is fine, the client can connect again
+        client.close().unwrap();
+        client.connect_ipc().unwrap();
+
+        let mut client = IpcClient::new("1", Some(dir.join("discord-ipc-1")));
+        assert!(client.connect_ipc().is_err());
+        assert_eq!(client.name(), "unknown socket");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn discover_sockets() {
+        let dir = temp_dir("discover");
+        let flatpak = dir.join("app/com.discordapp.Discord");
+        fs::create_dir_all(&flatpak).unwrap();
+        let _stable = UnixListener::bind(dir.join("discord-ipc-0")).unwrap();
+        let _flatpak = UnixListener::bind(flatpak.join("discord-ipc-0")).unwrap();
+        // Symlink to the Flatpak socket and a leftover regular file
+        symlink(flatpak.join("discord-ipc-0"), dir.join("discord-ipc-1")).unwrap();
+        fs::write(dir.join("discord-ipc-2"), "").unwrap();
+
+        assert_eq!(
+            discover_in(candidates_in(std::slice::from_ref(&dir))),
+            vec![dir.join("discord-ipc-0"), dir.join("discord-ipc-1")]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
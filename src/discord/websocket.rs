@@ -0,0 +1,448 @@
+use base64::Engine;
+use discord_rich_presence::DiscordIpc;
+use serde_json::Value;
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::discord::Transport;
+
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+
+// Discord and arRPC listen on the first free port of this range
+pub const PORTS: std::ops::RangeInclusive<u16> = 6463..=6472;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Replies are expected right away from a local server, a stuck server must not block the main loop
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Upgrade responses are a few hundred bytes
+const MAX_RESPONSE_HEAD: usize = 8192;
+
+// Discord RPC messages are a few KB, a larger length is a broken or hostile server and must not
+// be allocated
+const MAX_MESSAGE_SIZE: u64 = 1024 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+// Discord RPC over a local WebSocket, as served by arRPC and similar bridges for the Discord web
+// client. The application is chosen in the URL instead of a handshake frame, after that the same
+// JSON commands as on the IPC socket are sent as text frames.
+#[derive(Debug)]
+pub struct WebSocketClient {
+    client_id: String,
+    host: String,
+    // None tries every port of PORTS
+    port: Option<u16>,
+    stream: Option<TcpStream>,
+    connected_port: Option<u16>,
+}
+
+impl WebSocketClient {
+    pub fn new(client_id: &str, host: &str, port: Option<u16>) -> Self {
+        WebSocketClient {
+            client_id: client_id.to_string(),
+            host: host.to_string(),
+            port,
+            stream: None,
+            connected_port: None,
+        }
+    }
+
+    fn stream(&mut self) -> io::Result<&mut TcpStream> {
+        self.stream.as_mut().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                "not connected to the Discord RPC WebSocket",
+            )
+        })
+    }
+
+    fn open(&self, port: u16) -> Result<TcpStream, Box<dyn Error>> {
+        let address = (self.host.as_str(), port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| format!("Couldn't resolve {}", self.host))?;
+        let mut stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+
+        // No Origin header, arRPC only accepts requests without one or from discord.com.
+        // The server is local, so the Sec-WebSocket-Accept value is not checked.
+        let key =
+            base64::engine::general_purpose::STANDARD.encode(fastrand::u128(..).to_be_bytes());
+        write!(
+            stream,
+            "GET /?v=1&client_id={}&encoding=json HTTP/1.1\r\n\
+             Host: {}:{}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n",
+            url_escape::encode_component(&self.client_id),
+            self.host,
+            port,
+            key
+        )?;
+
+        let head = read_response_head(&mut stream)?;
+        let status = head.lines().next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("101") {
+            return Err(format!("WebSocket upgrade refused: {}", status).into());
+        }
+        Ok(stream)
+    }
+
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), Box<dyn Error>> {
+        let frame = encode_frame(opcode, payload, fastrand::u32(..).to_be_bytes());
+        self.write(&frame)
+    }
+
+    // Payload of the next text message, control frames are answered on the way
+    fn read_message(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut message = Vec::new();
+        loop {
+            let mut head = [0; 2];
+            self.read(&mut head)?;
+            let fin = head[0] & 0x80 != 0;
+            let opcode = head[0] & 0x0F;
+            let masked = head[1] & 0x80 != 0;
+            let length = match head[1] & 0x7F {
+                126 => {
+                    let mut length = [0; 2];
+                    self.read(&mut length)?;
+                    u16::from_be_bytes(length) as u64
+                }
+                127 => {
+                    let mut length = [0; 8];
+                    self.read(&mut length)?;
+                    u64::from_be_bytes(length)
+                }
+                length => length as u64,
+            };
+            if length.saturating_add(message.len() as u64) > MAX_MESSAGE_SIZE {
+                // Rest of the frame is never read, the stream can't be used anymore
+                self.stream = None;
+                return Err(format!(
+                    "Discord RPC WebSocket message is too large ({} bytes)",
+                    length
+                )
+                .into());
+            }
+            let mut mask = [0; 4];
+            if masked {
+                self.read(&mut mask)?;
+            }
+            let mut payload = vec![0; length as usize];
+            self.read(&mut payload)?;
+            if masked {
+                apply_mask(&mut payload, mask);
+            }
+
+            match opcode {
+                OPCODE_TEXT | OPCODE_CONTINUATION => {
+                    message.extend_from_slice(&payload);
+                    if fin {
+                        return Ok(message);
+                    }
+                }
+                OPCODE_PING => self.write_frame(OPCODE_PONG, &payload)?,
+                OPCODE_CLOSE => {
+                    self.stream = None;
+                    return Err("Discord RPC WebSocket was closed by the server".into());
+                }
+                // Pongs and binary frames are not used by Discord RPC
+                _ => {}
+            }
+        }
+    }
+}
+
+impl DiscordIpc for WebSocketClient {
+    fn connect_ipc(&mut self) -> Result<(), Box<dyn Error>> {
+        let ports: Vec<u16> = match self.port {
+            Some(port) => vec![port],
+            None => PORTS.collect(),
+        };
+
+        let mut last_error: Box<dyn Error> = "no port to connect to".into();
+        for port in ports {
+            match self.open(port) {
+                Ok(stream) => {
+                    self.stream = Some(stream);
+                    self.connected_port = Some(port);
+                    return Ok(());
+                }
+                Err(err) => last_error = err,
+            }
+        }
+        Err(format!(
+            "Couldn't connect to the Discord RPC WebSocket on {}: {}",
+            self.host, last_error
+        )
+        .into())
+    }
+
+    fn send_handshake(&mut self) -> Result<(), Box<dyn Error>> {
+        self.handshake()?;
+        Ok(())
+    }
+
+    // Opcodes of the IPC socket, 2 closes the connection
+    fn send(&mut self, data: Value, opcode: u8) -> Result<(), Box<dyn Error>> {
+        if opcode == 2 {
+            return self.write_frame(OPCODE_CLOSE, &[]);
+        }
+        self.write_frame(OPCODE_TEXT, data.to_string().as_bytes())
+    }
+
+    fn recv(&mut self) -> Result<(u32, Value), Box<dyn Error>> {
+        let message = self.read_message()?;
+        Ok((1, serde_json::from_slice(&message)?))
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.stream()?.write_all(data)?;
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Box<dyn Error>> {
+        self.stream()?.read_exact(buffer)?;
+        Ok(())
+    }
+
+    // Closing a closed client succeeds, so "reconnect" works after a failed write
+    fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.stream.is_none() {
+            return Ok(());
+        }
+        let _ = self.write_frame(OPCODE_CLOSE, &[]);
+        if let Some(stream) = self.stream.take() {
+            stream.shutdown(Shutdown::Both).ok();
+        }
+        Ok(())
+    }
+
+    fn get_client_id(&self) -> &String {
+        &self.client_id
+    }
+}
+
+impl Transport for WebSocketClient {
+    fn name(&self) -> String {
+        match self.connected_port.or(self.port) {
+            Some(port) => format!("ws://{}:{}", self.host, port),
+            None => format!("ws://{}", self.host),
+        }
+    }
+
+    // The server sends READY right after the upgrade, there is no handshake frame
+    fn handshake(&mut self) -> Result<(u32, Value), Box<dyn Error>> {
+        self.recv()
+    }
+}
+
+fn read_response_head(stream: &mut TcpStream) -> Result<String, Box<dyn Error>> {
+    let mut head = Vec::new();
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD {
+            return Err("WebSocket upgrade response is too long".into());
+        }
+        stream.read_exact(&mut byte)?;
+        head.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+// Frames sent by a client have to be masked
+fn encode_frame(opcode: u8, payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length if length < 126 => frame.push(0x80 | length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(&mask);
+    let start = frame.len();
+    frame.extend_from_slice(payload);
+    apply_mask(&mut frame[start..], mask);
+    frame
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (index, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[index % 4];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discord_rich_presence::activity::Activity;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    // Server frames are not masked
+    fn server_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = encode_frame(opcode, payload, [0; 4]);
+        frame[1] &= 0x7F;
+        frame.drain(2..6);
+        frame
+    }
+
+    // Stand-in for arRPC: accepts one client, sends a ping and READY and forwards the received
+    // messages
+    fn server() -> (u16, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            sender.send(request_line).unwrap();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+            }
+
+            let mut stream = stream;
+            stream
+                .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n")
+                .unwrap();
+            stream.write_all(&server_frame(OPCODE_PING, b"hi")).unwrap();
+            stream
+                .write_all(&server_frame(
+                    OPCODE_TEXT,
+                    br#"{"cmd":"DISPATCH","evt":"READY","data":{"v":1}}"#,
+                ))
+                .unwrap();
+
+            loop {
+                let mut head = [0; 2];
+                if reader.read_exact(&mut head).is_err() {
+                    return;
+                }
+                assert_eq!(head[1] & 0x80, 0x80, "client frames must be masked");
+                let length = match head[1] & 0x7F {
+                    126 => {
+                        let mut length = [0; 2];
+                        reader.read_exact(&mut length).unwrap();
+                        u16::from_be_bytes(length) as usize
+                    }
+                    length => length as usize,
+                };
+                let mut mask = [0; 4];
+                reader.read_exact(&mut mask).unwrap();
+                let mut payload = vec![0; length];
+                reader.read_exact(&mut payload).unwrap();
+                apply_mask(&mut payload, mask);
+                let message = match head[0] & 0x0F {
+                    OPCODE_PONG => format!("pong {}", String::from_utf8(payload).unwrap()),
+                    OPCODE_CLOSE => String::from("close"),
+                    _ => String::from_utf8(payload).unwrap(),
+                };
+                if sender.send(message).is_err() {
+                    return;
+                }
+            }
+        });
+        (port, receiver)
+    }
+
+    #[test]
+    fn sends_activity() {
+        let (port, messages) = server();
+        let mut client = WebSocketClient::new("1129859263741837373", DEFAULT_HOST, Some(port));
+        client.connect().unwrap();
+        assert_eq!(
+            messages.recv().unwrap(),
+            "GET /?v=1&client_id=1129859263741837373&encoding=json HTTP/1.1\r\n"
+        );
+        assert_eq!(client.name(), format!("ws://127.0.0.1:{}", port));
+        // Ping is answered while waiting for READY
+        assert_eq!(messages.recv().unwrap(), "pong hi");
+
+        client
+            .set_activity(Activity::new().details("Paranoid Android"))
+            .unwrap();
+
+        let message: Value = serde_json::from_str(&messages.recv().unwrap()).unwrap();
+        assert_eq!(message["cmd"], "SET_ACTIVITY");
+        assert_eq!(message["args"]["activity"]["details"], "Paranoid Android");
+
+        client.close().unwrap();
+        assert_eq!(messages.recv().unwrap(), "close");
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn refused_upgrade() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            let _ = stream.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n");
+        });
+
+        let mut client = WebSocketClient::new("1", DEFAULT_HOST, Some(port));
+        let err = client.connect().unwrap_err();
+        assert!(err.to_string().contains("403"), "{}", err);
+    }
+
+    #[test]
+    fn oversized_message() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            let _ = stream.write_all(b"HTTP/1.1 101 Switching Protocols\r\n\r\n");
+            // Text frame claiming a 4 GiB payload instead of READY
+            let mut frame = vec![0x80 | OPCODE_TEXT, 127];
+            frame.extend_from_slice(&(4u64 << 30).to_be_bytes());
+            let _ = stream.write_all(&frame);
+        });
+
+        let mut client = WebSocketClient::new("1", DEFAULT_HOST, Some(port));
+        let err = client.connect().unwrap_err();
+        assert!(err.to_string().contains("too large"), "{}", err);
+    }
+
+    #[test]
+    fn frame_lengths() {
+        let frame = encode_frame(OPCODE_TEXT, &[b'a'; 125], [1, 2, 3, 4]);
+        assert_eq!(frame[1], 0x80 | 125);
+        assert_eq!(frame.len(), 2 + 4 + 125);
+
+        let frame = encode_frame(OPCODE_TEXT, &[b'a'; 300], [1, 2, 3, 4]);
+        assert_eq!(frame[1], 0x80 | 126);
+        assert_eq!(&frame[2..4], &300u16.to_be_bytes());
+
+        let mut payload = frame[8..].to_vec();
+        apply_mask(&mut payload, [1, 2, 3, 4]);
+        assert_eq!(payload, vec![b'a'; 300]);
+    }
+}
@@ -0,0 +1,92 @@
+use std::fmt;
+
+// Errors that can happen while running the RPC. None of them should stop the main loop,
+// they are printed and the failed step is retried later.
+#[derive(Debug)]
+pub enum Error {
+    /// Communication with the MPRIS player or the session bus failed
+    DBus(String),
+    /// Discord is not running or the IPC socket was closed
+    Discord(String),
+    /// Discord refused the activity, the connection is still open
+    Rejected(String),
+    /// Discord refused the activity because it was updated too often
+    RateLimited(String),
+    /// Request to an external API failed
+    Http(String),
+    /// Reading or writing the cover cache failed
+    Cache(String),
+    /// Config file could not be read, parsed or written
+    Config(String),
+    /// External API rejected the request
+    Api(String),
+}
+
+impl Error {
+    // Discord IPC client only returns boxed errors, the transports box errors of this type
+    pub fn discord(err: Box<dyn std::error::Error>) -> Self {
+        match err.downcast::<Error>() {
+            Ok(err) => *err,
+            Err(err) => Error::Discord(err.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::DBus(msg) => write!(f, "D-Bus error: {}", msg),
+            Error::Discord(msg) => write!(f, "Discord IPC error: {}", msg),
+            Error::Rejected(msg) => write!(f, "Discord rejected the activity: {}", msg),
+            Error::RateLimited(msg) => write!(f, "Discord rate limit: {}", msg),
+            Error::Http(msg) => write!(f, "HTTP error: {}", msg),
+            Error::Cache(msg) => write!(f, "Cache error: {}", msg),
+            Error::Config(msg) => write!(f, "Config error: {}", msg),
+            Error::Api(msg) => write!(f, "API error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<mpris::DBusError> for Error {
+    fn from(err: mpris::DBusError) -> Self {
+        Error::DBus(err.to_string())
+    }
+}
+
+impl From<mpris::FindingError> for Error {
+    fn from(err: mpris::FindingError) -> Self {
+        Error::DBus(err.to_string())
+    }
+}
+
+impl From<dbus::Error> for Error {
+    fn from(err: dbus::Error) -> Self {
+        Error::DBus(err.to_string())
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Http(err.to_string())
+    }
+}
+
+impl From<pickledb::error::Error> for Error {
+    fn from(err: pickledb::error::Error) -> Self {
+        Error::Cache(err.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Cache(err.to_string())
+    }
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(err: serde_yaml::Error) -> Self {
+        Error::Config(err.to_string())
+    }
+}
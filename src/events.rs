@@ -0,0 +1,295 @@
+use dbus::blocking::Connection;
+use dbus::channel::MatchingReceiver;
+use dbus::message::MatchRule;
+use dbus::Message;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::debug_log;
+
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const MPRIS_BUS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const MPRIS_PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+// Players usually emit several signals at once (metadata, status, position), wait for them to settle
+const DEBOUNCE: Duration = Duration::from_millis(250);
+// Players that emit signals continuously (e.g. position updates) would otherwise never settle
+const MAX_DEBOUNCE: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum Event {
+    /// Metadata, playback status or any other player property changed, with the unique bus
+    /// name of the player
+    PropertiesChanged(String),
+    /// Player jumped to a new position, with the unique bus name of the player
+    Seeked(String),
+    /// New MPRIS player appeared on the bus
+    PlayerAppeared,
+    /// MPRIS player disappeared from the bus
+    PlayerVanished(String),
+    /// Album cover lookup finished on the cover worker
+    CoverFound,
+}
+
+// What the main loop should do after waiting for the player
+#[derive(Debug, PartialEq)]
+pub enum Wakeup {
+    /// Timeout passed or player properties changed, refresh the activity
+    Refresh,
+    /// Player jumped to a new position, timestamps need to be updated
+    Seeked,
+    /// The current player vanished or another one would be chosen now, search for the active
+    /// player again
+    PlayersChanged,
+}
+
+// Channel of events that should wake up the main loop before the next scheduled refresh
+pub struct Events {
+    sender: Sender<Event>,
+    receiver: Receiver<Event>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Events { sender, receiver }
+    }
+
+    // For threads that need to wake up the main loop
+    pub fn sender(&self) -> Sender<Event> {
+        self.sender.clone()
+    }
+
+    // Subscribe to MPRIS signals on a separate D-Bus connection. If this fails, the main loop
+    // still works by refreshing every interval.
+    pub fn watch_mpris(&self, debug_log: bool) {
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            let connection = match Connection::new_session() {
+                Ok(connection) => connection,
+                Err(err) => {
+                    println!(
+                        "Could not subscribe to MPRIS signals, falling back to polling: {}",
+                        err
+                    );
+                    return;
+                }
+            };
+
+            if let Err(err) = add_mpris_matches(&connection, &sender) {
+                println!(
+                    "Could not subscribe to MPRIS signals, falling back to polling: {}",
+                    err
+                );
+                return;
+            }
+            debug_log!(debug_log, "Subscribed to MPRIS signals.");
+
+            loop {
+                if let Err(err) = connection.process(Duration::from_secs(60)) {
+                    println!(
+                        "Lost connection to MPRIS signals, falling back to polling: {}",
+                        err
+                    );
+                    return;
+                }
+            }
+        });
+    }
+
+    // Block until an event arrives or the timeout passes. Returns every event received in a burst,
+    // empty list means the timeout passed.
+    pub fn wait(&self, timeout: Duration) -> Vec<Event> {
+        let mut events = Vec::new();
+        match self.receiver.recv_timeout(timeout) {
+            Ok(event) => events.push(event),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => return events,
+        }
+
+        let deadline = Instant::now() + MAX_DEBOUNCE;
+        loop {
+            let timeout = DEBOUNCE.min(deadline.saturating_duration_since(Instant::now()));
+            match self.receiver.recv_timeout(timeout) {
+                Ok(event) => events.push(event),
+                Err(_) => break,
+            }
+        }
+
+        events
+    }
+
+    // Wait for the next refresh of the player with the given bus names. Signals of other players
+    // are ignored unless "other_player_wins" says one of them would be chosen instead, so a
+    // browser tab starting a video doesn't interrupt the activity of the music player.
+    pub fn wait_for_player(
+        &self,
+        timeout: Duration,
+        bus_name: &str,
+        unique_name: &str,
+        other_player_wins: impl Fn() -> bool,
+    ) -> Wakeup {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let events = self.wait(deadline.saturating_duration_since(Instant::now()));
+            if events.is_empty() {
+                return Wakeup::Refresh;
+            }
+
+            let mut wakeup = None;
+            let mut other_players = false;
+            for event in &events {
+                match event {
+                    Event::PlayerVanished(name) if name == bus_name => {
+                        return Wakeup::PlayersChanged;
+                    }
+                    Event::PlayerVanished(_) => {}
+                    Event::PlayerAppeared => other_players = true,
+                    Event::PropertiesChanged(sender) if sender != unique_name => {
+                        other_players = true
+                    }
+                    Event::Seeked(sender) if sender != unique_name => {}
+                    Event::Seeked(_) => wakeup = Some(Wakeup::Seeked),
+                    Event::PropertiesChanged(_) | Event::CoverFound => {
+                        wakeup.get_or_insert(Wakeup::Refresh);
+                    }
+                }
+            }
+
+            if other_players && other_player_wins() {
+                return Wakeup::PlayersChanged;
+            }
+            if let Some(wakeup) = wakeup {
+                return wakeup;
+            }
+        }
+    }
+}
+
+fn add_mpris_matches(connection: &Connection, sender: &Sender<Event>) -> Result<(), dbus::Error> {
+    // Only changes of the player interface, not e.g. the track list or custom interfaces.
+    // MatchRule has no builder for argument matches, the rule is registered as a string.
+    let properties_rule =
+        MatchRule::new_signal("org.freedesktop.DBus.Properties", "PropertiesChanged")
+            .with_path(MPRIS_PATH);
+    connection.add_match_no_cb(&format!(
+        "{},arg0='{}'",
+        properties_rule.match_str(),
+        MPRIS_PLAYER_INTERFACE
+    ))?;
+    let properties_sender = sender.clone();
+    connection.start_receive(
+        properties_rule,
+        Box::new(move |message, _| {
+            // Local matching ignores arguments, check the interface again
+            if message.read1::<&str>().ok() != Some(MPRIS_PLAYER_INTERFACE) {
+                return true;
+            }
+            properties_sender
+                .send(Event::PropertiesChanged(sender_name(&message)))
+                .is_ok()
+        }),
+    );
+
+    let seeked_sender = sender.clone();
+    connection.add_match(
+        MatchRule::new_signal(MPRIS_PLAYER_INTERFACE, "Seeked").with_path(MPRIS_PATH),
+        move |_: (), _, message: &Message| {
+            seeked_sender
+                .send(Event::Seeked(sender_name(message)))
+                .is_ok()
+        },
+    )?;
+
+    let owner_sender = sender.clone();
+    connection.add_match(
+        MatchRule::new_signal("org.freedesktop.DBus", "NameOwnerChanged")
+            .with_sender("org.freedesktop.DBus"),
+        move |(name, old_owner, new_owner): (String, String, String), _, _| {
+            if !name.starts_with(MPRIS_BUS_PREFIX) {
+                return true;
+            }
+
+            let event = if new_owner.is_empty() {
+                Event::PlayerVanished(name)
+            } else if old_owner.is_empty() {
+                Event::PlayerAppeared
+            } else {
+                return true;
+            };
+            owner_sender.send(event).is_ok()
+        },
+    )?;
+
+    Ok(())
+}
+
+// Unique name (e.g. ":1.42") of the connection that sent the signal
+fn sender_name(message: &Message) -> String {
+    message
+        .sender()
+        .map(|sender| sender.to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debounce_has_deadline() {
+        let events = Events::new();
+        let sender = events.sender();
+        thread::spawn(move || {
+            while sender
+                .send(Event::PropertiesChanged(String::from(":1.1")))
+                .is_ok()
+            {
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let start = Instant::now();
+        let received = events.wait(Duration::from_secs(5));
+        assert!(received.len() > 1);
+        assert!(start.elapsed() < MAX_DEBOUNCE + DEBOUNCE);
+    }
+
+    #[test]
+    fn other_players_ignored() {
+        let events = Events::new();
+        let sender = events.sender();
+        let wait = |other_player_wins: bool| {
+            events.wait_for_player(
+                Duration::from_millis(600),
+                "org.mpris.MediaPlayer2.spotify",
+                ":1.1",
+                || other_player_wins,
+            )
+        };
+
+        // Seek of a browser tab doesn't reset the timestamps, the wait runs until the timeout
+        sender.send(Event::Seeked(String::from(":1.2"))).unwrap();
+        sender.send(Event::PlayerAppeared).unwrap();
+        let start = Instant::now();
+        assert_eq!(wait(false), Wakeup::Refresh);
+        assert!(start.elapsed() >= Duration::from_millis(600));
+
+        sender
+            .send(Event::PropertiesChanged(String::from(":1.2")))
+            .unwrap();
+        sender.send(Event::Seeked(String::from(":1.1"))).unwrap();
+        assert_eq!(wait(false), Wakeup::Seeked);
+
+        // New player is playing and the current one is not
+        sender.send(Event::PlayerAppeared).unwrap();
+        assert_eq!(wait(true), Wakeup::PlayersChanged);
+
+        sender
+            .send(Event::PlayerVanished(String::from(
+                "org.mpris.MediaPlayer2.spotify",
+            )))
+            .unwrap();
+        assert_eq!(wait(false), Wakeup::PlayersChanged);
+    }
+}
@@ -0,0 +1,29 @@
+use reqwest::blocking::Client;
+use std::time::Duration;
+
+use crate::error::Error;
+
+// MusicBrainz and some image hosts block requests without a meaningful user agent
+pub const USER_AGENT: &str = concat!(
+    "mpris-discord-rpc/",
+    env!("CARGO_PKG_VERSION"),
+    " ( https://github.com/patryk-ku/mpris-discord-rpc )"
+);
+
+pub const DEFAULT_TIMEOUT_SECS: u64 = 10;
+
+// Unreachable hosts should fail fast, slow responses get the whole "http_timeout"
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Client shared by the cover providers, so connections are reused. Without "proxy" the usual
+// HTTP_PROXY, HTTPS_PROXY and NO_PROXY environment variables are used.
+pub fn client(timeout_secs: u64, proxy: Option<&str>) -> Result<Client, Error> {
+    let mut builder = Client::builder()
+        .user_agent(USER_AGENT)
+        .connect_timeout(CONNECT_TIMEOUT.min(Duration::from_secs(timeout_secs)))
+        .timeout(Duration::from_secs(timeout_secs));
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    Ok(builder.build()?)
+}
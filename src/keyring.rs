@@ -0,0 +1,60 @@
+use dbus::arg::{RefArg, Variant};
+use dbus::blocking::Connection;
+use dbus::Path;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::error::Error;
+
+const SECRETS_BUS_NAME: &str = "org.freedesktop.secrets";
+const SECRETS_PATH: &str = "/org/freedesktop/secrets";
+const SERVICE_INTERFACE: &str = "org.freedesktop.Secret.Service";
+const ITEM_INTERFACE: &str = "org.freedesktop.Secret.Item";
+const SESSION_INTERFACE: &str = "org.freedesktop.Secret.Session";
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+// Attribute used to find secrets of this app in the keyring
+pub const SERVICE_ATTRIBUTE: &str = "mpris-discord-rpc";
+
+// Session, parameters, value and content type, as returned by Item.GetSecret
+type Secret = (Path<'static>, Vec<u8>, Vec<u8>, String);
+
+// Read a secret stored with attributes "service=mpris-discord-rpc key=<key>" from the Secret Service
+// (GNOME Keyring, KWallet, KeePassXC). Locked items are skipped, this never shows an unlock prompt.
+// Store a secret using: secret-tool store --label="..." service mpris-discord-rpc key <key>
+pub fn get_secret(key: &str) -> Result<Option<String>, Error> {
+    let connection = Connection::new_session()?;
+    let service = connection.with_proxy(SECRETS_BUS_NAME, SECRETS_PATH, TIMEOUT);
+
+    let mut attributes = HashMap::new();
+    attributes.insert("service", SERVICE_ATTRIBUTE);
+    attributes.insert("key", key);
+    let (unlocked, _locked): (Vec<Path<'static>>, Vec<Path<'static>>) =
+        service.method_call(SERVICE_INTERFACE, "SearchItems", (attributes,))?;
+
+    let item_path = match unlocked.first() {
+        Some(path) => path.clone(),
+        None => return Ok(None),
+    };
+
+    // Secrets are transferred without encryption over the session bus, same as secret-tool does
+    let (_, session): (Variant<Box<dyn RefArg>>, Path<'static>) =
+        service.method_call(SERVICE_INTERFACE, "OpenSession", ("plain", Variant("")))?;
+
+    let item = connection.with_proxy(SECRETS_BUS_NAME, item_path, TIMEOUT);
+    let secret: Result<(Secret,), dbus::Error> =
+        item.method_call(ITEM_INTERFACE, "GetSecret", (session.clone(),));
+
+    let session_proxy = connection.with_proxy(SECRETS_BUS_NAME, session, TIMEOUT);
+    let _: Result<(), dbus::Error> = session_proxy.method_call(SESSION_INTERFACE, "Close", ());
+
+    let ((_, _, value, _),) = secret?;
+    match String::from_utf8(value) {
+        Ok(value) if !value.trim().is_empty() => Ok(Some(value.trim().to_string())),
+        Ok(_) => Ok(None),
+        Err(_) => Err(Error::Config(format!(
+            "Secret \"{}\" in the keyring is not valid UTF-8",
+            key
+        ))),
+    }
+}
@@ -0,0 +1,455 @@
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::debug_log;
+use crate::error::Error;
+use crate::keyring;
+use crate::scrobble::{Listen, ScrobbleService};
+
+// Key baked into release builds, set LASTFM_API_KEY in .env and build with "--features bundled-api-key"
+#[cfg(feature = "bundled-api-key")]
+const BUNDLED_API_KEY: Option<&str> = Some(dotenvy_macro::dotenv!("LASTFM_API_KEY"));
+#[cfg(not(feature = "bundled-api-key"))]
+const BUNDLED_API_KEY: Option<&str> = None;
+
+// Default locations of the credential files, relative to the config directory
+const API_KEY_FILE: &str = "lastfm_api_key";
+const API_SECRET_FILE: &str = "lastfm_api_secret";
+const SESSION_FILE: &str = "lastfm_session.json";
+
+const API_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+const AUTH_URL: &str = "https://www.last.fm/api/auth/";
+
+// Last.fm accepts up to 50 scrobbles in one request
+const MAX_BATCH_SIZE: usize = 50;
+
+// Find Last.fm API key in order: config file, environment variable, key file, keyring, built-in key
+pub fn resolve_api_key(
+    config_key: Option<&str>,
+    key_file: &Option<String>,
+    config_dir: Option<&Path>,
+    debug_log: bool,
+) -> Option<String> {
+    let key = resolve_credential(
+        "API key",
+        config_key,
+        "LASTFM_API_KEY",
+        key_file,
+        API_KEY_FILE,
+        "lastfm_api_key",
+        config_dir,
+        debug_log,
+    );
+    if key.is_some() {
+        return key;
+    }
+
+    if let Some(key) = BUNDLED_API_KEY {
+        if !key.trim().is_empty() {
+            debug_log!(debug_log, "Using built-in Last.fm API key.");
+            return Some(key.trim().to_string());
+        }
+    }
+
+    println!("[last.fm] No API key available, album covers from Last.fm are disabled. Set \"lastfm_api_key\" in the config file or LASTFM_API_KEY environment variable.");
+    None
+}
+
+// Shared secret is only needed for scrobbling, there is no built-in one
+pub fn resolve_api_secret(
+    config_secret: Option<&str>,
+    config_dir: Option<&Path>,
+    debug_log: bool,
+) -> Option<String> {
+    resolve_credential(
+        "API secret",
+        config_secret,
+        "LASTFM_API_SECRET",
+        &None,
+        API_SECRET_FILE,
+        "lastfm_api_secret",
+        config_dir,
+        debug_log,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn resolve_credential(
+    name: &str,
+    config_value: Option<&str>,
+    env_var: &str,
+    file: &Option<String>,
+    default_file: &str,
+    keyring_key: &str,
+    config_dir: Option<&Path>,
+    debug_log: bool,
+) -> Option<String> {
+    if let Some(value) = config_value {
+        if !value.trim().is_empty() {
+            println!("[last.fm] Using {} from config file.", name);
+            return Some(value.trim().to_string());
+        }
+    }
+
+    if let Ok(value) = env::var(env_var) {
+        if !value.trim().is_empty() {
+            println!(
+                "[last.fm] Using {} from {} environment variable.",
+                name, env_var
+            );
+            return Some(value.trim().to_string());
+        }
+    }
+
+    let file: Option<PathBuf> = match file {
+        Some(path) => Some(PathBuf::from(path)),
+        None => config_dir.map(|dir| dir.join(default_file)),
+    };
+    if let Some(file) = file {
+        match fs::read_to_string(&file) {
+            Ok(value) if !value.trim().is_empty() => {
+                println!("[last.fm] Using {} from file: {}", name, file.display());
+                return Some(value.trim().to_string());
+            }
+            Ok(_) => println!("[last.fm] {} file is empty: {}", name, file.display()),
+            Err(err) => debug_log!(
+                debug_log,
+                "Could not read {} file {}: {}",
+                name,
+                file.display(),
+                err
+            ),
+        }
+    }
+
+    match keyring::get_secret(keyring_key) {
+        Ok(Some(value)) => {
+            println!("[last.fm] Using {} from keyring.", name);
+            return Some(value);
+        }
+        Ok(None) => {}
+        Err(err) => debug_log!(debug_log, "Could not read {} from keyring: {}", name, err),
+    }
+
+    None
+}
+
+// Authenticated session, created once with "lastfm login"
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub name: String,
+    pub key: String,
+}
+
+pub fn load_session(config_dir: &Path) -> Option<Session> {
+    let session = fs::read_to_string(config_dir.join(SESSION_FILE)).ok()?;
+    serde_json::from_str(&session).ok()
+}
+
+fn save_session(config_dir: &Path, session: &Session) -> Result<(), Error> {
+    fs::create_dir_all(config_dir)
+        .map_err(|err| Error::Config(format!("Failed to create config directory: {}", err)))?;
+    let json = serde_json::to_string_pretty(session)
+        .map_err(|err| Error::Config(format!("Failed to serialize session: {}", err)))?;
+
+    // Session key gives full access to scrobbling, keep it private
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(config_dir.join(SESSION_FILE))
+        .map_err(|err| Error::Config(format!("Failed to write session file: {}", err)))?;
+    file.write_all(json.as_bytes())
+        .map_err(|err| Error::Config(format!("Failed to write session file: {}", err)))?;
+    Ok(())
+}
+
+// Signature of a request: md5 of all params sorted by name, concatenated with the shared secret
+fn sign(params: &BTreeMap<String, String>, api_secret: &str) -> String {
+    let mut signature = String::new();
+    for (name, value) in params {
+        if name == "format" || name == "callback" {
+            continue;
+        }
+        signature.push_str(name);
+        signature.push_str(value);
+    }
+    signature.push_str(api_secret);
+    format!("{:x}", md5::compute(signature.as_bytes()))
+}
+
+// Signed POST request to the Last.fm API
+fn call(
+    client: &Client,
+    method: &str,
+    mut params: BTreeMap<String, String>,
+    api_key: &str,
+    api_secret: &str,
+) -> Result<serde_json::Value, Error> {
+    params.insert("method".to_string(), method.to_string());
+    params.insert("api_key".to_string(), api_key.to_string());
+    let signature = sign(&params, api_secret);
+    params.insert("api_sig".to_string(), signature);
+    params.insert("format".to_string(), "json".to_string());
+
+    let data = client
+        .post(API_URL)
+        .form(&params)
+        .send()?
+        .json::<serde_json::Value>()?;
+
+    if let Some(code) = data["error"].as_i64() {
+        let message = data["message"].as_str().unwrap_or_default();
+        let message = format!("{} (code {}): {}", method, code, message);
+        return Err(match code {
+            // Invalid session, authentication failed or API key suspended
+            4 | 9 | 10 | 14 | 26 => Error::Config(format!(
+                "{}. Run \"mpris-discord-rpc lastfm login\" again.",
+                message
+            )),
+            // Service offline, temporarily unavailable or rate limit exceeded, retry later
+            11 | 16 | 29 => Error::Http(message),
+            _ => Error::Api(message),
+        });
+    }
+
+    Ok(data)
+}
+
+// Desktop authentication flow: get a token, let the user allow access in the browser, exchange
+// the token for a session key and save it
+pub fn login(client: &Client, api_key: Option<&str>, api_secret: Option<&str>, config_dir: &Path) {
+    let (api_key, api_secret) = match (api_key, api_secret) {
+        (Some(api_key), Some(api_secret)) => (api_key, api_secret),
+        _ => {
+            println!("[last.fm] Logging in requires your own API key and shared secret. You can get them here: https://www.last.fm/api/account/create");
+            println!("[last.fm] Set \"lastfm_api_key\" and \"lastfm_api_secret\" in the config file and try again.");
+            process::exit(crate::utils::EXIT_LOGIN_FAILED);
+        }
+    };
+
+    match authenticate(client, api_key, api_secret) {
+        Ok(session) => match save_session(config_dir, &session) {
+            Ok(_) => {
+                println!("[last.fm] Logged in as {}.", session.name);
+                println!("[last.fm] Enable scrobbling with \"lastfm_scrobble: true\" in the config file.");
+                process::exit(crate::utils::EXIT_SUCCESS);
+            }
+            Err(err) => {
+                println!("[last.fm] {}", err);
+                process::exit(crate::utils::EXIT_CONFIG_ERROR);
+            }
+        },
+        Err(err) => {
+            println!("[last.fm] Login failed: {}", err);
+            process::exit(crate::utils::EXIT_LOGIN_FAILED);
+        }
+    }
+}
+
+pub fn logout(config_dir: &Path) {
+    let session_file = config_dir.join(SESSION_FILE);
+    if !session_file.exists() {
+        println!("[last.fm] Not logged in.");
+        process::exit(crate::utils::EXIT_SUCCESS);
+    }
+
+    match fs::remove_file(&session_file) {
+        Ok(_) => {
+            println!("[last.fm] Logged out, session removed.");
+            process::exit(crate::utils::EXIT_SUCCESS);
+        }
+        Err(err) => {
+            println!("[last.fm] Failed to remove session file: {}", err);
+            process::exit(crate::utils::EXIT_CONFIG_ERROR);
+        }
+    }
+}
+
+fn authenticate(client: &Client, api_key: &str, api_secret: &str) -> Result<Session, Error> {
+    let data = call(
+        client,
+        "auth.getToken",
+        BTreeMap::new(),
+        api_key,
+        api_secret,
+    )?;
+    // Token is only valid for 60 minutes and can be used once
+    let token = data["token"]
+        .as_str()
+        .ok_or_else(|| Error::Api("auth.getToken: missing token in response".to_string()))?
+        .to_string();
+
+    let auth_url = format!("{}?api_key={}&token={}", AUTH_URL, api_key, token);
+    println!("Open this link in your browser and allow access to your account:");
+    println!();
+    println!("{}", auth_url);
+    println!();
+    let _ = process::Command::new("xdg-open")
+        .arg(&auth_url)
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn();
+
+    print!("Press Enter after allowing access...");
+    let _ = io::stdout().flush();
+    let mut input = String::new();
+    let _ = io::stdin().read_line(&mut input);
+
+    let mut params = BTreeMap::new();
+    params.insert("token".to_string(), token);
+    let data = call(client, "auth.getSession", params, api_key, api_secret)?;
+
+    match (
+        data["session"]["name"].as_str(),
+        data["session"]["key"].as_str(),
+    ) {
+        (Some(name), Some(key)) => Ok(Session {
+            name: name.to_string(),
+            key: key.to_string(),
+        }),
+        _ => Err(Error::Api(
+            "auth.getSession: missing session in response".to_string(),
+        )),
+    }
+}
+
+pub struct LastfmService {
+    client: Client,
+    api_key: String,
+    api_secret: String,
+    session_key: String,
+}
+
+impl LastfmService {
+    pub fn new(client: &Client, api_key: &str, api_secret: &str, session: Session) -> Self {
+        LastfmService {
+            client: client.clone(),
+            api_key: api_key.to_string(),
+            api_secret: api_secret.to_string(),
+            session_key: session.key,
+        }
+    }
+
+    fn track_params(listen: &Listen, suffix: &str) -> BTreeMap<String, String> {
+        let mut params = BTreeMap::new();
+        params.insert(format!("artist{}", suffix), listen.artist.clone());
+        params.insert(format!("track{}", suffix), listen.title.clone());
+        if let Some(album) = &listen.album {
+            params.insert(format!("album{}", suffix), album.clone());
+        }
+        if let Some(album_artist) = &listen.album_artist {
+            params.insert(format!("albumArtist{}", suffix), album_artist.clone());
+        }
+        if let Some(duration) = listen.duration {
+            params.insert(format!("duration{}", suffix), duration.to_string());
+        }
+        params
+    }
+}
+
+impl ScrobbleService for LastfmService {
+    fn name(&self) -> &str {
+        "last.fm"
+    }
+
+    fn max_batch_size(&self) -> usize {
+        MAX_BATCH_SIZE
+    }
+
+    fn now_playing(&mut self, listen: &Listen) -> Result<(), Error> {
+        let mut params = Self::track_params(listen, "");
+        params.insert("sk".to_string(), self.session_key.clone());
+        call(
+            &self.client,
+            "track.updateNowPlaying",
+            params,
+            &self.api_key,
+            &self.api_secret,
+        )?;
+        Ok(())
+    }
+
+    fn submit(&mut self, listens: &[Listen]) -> Result<(), Error> {
+        let mut params = BTreeMap::new();
+        for (index, listen) in listens.iter().enumerate() {
+            let suffix = format!("[{}]", index);
+            params.append(&mut Self::track_params(listen, &suffix));
+            params.insert(format!("timestamp{}", suffix), listen.timestamp.to_string());
+        }
+        params.insert("sk".to_string(), self.session_key.clone());
+
+        let data = call(
+            &self.client,
+            "track.scrobble",
+            params,
+            &self.api_key,
+            &self.api_secret,
+        )?;
+        let ignored = data["scrobbles"]["@attr"]["ignored"].as_u64().unwrap_or(0);
+        if ignored > 0 {
+            println!("[last.fm] {} scrobble(s) ignored by Last.fm.", ignored);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listen() -> Listen {
+        Listen {
+            artist: String::from("Radiohead"),
+            title: String::from("Airbag"),
+            album: Some(String::from("OK Computer")),
+            album_artist: Some(String::from("Radiohead")),
+            duration: Some(283),
+            timestamp: 1700000000,
+            recording_mbid: None,
+            release_mbid: None,
+            artist_mbids: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn signature() {
+        let mut params = BTreeMap::new();
+        params.insert("token".to_string(), "TOKEN".to_string());
+        params.insert("method".to_string(), "auth.getSession".to_string());
+        params.insert("api_key".to_string(), "KEY".to_string());
+        // Parameters are sorted by name, format and callback are not signed
+        let signature = sign(&params, "SECRET");
+        assert_eq!(signature, "5479efdba54a79385144dca24a423b8a");
+
+        params.insert("format".to_string(), "json".to_string());
+        assert_eq!(sign(&params, "SECRET"), signature);
+        assert_ne!(sign(&params, "OTHER"), signature);
+    }
+
+    #[test]
+    fn track_parameters() {
+        let params = LastfmService::track_params(&listen(), "[1]");
+        assert_eq!(params.len(), 5);
+        assert_eq!(params["artist[1]"], "Radiohead");
+        assert_eq!(params["track[1]"], "Airbag");
+        assert_eq!(params["album[1]"], "OK Computer");
+        assert_eq!(params["albumArtist[1]"], "Radiohead");
+        assert_eq!(params["duration[1]"], "283");
+
+        let mut listen = listen();
+        listen.album = None;
+        listen.album_artist = None;
+        listen.duration = None;
+        let params = LastfmService::track_params(&listen, "");
+        assert_eq!(params.keys().collect::<Vec<_>>(), vec!["artist", "track"]);
+    }
+}
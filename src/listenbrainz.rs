@@ -0,0 +1,311 @@
+use reqwest::blocking::Client;
+use serde_json::json;
+use std::env;
+
+use crate::debug_log;
+use crate::error::Error;
+use crate::keyring;
+use crate::scrobble::{Listen, ScrobbleService};
+
+pub const DEFAULT_URL: &str = "https://api.listenbrainz.org";
+
+// ListenBrainz accepts up to 1000 listens in one import request
+const MAX_BATCH_SIZE: usize = 1000;
+
+// Find the user token in order: config file, environment variable, keyring
+pub fn resolve_token(config_token: Option<&str>, debug_log: bool) -> Option<String> {
+    if let Some(token) = config_token {
+        if !token.trim().is_empty() {
+            println!("[listenbrainz] Using user token from config file.");
+            return Some(token.trim().to_string());
+        }
+    }
+
+    if let Ok(token) = env::var("LISTENBRAINZ_TOKEN") {
+        if !token.trim().is_empty() {
+            println!(
+                "[listenbrainz] Using user token from LISTENBRAINZ_TOKEN environment variable."
+            );
+            return Some(token.trim().to_string());
+        }
+    }
+
+    match keyring::get_secret("listenbrainz_token") {
+        Ok(Some(token)) => {
+            println!("[listenbrainz] Using user token from keyring.");
+            return Some(token);
+        }
+        Ok(None) => {}
+        Err(err) => debug_log!(debug_log, "Could not read user token from keyring: {}", err),
+    }
+
+    None
+}
+
+pub struct ListenbrainzService {
+    client: Client,
+    submit_url: String,
+    token: String,
+}
+
+impl ListenbrainzService {
+    // Base URL of the API, for compatible servers it's the path before "/1/submit-listens"
+    pub fn new(client: &Client, base_url: &str, token: &str) -> Self {
+        ListenbrainzService {
+            client: client.clone(),
+            submit_url: format!("{}/1/submit-listens", base_url.trim_end_matches('/')),
+            token: token.to_string(),
+        }
+    }
+
+    fn track_metadata(listen: &Listen) -> serde_json::Value {
+        let mut additional_info = json!({
+            "submission_client": "mpris-discord-rpc",
+            "submission_client_version": env!("CARGO_PKG_VERSION"),
+        });
+        if let Some(duration) = listen.duration {
+            additional_info["duration_ms"] = json!(duration * 1000);
+        }
+        if let Some(recording_mbid) = &listen.recording_mbid {
+            additional_info["recording_mbid"] = json!(recording_mbid);
+        }
+        if let Some(release_mbid) = &listen.release_mbid {
+            additional_info["release_mbid"] = json!(release_mbid);
+        }
+        if !listen.artist_mbids.is_empty() {
+            additional_info["artist_mbids"] = json!(listen.artist_mbids);
+        }
+
+        let mut track_metadata = json!({
+            "artist_name": listen.artist,
+            "track_name": listen.title,
+            "additional_info": additional_info,
+        });
+        if let Some(album) = &listen.album {
+            track_metadata["release_name"] = json!(album);
+        }
+        track_metadata
+    }
+
+    fn listen_payload(listen: &Listen) -> serde_json::Value {
+        json!({
+            "listened_at": listen.timestamp,
+            "track_metadata": Self::track_metadata(listen),
+        })
+    }
+
+    fn post(&self, listen_type: &str, payload: Vec<serde_json::Value>) -> Result<(), Error> {
+        let response = self
+            .client
+            .post(&self.submit_url)
+            .header("Authorization", format!("Token {}", self.token))
+            .json(&json!({
+                "listen_type": listen_type,
+                "payload": payload,
+            }))
+            .send()?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        let message = match response.json::<serde_json::Value>() {
+            Ok(data) => data["error"].as_str().unwrap_or_default().to_string(),
+            Err(_) => String::new(),
+        };
+        let message = format!("{} ({}): {}", listen_type, status, message);
+        Err(match status.as_u16() {
+            401 => Error::Config(format!(
+                "{}. Check \"listenbrainz_token\" in the config file.",
+                message
+            )),
+            // Listen was rejected as invalid, sending it again won't help
+            400 => Error::Api(message),
+            _ => Error::Http(message),
+        })
+    }
+}
+
+impl ScrobbleService for ListenbrainzService {
+    fn name(&self) -> &str {
+        "listenbrainz"
+    }
+
+    fn max_batch_size(&self) -> usize {
+        MAX_BATCH_SIZE
+    }
+
+    fn now_playing(&mut self, listen: &Listen) -> Result<(), Error> {
+        self.post(
+            "playing_now",
+            vec![json!({ "track_metadata": Self::track_metadata(listen) })],
+        )
+    }
+
+    // Single listens are submitted as they happen, the backlog after an outage is imported at once
+    fn submit(&mut self, listens: &[Listen]) -> Result<(), Error> {
+        if listens.len() == 1 {
+            return self.post("single", vec![Self::listen_payload(&listens[0])]);
+        }
+
+        let payload = listens.iter().map(Self::listen_payload).collect();
+        match self.post("import", payload) {
+            // One invalid listen rejects the whole import, send them one by one to drop only the
+            // invalid ones. Listens already accepted before a network error are deduplicated by
+            // ListenBrainz when the batch is sent again.
+            Err(Error::Api(err)) => {
+                println!(
+                    "[listenbrainz] Import rejected, sending the listens one by one: {}",
+                    err
+                );
+                for listen in listens {
+                    match self.post("single", vec![Self::listen_payload(listen)]) {
+                        Ok(_) => {}
+                        Err(Error::Api(err)) => println!(
+                            "[listenbrainz] Dropping rejected scrobble: {} - {}: {}",
+                            listen.artist, listen.title, err
+                        ),
+                        Err(err) => return Err(err),
+                    }
+                }
+                Ok(())
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn listen(title: &str) -> Listen {
+        Listen {
+            artist: String::from("Radiohead"),
+            title: title.to_string(),
+            album: Some(String::from("OK Computer")),
+            album_artist: Some(String::from("Radiohead")),
+            duration: Some(383),
+            timestamp: 1700000000,
+            recording_mbid: None,
+            release_mbid: None,
+            artist_mbids: Vec::new(),
+        }
+    }
+
+    // Stand-in for the ListenBrainz API: rejects imports and listens of "Invalid", reports the
+    // listen type and titles of every request
+    fn server() -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                let titles: Vec<&str> = body["payload"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|listen| listen["track_metadata"]["track_name"].as_str().unwrap())
+                    .collect();
+                let rejected = body["listen_type"] == "import" || titles.contains(&"Invalid");
+                let _ = sender.send(format!("{} {}", body["listen_type"], titles.join(", ")));
+
+                let (status, response) = if rejected {
+                    (
+                        "400 Bad Request",
+                        r#"{"code":400,"error":"Invalid listen"}"#,
+                    )
+                } else {
+                    ("200 OK", r#"{"status":"ok"}"#)
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    response.len(),
+                    response
+                );
+            }
+        });
+        (url, receiver)
+    }
+
+    #[test]
+    fn listen_metadata() {
+        let mut listen = listen("Airbag");
+        let metadata = ListenbrainzService::track_metadata(&listen);
+        assert_eq!(metadata["artist_name"], "Radiohead");
+        assert_eq!(metadata["release_name"], "OK Computer");
+        assert_eq!(metadata["additional_info"]["duration_ms"], 383000);
+        assert!(metadata["additional_info"].get("recording_mbid").is_none());
+        assert!(metadata["additional_info"].get("artist_mbids").is_none());
+
+        listen.album = None;
+        listen.recording_mbid = Some(String::from("c2e9a7f9-41e4-4b6a-8e5c-4f1c3a3f4a11"));
+        listen.release_mbid = Some(String::from("b1392450-e666-3926-a536-22c65f834433"));
+        listen.artist_mbids = vec![String::from("a74b1b7f-71a5-4011-9441-d0b5e4122711")];
+        let payload = ListenbrainzService::listen_payload(&listen);
+        assert_eq!(payload["listened_at"], 1700000000);
+        let metadata = &payload["track_metadata"];
+        assert!(metadata.get("release_name").is_none());
+        assert_eq!(
+            metadata["additional_info"]["recording_mbid"],
+            "c2e9a7f9-41e4-4b6a-8e5c-4f1c3a3f4a11"
+        );
+        assert_eq!(
+            metadata["additional_info"]["release_mbid"],
+            "b1392450-e666-3926-a536-22c65f834433"
+        );
+        assert_eq!(
+            metadata["additional_info"]["artist_mbids"],
+            json!(["a74b1b7f-71a5-4011-9441-d0b5e4122711"])
+        );
+    }
+
+    #[test]
+    fn single_and_import() {
+        let (url, requests) = server();
+        let mut service = ListenbrainzService::new(&Client::new(), &url, "token");
+
+        service.submit(&[listen("Airbag")]).unwrap();
+        assert_eq!(requests.recv().unwrap(), "\"single\" Airbag");
+
+        let err = service.submit(&[listen("Invalid")]).unwrap_err();
+        assert!(matches!(err, Error::Api(_)), "{:?}", err);
+        assert_eq!(requests.recv().unwrap(), "\"single\" Invalid");
+
+        // Rejected import is sent again one listen at a time, only the invalid one is dropped
+        service
+            .submit(&[listen("Airbag"), listen("Invalid"), listen("Lucky")])
+            .unwrap();
+        assert_eq!(
+            requests.recv().unwrap(),
+            "\"import\" Airbag, Invalid, Lucky"
+        );
+        assert_eq!(requests.recv().unwrap(), "\"single\" Airbag");
+        assert_eq!(requests.recv().unwrap(), "\"single\" Invalid");
+        assert_eq!(requests.recv().unwrap(), "\"single\" Lucky");
+    }
+}
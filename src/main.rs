@@ -1,23 +1,33 @@
-use discord_rich_presence::{activity, DiscordIpc, DiscordIpcClient};
-use dotenvy_macro::dotenv;
 use mpris::PlayerFinder;
-use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
-use url_escape;
 
+use std::collections::HashMap;
 use std::env;
-use std::fs;
-use std::ops::Sub;
 use std::path::PathBuf;
 use std::thread::sleep;
 use std::time::{Duration, SystemTime};
 
+mod backoff;
+mod cover;
+mod discord;
+mod error;
+mod events;
+mod http;
+mod keyring;
+mod lastfm;
+mod listenbrainz;
+mod overrides;
+mod presence;
+mod scrobble;
 mod settings;
+mod template;
 mod utils;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Load api key from .env file durning compilation
-    const LASTFM_API_KEY: &str = dotenv!("LASTFM_API_KEY");
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+// Discord allows 5 activity updates in 20 seconds
+const RATE_LIMIT_DELAY: Duration = Duration::from_secs(20);
 
+fn main() -> Result<(), error::Error> {
     // Set home path, If $HOME is not set, do not write or read anything from the user's disk
     let (home_exists, home_dir) = match env::var("HOME") {
         Ok(val) => (true, PathBuf::from(val)),
@@ -35,9 +45,101 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         Some(settings::Commands::Enable {}) => utils::enable_service(),
         Some(settings::Commands::Disable {}) => utils::disable_service(),
         Some(settings::Commands::Restart {}) => utils::restart_service(),
+        // Needs the API key, handled below
+        Some(settings::Commands::Lastfm { .. }) => {}
+        Some(settings::Commands::Cache { .. }) => {}
         None => {}
     }
 
+    // Set cache path
+    let cache_dir = match env::var("XDG_CACHE_HOME") {
+        Ok(xgd_cache_home) => PathBuf::from(xgd_cache_home).join("mpris-discord-rpc"),
+        Err(_) => home_dir.join(".cache/mpris-discord-rpc"),
+    };
+    let cache_options = cover::cache::CacheOptions {
+        max_entries: settings
+            .cache_max_entries
+            .unwrap_or(cover::cache::DEFAULT_MAX_ENTRIES),
+        hit_ttl: settings.cache_hit_ttl.map(|hours| hours * 3600),
+        miss_ttl: settings
+            .cache_miss_ttl
+            .unwrap_or(cover::cache::DEFAULT_MISS_TTL_HOURS)
+            * 3600,
+        check_interval: match settings
+            .cache_check_interval
+            .unwrap_or(cover::cache::DEFAULT_CHECK_INTERVAL_HOURS)
+        {
+            0 => None,
+            hours => Some(hours * 3600),
+        },
+    };
+
+    // Shared by the cover providers, link checks and the Last.fm avatar request
+    let http_client = http::client(
+        settings.http_timeout.unwrap_or(http::DEFAULT_TIMEOUT_SECS),
+        settings.http_proxy.as_deref(),
+    )?;
+
+    // Cover cache subcommands
+    if let Some(settings::Commands::Cache { command }) = &settings.suboptions.command {
+        if !home_exists {
+            println!("[cache] $HOME is not set, the cache is not available.");
+            std::process::exit(utils::EXIT_CACHE_ERROR);
+        }
+        cover::commands::run(
+            command,
+            &cache_dir,
+            cache_options,
+            &cover::normalize::Normalizer::new(&settings.cover_normalization),
+            &http_client,
+        );
+    }
+
+    let config_dir = if home_exists {
+        Some(settings::config_dir(&home_dir))
+    } else {
+        None
+    };
+
+    // Last.fm API key from config, environment, key file, keyring or built-in default.
+    // Only resolved where it's used, so other subcommands don't warn about a missing key.
+    let resolve_api_key = || {
+        lastfm::resolve_api_key(
+            settings.lastfm_api_key.as_deref(),
+            &settings.lastfm_api_key_file,
+            config_dir.as_deref(),
+            settings.debug_log,
+        )
+    };
+
+    // Last.fm account subcommands
+    if let Some(settings::Commands::Lastfm { command }) = &settings.suboptions.command {
+        let config_dir = match &config_dir {
+            Some(config_dir) => config_dir,
+            None => {
+                println!("[last.fm] $HOME is not set, cannot save the session.");
+                std::process::exit(utils::EXIT_CONFIG_ERROR);
+            }
+        };
+        match command {
+            settings::LastfmCommands::Login {} => {
+                let lastfm_api_secret = lastfm::resolve_api_secret(
+                    settings.lastfm_api_secret.as_deref(),
+                    Some(config_dir),
+                    settings.debug_log,
+                );
+                lastfm::login(
+                    &http_client,
+                    resolve_api_key().as_deref(),
+                    lastfm_api_secret.as_deref(),
+                    config_dir,
+                );
+            }
+            settings::LastfmCommands::Logout {} => lastfm::logout(config_dir),
+        }
+    }
+    let lastfm_api_key = resolve_api_key();
+
     // User settings
     // Main loop interval
     let mut interval = settings.interval.unwrap_or(10);
@@ -47,24 +149,59 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     debug_log!(settings.debug_log, "interval: {}", interval);
 
     // Nicknames for buttons
-    let lastfm_name = settings.lastfm_name.unwrap_or_default();
-    let listenbrainz_name = settings.listenbrainz_name.unwrap_or_default();
+    let lastfm_name = settings.lastfm_name.clone().unwrap_or_default();
+    let listenbrainz_name = settings.listenbrainz_name.clone().unwrap_or_default();
 
-    let small_image = settings.small_image.unwrap_or(String::from("playPause"));
+    // Resolution of album covers and the avatar
+    let cover_size = cover::size::CoverSize::from_name(settings.cover_size.as_deref());
+
+    let global_profile = settings.global_profile();
     let mut lastfm_avatar = String::new();
-    if small_image == "lastfmAvatar" && !lastfm_name.is_empty() {
-        lastfm_avatar = utils::get_lastfm_avatar(&lastfm_name, LASTFM_API_KEY);
+    let uses_lastfm_avatar = std::iter::once(&global_profile)
+        .chain(settings.players.values())
+        .any(|profile| profile.small_image.as_deref() == Some("lastfmAvatar"));
+    if uses_lastfm_avatar && !lastfm_name.is_empty() {
+        lastfm_avatar = utils::get_lastfm_avatar(
+            &http_client,
+            &lastfm_name,
+            lastfm_api_key.as_deref(),
+            cover_size,
+        );
         debug_log!(settings.debug_log, "lastfm_avatar: {}", lastfm_avatar);
     }
-    let lastfm_icon_text = if !lastfm_name.is_empty() {
-        lastfm_name.to_string() + " on Last.fm"
-    } else {
-        String::new()
-    };
 
-    // Force player id and name
-    let force_player_name = settings.force_player_name.unwrap_or_default();
-    let force_player_id = settings.force_player_id.unwrap_or_default();
+    // Settings used to render the activity, global and for every player profile
+    let presence_settings = match presence::PresenceSettings::from_profile(
+        &global_profile,
+        &settings.custom_buttons,
+        &lastfm_name,
+        &listenbrainz_name,
+        &lastfm_avatar,
+    ) {
+        Ok(presence_settings) => presence_settings,
+        Err(err) => {
+            println!("[config] {}", err);
+            std::process::exit(utils::EXIT_CONFIG_ERROR);
+        }
+    };
+    let mut player_presence_settings: HashMap<String, presence::PresenceSettings> = HashMap::new();
+    for (name, profile) in &settings.players {
+        match presence::PresenceSettings::from_profile(
+            profile,
+            &settings.custom_buttons,
+            &lastfm_name,
+            &listenbrainz_name,
+            &lastfm_avatar,
+        ) {
+            Ok(presence_settings) => {
+                player_presence_settings.insert(name.clone(), presence_settings);
+            }
+            Err(err) => {
+                println!("[config] players.{}: {}", name, err);
+                std::process::exit(utils::EXIT_CONFIG_ERROR);
+            }
+        }
+    }
 
     // Enable/disable use of cache
     let mut cache_enabled: bool = !settings.disable_cache;
@@ -72,6 +209,65 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         cache_enabled = false;
     }
 
+    // Scrobbling services, listens that failed to send are kept in the data directory
+    let data_dir = match env::var("XDG_DATA_HOME") {
+        Ok(xdg_data_home) => PathBuf::from(xdg_data_home).join("mpris-discord-rpc"),
+        Err(_) => home_dir.join(".local/share/mpris-discord-rpc"),
+    };
+    let mut scrobblers: Vec<scrobble::Scrobbler> = Vec::new();
+    if settings.lastfm_scrobble {
+        let lastfm_api_secret = lastfm::resolve_api_secret(
+            settings.lastfm_api_secret.as_deref(),
+            config_dir.as_deref(),
+            settings.debug_log,
+        );
+        let session = config_dir.as_deref().and_then(lastfm::load_session);
+        match (&lastfm_api_key, lastfm_api_secret, session) {
+            (Some(api_key), Some(api_secret), Some(session)) => {
+                println!("[last.fm] Scrobbling as {}.", session.name);
+                let service = lastfm::LastfmService::new(&http_client, api_key, &api_secret, session);
+                scrobblers.push(scrobble::Scrobbler::new(
+                    Box::new(service),
+                    if home_exists {
+                        Some(data_dir.join("lastfm_queue.json"))
+                    } else {
+                        None
+                    },
+                ));
+            }
+            (_, None, _) => println!("[last.fm] No API secret available, scrobbling is disabled."),
+            _ => println!("[last.fm] Not logged in, scrobbling is disabled. Run \"mpris-discord-rpc lastfm login\" first."),
+        }
+    }
+    if settings.listenbrainz_scrobble {
+        match listenbrainz::resolve_token(settings.listenbrainz_token.as_deref(), settings.debug_log) {
+            Some(token) => {
+                let url = settings
+                    .listenbrainz_url
+                    .as_deref()
+                    .unwrap_or(listenbrainz::DEFAULT_URL);
+                println!("[listenbrainz] Submitting listens to {}.", url);
+                let service = listenbrainz::ListenbrainzService::new(&http_client, url, &token);
+                scrobblers.push(scrobble::Scrobbler::new(
+                    Box::new(service),
+                    if home_exists {
+                        Some(data_dir.join("listenbrainz_queue.json"))
+                    } else {
+                        None
+                    },
+                ));
+            }
+            None => println!("[listenbrainz] No user token available, submitting listens is disabled. Set \"listenbrainz_token\" in the config file."),
+        }
+    }
+    // Queued listens are sent first, then the submissions run next to the presence loop
+    let scrobble_worker = if scrobblers.is_empty() {
+        None
+    } else {
+        Some(scrobble::ScrobbleWorker::spawn(scrobblers))
+    };
+    let mut listen_tracker = scrobble::ListenTracker::new();
+
     // Allowlist of music players
     let allowlist_enabled: bool = match settings.allowlist.len() {
         0 => false,
@@ -83,91 +279,115 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut last_album: String = String::new();
     let mut last_artist: String = String::new();
     let mut last_album_artist: String = String::new();
-    let mut last_album_id: String = String::new();
     let mut last_track_position: u64 = 0;
     let mut last_is_playing: bool = false;
 
-    let mut _cover_url: String = "".to_string();
-    let mut is_first_time_audio: bool = true;
-    let mut is_first_time_video: bool = true;
+    let mut last_cover_url: String = String::new();
     let mut is_interrupted: bool = false;
-    let mut is_activity_set: bool = false;
 
     // Preventing stdout spam while waiting for player or discord
     let mut dbus_notif: bool = false;
     let mut player_notif: u8 = 0;
     let mut discord_notif: bool = false;
 
-    let mut client_audio = DiscordIpcClient::new("1129859263741837373")?;
-    let mut client_video = DiscordIpcClient::new("1356756023813210293")?;
-    let mut client: &mut DiscordIpcClient = &mut client_audio;
-
-    // Set cache path
-    let cache_dir = match env::var("XDG_CACHE_HOME") {
-        Ok(xgd_cache_home) => PathBuf::from(xgd_cache_home).join("mpris-discord-rpc"),
-        Err(_) => home_dir.join(".cache/mpris-discord-rpc"),
+    // Wake up the main loop on MPRIS signals, the interval is only used as a resync
+    let events = events::Events::new();
+    events.watch_mpris(settings.debug_log);
+
+    // Delay between reconnect attempts, grows on every failure
+    let mut dbus_backoff = backoff::Backoff::new(RECONNECT_MIN_DELAY, RECONNECT_MAX_DELAY);
+    let mut discord_backoff = backoff::Backoff::new(RECONNECT_MIN_DELAY, RECONNECT_MAX_DELAY);
+
+    // Discord clients keyed by application id, created when a player needs them
+    let discord_endpoint = match settings.discord_transport.as_deref() {
+        Some("websocket") => discord::Endpoint::WebSocket(
+            settings
+                .discord_websocket_host
+                .clone()
+                .unwrap_or(String::from(discord::websocket::DEFAULT_HOST)),
+            settings.discord_websocket_port,
+        ),
+        _ => discord::Endpoint::Ipc(settings.discord_socket.as_ref().map(PathBuf::from)),
     };
+    let mut discord = discord::ClientPool::new(discord_endpoint, settings.discord_broadcast);
 
-    if cache_enabled {
+    let cover_cache = if cache_enabled {
         debug_log!(
             settings.debug_log,
             "Cache location: {}",
             &cache_dir.display()
         );
-        if let Err(err) = fs::create_dir_all(&cache_dir) {
-            println!("Could not create cache directory: {}", err);
-        }
-    }
-
-    // Cache file
-    let db_path = cache_dir.join("album_cache.db");
-    let mut album_cache = match PickleDb::load(
-        &db_path,
-        PickleDbDumpPolicy::AutoDump,
-        SerializationMethod::Json,
-    ) {
-        Ok(db) => {
-            if cache_enabled {
-                println!("Cache loaded from file: {}", &db_path.display());
-            }
-            db
-        }
-        Err(_) => {
-            if cache_enabled {
-                println!("Generated new cache file: {}", &db_path.display());
-            }
-            PickleDb::new(
-                &db_path,
-                PickleDbDumpPolicy::AutoDump,
-                SerializationMethod::Json,
-            )
-        }
+        cover::cache::CoverCache::open(&cache_dir, cache_options)
+    } else {
+        cover::cache::CoverCache::disabled()
     };
 
+    // Album cover providers in the order they are asked
+    let cover_upload =
+        settings
+            .cover_upload
+            .as_ref()
+            .map(|service| cover::upload::UploadSettings {
+                service: service.clone(),
+                url: settings
+                    .cover_upload_url
+                    .clone()
+                    .or_else(|| {
+                        cover::upload::UploadSettings::default_url(service).map(String::from)
+                    })
+                    .unwrap_or_default(),
+                public_url: settings.cover_upload_public_url.clone(),
+                authorization: settings
+                    .cover_upload_authorization
+                    .as_deref()
+                    .map(String::from),
+            });
+    let cover_finder = cover::CoverFinder::new(
+        &settings.cover_providers,
+        &http_client,
+        lastfm_api_key.as_deref(),
+        cover_upload,
+        cover::normalize::Normalizer::new(&settings.cover_normalization),
+        cover_size,
+        cover_cache,
+        cache_enabled.then_some(cache_dir.as_path()),
+        settings.debug_log,
+    );
+    let mut cover_worker = cover::worker::CoverWorker::spawn(cover_finder, events.sender());
+
+    // Fixed covers and corrected track info, reloaded when the file changes
+    let mut overrides = overrides::Overrides::new(match &settings.overrides_file {
+        Some(path) => Some(PathBuf::from(path)),
+        None => config_dir
+            .as_ref()
+            .map(|config_dir| config_dir.join("overrides.yaml")),
+    });
+
     loop {
         debug_log!(
             settings.debug_log,
             "───────────────────────────────Loop─1───────────────────────────────────"
         );
         // Connect to MPRIS
-        let player = match PlayerFinder::new() {
-            Ok(player) => {
+        let finder = match PlayerFinder::new() {
+            Ok(finder) => {
                 dbus_notif = false;
-                player
+                dbus_backoff.reset();
+                finder
             }
             Err(err) => {
                 if !dbus_notif {
-                    println!("Could not connect to D-Bus: {}", err);
+                    println!("Could not connect to D-Bus: {}", error::Error::from(err));
                     dbus_notif = true;
                 }
-                sleep(Duration::from_secs(interval));
+                sleep(dbus_backoff.next_delay());
                 continue;
             }
         };
 
         // List available players and exit
         if settings.list_players {
-            match player.find_all() {
+            match finder.find_all() {
                 Ok(player_list) => {
                     if player_list.is_empty() {
                         println!("Could not find any player with MPRIS support.");
@@ -199,23 +419,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             return Ok(());
         }
 
-        // Find active player (and filter them by name if enabled)
-        let player_finder = if allowlist_enabled {
-            let mut allowlist_finder = Err(mpris::FindingError::NoPlayerFound);
-            for allowlist_entry in &settings.allowlist {
-                allowlist_finder = player.find_by_name(&allowlist_entry);
-
-                if allowlist_finder.is_ok() {
-                    break;
-                }
-            }
-            allowlist_finder
-        } else {
-            player.find_active()
-        };
-
         // Connect with player
-        let player = match player_finder {
+        let player = match find_player(&finder, &settings.allowlist) {
             Ok(player) => {
                 if player_notif != 1 {
                     println!("Found active player with MPRIS support.");
@@ -240,79 +445,92 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
 
                 is_interrupted = true;
-                utils::clear_activity(&mut is_activity_set, &mut client);
-                sleep(Duration::from_secs(interval));
+                discord.clear_activity();
+                events.wait(Duration::from_secs(interval));
                 continue;
             }
         };
 
-        // Use video presence if player is in video_players list
-        let is_video_player = settings
-            .video_players
-            .iter()
-            .any(|player_name| player_name == &player.identity().to_string());
+        // Use settings from the player profile if there is one
+        let profile = settings.player_profile(player.identity(), player.bus_name());
+        let presence_settings = match profile {
+            Some((name, _)) => {
+                debug_log!(settings.debug_log, "Using player profile: {}", name);
+                player_presence_settings
+                    .get(name)
+                    .unwrap_or(&presence_settings)
+            }
+            None => &presence_settings,
+        };
+
+        // Use video presence if player is in video_players list or its profile says so
+        let is_video_player =
+            match profile.and_then(|(_, profile)| profile.activity_type.as_deref()) {
+                Some(activity_type) => activity_type == "watching",
+                None => settings
+                    .video_players
+                    .iter()
+                    .any(|player_name| player_name == &player.identity().to_string()),
+            };
         if is_video_player {
-            client = &mut client_video;
             debug_log!(settings.debug_log, "Using video player presence");
         } else {
-            client = &mut client_audio;
             debug_log!(settings.debug_log, "Using audio player presence");
         }
 
-        let player_name = if force_player_name.is_empty() {
-            player.identity().to_string()
-        } else {
-            force_player_name.to_string()
-        };
-        let player_id = if force_player_id.is_empty() {
-            utils::sanitize_name(&player_name)
-        } else {
-            force_player_id.to_string()
+        // Application name is the activity header, the previous player's activity is cleared
+        let app_id = match settings.player_app_id(player.identity(), player.bus_name()) {
+            Some(app_id) => app_id,
+            None if is_video_player => discord::VIDEO_APP_ID,
+            None => discord::AUDIO_APP_ID,
         };
+        debug_log!(settings.debug_log, "app_id: {}", app_id);
+        discord.switch(app_id);
+
+        let player_name = presence_settings.player_name(player.identity());
+        let player_id = presence_settings.player_id(player.identity());
         debug_log!(settings.debug_log, "player_name: {}", player_name);
         debug_log!(settings.debug_log, "player_id: {}", player_id);
 
         // Connect with Discord
-        if (is_first_time_audio && !is_video_player) || (is_first_time_video && is_video_player) {
-            match client.connect() {
-                Ok(_) => {
-                    println!("Connected to Discord.");
-                    discord_notif = false;
-                }
-                Err(_) => {
-                    if !discord_notif {
-                        println!("Could not connect to Discord. Waiting for discord to start...");
-                        discord_notif = true;
-                    }
-                    sleep(Duration::from_secs(interval));
-                    continue;
-                }
-            };
-            if is_video_player {
-                is_first_time_video = false;
-            } else {
-                is_first_time_audio = false;
+        match discord.connect() {
+            Ok(discord::Connection::Connected) => {
+                println!("Connected to Discord at {}.", discord.socket_name());
+                discord_notif = false;
+                discord_backoff.reset();
             }
-        } else {
-            match client.reconnect() {
-                Ok(_) => {
-                    if discord_notif {
-                        println!("Reconnected to Discord.");
-                    }
-                    is_interrupted = true;
-                    discord_notif = false;
+            // Player changed, the activity is updated without a new handshake
+            Ok(discord::Connection::Open) => {
+                is_interrupted = true;
+                discord_notif = false;
+                discord_backoff.reset();
+            }
+            Ok(discord::Connection::Reconnected) => {
+                if discord_notif {
+                    println!("Reconnected to Discord at {}.", discord.socket_name());
                 }
-                Err(_) => {
-                    if !discord_notif {
-                        println!("Could not reconnect to Discord. Waiting for discord to start...");
-                        discord_notif = true;
-                    }
-                    sleep(Duration::from_secs(interval));
-                    continue;
+                is_interrupted = true;
+                discord_notif = false;
+                discord_backoff.reset();
+            }
+            Err(err) => {
+                if !discord_notif {
+                    println!("Could not connect to Discord. Waiting for discord to start...");
+                    discord_notif = true;
                 }
-            };
+                debug_log!(settings.debug_log, "{}", err);
+                sleep(discord_backoff.next_delay());
+                continue;
+            }
         }
 
+        // Signals of other players only matter if one of them would be shown instead
+        let other_player_wins = || {
+            find_player(&finder, &settings.allowlist)
+                .is_ok_and(|found| found.unique_name() != player.unique_name())
+        };
+
+        let mut is_seeked: bool = false;
         loop {
             debug_log!(
                 settings.debug_log,
@@ -323,7 +541,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 Ok(metadata) => metadata,
                 Err(err) => {
                     println!("Could not get metadata from player: {}", err);
-                    utils::clear_activity(&mut is_activity_set, &mut client);
+                    discord.clear_activity();
+                    events.wait(Duration::from_secs(interval));
                     break;
                 }
             };
@@ -333,7 +552,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 Ok(status) => status,
                 Err(err) => {
                     println!("Could not get playback status from player: {}", err);
-                    utils::clear_activity(&mut is_activity_set, &mut client);
+                    discord.clear_activity();
+                    events.wait(Duration::from_secs(interval));
                     break;
                 }
             };
@@ -351,35 +571,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             );
 
             // Parse metadata
-            let title = metadata.title().unwrap_or("Unknown Title");
-            let mut album = metadata.album_name().unwrap_or("Unknown Album");
-            if album.is_empty() {
-                album = "Unknown Album";
-            }
-            let artist = match metadata.artists() {
-                Some(artists) => {
-                    if artists.is_empty() {
-                        "Unknown Artist"
-                    } else {
-                        artists[0]
-                    }
-                }
-                None => "Unknown Artist",
-            };
-            let mut album_artist = match metadata.album_artists() {
-                Some(artists) => {
-                    if artists.is_empty() {
-                        "Unknown Artist"
-                    } else {
-                        artists[0]
-                    }
-                }
-                None => "Unknown Artist",
-            };
-            if album_artist.is_empty() || album_artist == "Unknown Artist" {
-                album_artist = artist;
-            }
-            let album_id = format!("{} - {}", album_artist, album);
+            let mut track = presence::Track::from_metadata(&metadata);
+            let overrides_changed = overrides.reload_if_changed();
+            let applied = overrides.apply(&mut track);
+            debug_log!(settings.debug_log, "overrides: {:?}", applied);
+            let title = track.title.as_str();
+            let album = track.album.as_str();
+            let artist = track.artist.as_str();
+            let album_artist = track.album_artist.as_str();
 
             // If all metadata values are unknown then break
             if (artist == "Unknown Artist")
@@ -387,17 +586,36 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 & (title == "Unknown Title")
             {
                 debug_log!(settings.debug_log, "Unknown metadata, skipping...");
-                sleep(Duration::from_secs(interval));
+                events.wait(Duration::from_secs(interval));
                 break;
             }
 
             // If artist or track is empty then break
-            if (artist.len() == 0) | (title.len() == 0) {
+            if artist.is_empty() | title.is_empty() {
                 debug_log!(settings.debug_log, "Unknown metadata, skipping...");
-                sleep(Duration::from_secs(interval));
+                events.wait(Duration::from_secs(interval));
                 break;
             }
 
+            // Album cover is looked up in the background, the activity is updated when it's found
+            let use_art_url = !presence_settings.disable_mpris_art_url;
+            let cover_url = match &applied.cover {
+                Some(cover_url) => cover_url.clone(),
+                None => match cover_worker.cover(&track, use_art_url) {
+                    cover::worker::Lookup::Done(cover) => {
+                        debug_log!(settings.debug_log, "cover: {:?}", cover);
+                        match cover {
+                            Some(cover) => cover.url,
+                            None => String::from("missing-cover"),
+                        }
+                    }
+                    cover::worker::Lookup::Pending => {
+                        debug_log!(settings.debug_log, "cover: still looking up");
+                        cover_worker.placeholder(&track, use_art_url)
+                    }
+                },
+            };
+
             let mut metadata_changed: bool = false;
             debug_log!(settings.debug_log, "Checking if metadata changed:");
             debug_log!(settings.debug_log, "{title} - {last_title}");
@@ -415,13 +633,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 | (artist != last_artist)
                 | (album_artist != last_album_artist)
                 | (is_playing != last_is_playing)
+                | overrides_changed
+                | (cover_url != last_cover_url)
             {
                 metadata_changed = true;
             }
 
-            // Get track duration if supported by player else return 0
-            let track_duration = metadata.length().unwrap_or(Duration::new(0, 0)).as_secs();
-
             // Get track position if supported by player else return 0 secs
             let mut is_track_position: bool = false;
             let track_position = match player.get_position() {
@@ -438,54 +655,74 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 last_track_position
             );
 
+            // Scrobbling follows the actual playback, so it runs on every refresh
+            if let Some(scrobble_worker) = &scrobble_worker {
+                let now = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+                    Ok(n) => n.as_secs(),
+                    Err(_) => 0,
+                };
+                let position = if is_track_position {
+                    Some(track_position)
+                } else {
+                    None
+                };
+                for event in listen_tracker.update(&track, is_playing, position, now) {
+                    debug_log!(settings.debug_log, "{:?}", event);
+                    scrobble_worker.handle(event);
+                }
+            }
+
             // Check if song repeated
             if (track_position < last_track_position) && !metadata_changed {
                 debug_log!(settings.debug_log, "Detected a potential song seek/replay");
                 metadata_changed = true;
             }
+
+            // Player reported a seek, timestamps need to be updated
+            if is_seeked && !metadata_changed {
+                debug_log!(settings.debug_log, "Detected a seek");
+                metadata_changed = true;
+            }
+            is_seeked = false;
             last_track_position = track_position; // update it before loop continue
             debug_log!(settings.debug_log, "metadata_changed: {}", metadata_changed);
 
+            // Discord clients started later get the activity too
+            for socket in discord.refresh() {
+                println!("Connected to Discord at {}.", socket);
+                is_interrupted = true;
+            }
+
             if !metadata_changed & !is_interrupted {
                 debug_log!(
                     settings.debug_log,
                     "The same metadata and status, skipping..."
                 );
 
-                sleep(Duration::from_secs(interval));
+                match events.wait_for_player(
+                    Duration::from_secs(interval),
+                    player.bus_name(),
+                    player.unique_name(),
+                    other_player_wins,
+                ) {
+                    events::Wakeup::PlayersChanged => break,
+                    events::Wakeup::Seeked => is_seeked = true,
+                    events::Wakeup::Refresh => {}
+                }
                 continue;
             }
 
-            // Get unix time of track start if supported, else return time now
-            let time_start: u64 = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-                Ok(n) => n.as_secs().sub(track_position),
-                Err(_) => 0,
-            };
-
-            // Fetch cover from last.fm
-            _cover_url = utils::get_cover_url(
-                &album_id,
-                &last_album_id,
-                album,
-                _cover_url,
-                cache_enabled,
-                &mut album_cache,
-                album_artist,
-                LASTFM_API_KEY,
-            );
-            let image: String = if _cover_url.is_empty() || _cover_url == "missing-cover" {
-                match metadata.art_url() {
-                    Some(url) => {
-                        if url.starts_with("http") && !settings.disable_mpris_art_url {
-                            url.to_string()
-                        } else {
-                            "missing-cover".to_string()
-                        }
-                    }
-                    _ => "missing-cover".to_string(),
+            // Player icon forced by the overrides file
+            let overridden_settings;
+            let presence_settings = match &applied.player_icon {
+                Some(player_icon) => {
+                    overridden_settings = presence::PresenceSettings {
+                        force_player_id: player_icon.clone(),
+                        ..presence_settings.clone()
+                    };
+                    &overridden_settings
                 }
-            } else {
-                _cover_url.clone()
+                None => presence_settings,
             };
 
             // Save last refresh info
@@ -493,195 +730,96 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             last_album = album.to_string();
             last_artist = artist.to_string();
             last_album_artist = album_artist.to_string();
-            last_album_id = album_id.to_string();
             last_is_playing = is_playing;
+            last_cover_url = cover_url.clone();
 
             // Set activity
-            let song_name: String = format!("{artist} - {title}");
-            let title = format!("{} ", title); // Discord activity min 2 char len bug fix
-            let artist = format!("by: {}", artist);
-            let album = format!("album: {}", album);
-            let status_text: String = if is_playing {
-                "playing".to_string()
-            } else {
-                "paused".to_string()
-            };
-
-            let mut assets = activity::Assets::new().large_image(&image);
-
-            if !settings.hide_album_name {
-                assets = assets.large_text(&album);
-            }
-
-            match small_image.as_str() {
-                "player" => {
-                    if !settings.disable_mpris_art_url && image.contains("ytimg.com/") {
-                        assets = assets.small_image("youtube").small_text("YouTube")
-                    } else {
-                        assets = assets.small_image(&player_id).small_text(&player_name)
-                    }
-                }
-                "lastfmAvatar" => {
-                    if !lastfm_avatar.is_empty() {
-                        assets = assets
-                            .small_image(&lastfm_avatar)
-                            .small_text(&lastfm_icon_text);
-                    }
-                }
-                "none" => {}
-                _ => assets = assets.small_image(&status_text).small_text(&status_text),
-            }
-
-            // Display paused icon anyway if playpack is paused or stopped
-            if status_text != "playing" {
-                assets = assets.small_image(&status_text).small_text(&status_text)
-            }
-
-            let mut payload = activity::Activity::new()
-                .details(&title)
-                .assets(assets)
-                .activity_type(if is_video_player {
-                    activity::ActivityType::Watching
-                } else {
-                    activity::ActivityType::Listening
-                });
-
-            // Don't display Unknown Artist for videos
-            if !(is_video_player && artist == "by: Unknown Artist") {
-                payload = payload.state(&artist);
-            }
-
-            payload = if is_track_position & (track_duration > 0) {
-                let time_end = time_start + track_duration;
-                if is_playing {
-                    payload.timestamps(
-                        activity::Timestamps::new()
-                            .start(time_start.try_into().unwrap())
-                            .end(time_end.try_into().unwrap()),
-                    )
+            let snapshot = presence::PlayerSnapshot {
+                track: track.clone(),
+                is_playing,
+                position: if is_track_position {
+                    Some(track_position)
                 } else {
-                    payload.timestamps(
-                        activity::Timestamps::new().start(time_start.try_into().unwrap()),
-                    )
-                }
-            } else {
-                payload.timestamps(activity::Timestamps::new().end(time_start.try_into().unwrap()))
-            };
-
-            // Create urls for activity links
-            let yt_url: String = format!(
-                "https://www.youtube.com/results?search_query={}",
-                url_escape::encode_component(&song_name)
-            );
-            let lastfm_url: String = format!(
-                "https://www.last.fm/user/{}",
-                url_escape::encode_component(&lastfm_name)
-            );
-            let listenbrainz_url: String = format!(
-                "https://listenbrainz.org/user/{}/",
-                url_escape::encode_component(&listenbrainz_name)
-            );
-            let mpris_url = match metadata.url() {
-                Some(url) => {
-                    let url_string = url.to_string();
-                    if url_string.starts_with("http://") || url_string.starts_with("https://") {
-                        url_string
-                    } else {
-                        String::new()
-                    }
-                }
-                _ => String::new(),
+                    None
+                },
+                player_identity: player.identity().to_string(),
+                is_video_player,
+                cover_url,
+                now: match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+                    Ok(n) => n.as_secs(),
+                    Err(_) => 0,
+                },
+                settings: presence_settings,
             };
+            let description = presence::render(&snapshot);
+            debug_log!(settings.debug_log, "{:#?}", description);
 
-            // Add activity buttons
-            let mut buttons = Vec::new();
-            let mut first_button = "";
-            for button in &settings.button {
-                let initial_len = buttons.len();
-                if initial_len == 2 {
-                    break;
-                }
-
-                // Make sure buttons wont repeat
-                if initial_len > 0 {
-                    if first_button == button {
-                        continue;
-                    }
-                }
-
-                match button.as_str() {
-                    "yt" => {
-                        buttons.push(activity::Button::new(
-                            "Search this song on YouTube",
-                            &yt_url,
-                        ));
-                    }
-                    "lastfm" => {
-                        if lastfm_name.len() > 0 {
-                            buttons.push(activity::Button::new("Last.fm profile", &lastfm_url));
-                        }
-                    }
-                    "listenbrainz" => {
-                        if listenbrainz_name.len() > 0 {
-                            buttons.push(activity::Button::new(
-                                "Listenbrainz profile",
-                                &listenbrainz_url,
-                            ));
-                        }
-                    }
-                    "mprisUrl" => {
-                        if mpris_url.is_empty() {
-                            // if mpris url is empty or not set convert button to yt button
-                            buttons.push(activity::Button::new(
-                                "Search this song on YouTube",
-                                &yt_url,
-                            ));
-                        } else {
-                            if is_video_player {
-                                buttons.push(activity::Button::new("Watch Now", &mpris_url));
-                            } else {
-                                buttons.push(activity::Button::new("Play Now", &mpris_url));
-                            }
-                        }
-                    }
-                    "shamelessAd" => {
-                        buttons.push(activity::Button::new(
-                            "Get This RPC",
-                            "https://github.com/patryk-ku/mpris-discord-rpc",
-                        ));
-                    }
-                    _ => continue,
-                }
-
-                // Make sure buttons wont repeat
-                if initial_len < buttons.len() {
-                    first_button = button;
-                }
-            }
-
-            payload = match buttons.is_empty() {
-                false => payload.buttons(buttons),
-                true => payload,
-            };
+            let song_name: String = format!("{artist} - {title}");
+            let status_text = presence::status_text(is_playing);
+            let payload = description.to_activity();
 
-            match client.set_activity(payload) {
+            match discord.set_activity(payload) {
                 Ok(_) => {
                     is_interrupted = false;
-                    is_activity_set = true;
                     println!("=> Set activity [{status_text}]: {song_name}");
                 }
-                Err(_) => {
-                    println!("Could not set activity.");
+                // Connection is fine, sending the same activity again would fail the same way
+                Err(err @ error::Error::Rejected(_)) => {
+                    println!("Could not set activity: {}", err);
+                    is_interrupted = false;
+                }
+                Err(err @ error::Error::RateLimited(_)) => {
+                    println!(
+                        "Could not set activity: {}. Trying again in {} seconds.",
+                        err,
+                        RATE_LIMIT_DELAY.as_secs()
+                    );
+                    is_interrupted = true;
+                    sleep(RATE_LIMIT_DELAY);
+                    continue;
+                }
+                Err(err) => {
+                    println!("Could not set activity: {}", err);
                     is_interrupted = true;
-                    is_activity_set = false;
-                    client.close()?;
+                    // Socket is most likely already closed, reconnect is handled in the outer loop
+                    if let Err(err) = discord.close() {
+                        debug_log!(settings.debug_log, "{}", err);
+                    }
+                    sleep(discord_backoff.next_delay());
                     break;
                 }
             };
 
-            sleep(Duration::from_secs(interval));
+            match events.wait_for_player(
+                Duration::from_secs(interval),
+                player.bus_name(),
+                player.unique_name(),
+                other_player_wins,
+            ) {
+                events::Wakeup::PlayersChanged => break,
+                events::Wakeup::Seeked => is_seeked = true,
+                events::Wakeup::Refresh => {}
+            }
         }
+    }
+}
 
-        sleep(Duration::from_secs(interval));
+// Player the activity is shown for: the first running player from the allowlist, otherwise the
+// active one (playing, then paused, then any other)
+fn find_player(
+    finder: &PlayerFinder,
+    allowlist: &[String],
+) -> Result<mpris::Player, mpris::FindingError> {
+    if allowlist.is_empty() {
+        return finder.find_active();
+    }
+
+    let mut found = Err(mpris::FindingError::NoPlayerFound);
+    for name in allowlist {
+        found = finder.find_by_name(name);
+        if found.is_ok() {
+            break;
+        }
     }
+    found
 }
@@ -0,0 +1,287 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::error::Error;
+use crate::presence::Track;
+
+// Which tracks an override applies to. Patterns are case-insensitive, "*" matches any text
+// and "?" a single character. Fields that are not set match every track.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Pattern {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub title: Option<String>,
+}
+
+// Corrected track info, used for the activity, cover lookups and scrobbles
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Corrections {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+}
+
+// Entry of the overrides file
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Override {
+    #[serde(rename = "match")]
+    pub pattern: Pattern,
+    /// Fixed album cover, used instead of the cover providers
+    pub cover: Option<String>,
+    #[serde(default)]
+    pub set: Corrections,
+    /// Icon next to the album cover, same as "force_player_id"
+    pub player_icon: Option<String>,
+}
+
+impl Override {
+    fn validate(&self) -> Result<(), String> {
+        let pattern = &self.pattern;
+        if pattern.artist.is_none() && pattern.album.is_none() && pattern.title.is_none() {
+            return Err(String::from(
+                "\"match\" needs at least one of: artist, album, title",
+            ));
+        }
+        if let Some(cover) = &self.cover {
+            if !cover.starts_with("https://") && !cover.starts_with("http://") {
+                return Err(format!("cover \"{}\" is not a http(s) link", cover));
+            }
+        }
+        Ok(())
+    }
+
+    fn matches(&self, track: &Track) -> bool {
+        let field_matches = |pattern: &Option<String>, value: &str| match pattern {
+            Some(pattern) => glob_match(pattern, value),
+            None => true,
+        };
+        field_matches(&self.pattern.artist, &track.artist)
+            && field_matches(&self.pattern.album, &track.album)
+            && field_matches(&self.pattern.title, &track.title)
+    }
+}
+
+// What the matching overrides changed besides the track info
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Applied {
+    pub cover: Option<String>,
+    pub player_icon: Option<String>,
+}
+
+// Overrides file, read again when it's modified
+pub struct Overrides {
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    entries: Vec<Override>,
+}
+
+impl Overrides {
+    // "path" is None if $HOME is not set and no file was given
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let mut overrides = Overrides {
+            path,
+            modified: None,
+            entries: Vec::new(),
+        };
+        overrides.reload_if_changed();
+        overrides
+    }
+
+    // Returns true if the overrides changed. A broken file is reported and the previous
+    // overrides are kept, so a typo doesn't reset all covers.
+    pub fn reload_if_changed(&mut self) -> bool {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return false,
+        };
+
+        let modified = fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+
+        // File was removed
+        if modified.is_none() {
+            let changed = !self.entries.is_empty();
+            self.entries.clear();
+            return changed;
+        }
+
+        match load(path) {
+            Ok(entries) => {
+                println!(
+                    "[overrides] Loaded {} overrides from file: {}",
+                    entries.len(),
+                    path.display()
+                );
+                self.entries = entries;
+                true
+            }
+            Err(err) => {
+                println!("[overrides] {}: {}", path.display(), err);
+                false
+            }
+        }
+    }
+
+    // Matching overrides are applied in the order of the file, later ones win.
+    // Patterns are always compared with the track info sent by the player.
+    pub fn apply(&self, track: &mut Track) -> Applied {
+        let original = track.clone();
+        let mut applied = Applied::default();
+        for entry in self.entries.iter().filter(|entry| entry.matches(&original)) {
+            if let Some(title) = &entry.set.title {
+                track.title = title.clone();
+            }
+            if let Some(artist) = &entry.set.artist {
+                track.artist = artist.clone();
+                track.artists = vec![artist.clone()];
+            }
+            if let Some(album) = &entry.set.album {
+                track.album = album.clone();
+            }
+            if let Some(album_artist) = &entry.set.album_artist {
+                track.album_artist = album_artist.clone();
+            }
+            if entry.cover.is_some() {
+                applied.cover = entry.cover.clone();
+            }
+            if entry.player_icon.is_some() {
+                applied.player_icon = entry.player_icon.clone();
+            }
+        }
+        applied
+    }
+}
+
+fn load(path: &Path) -> Result<Vec<Override>, Error> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| Error::Config(format!("could not read the file: {}", err)))?;
+    if content.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let entries: Vec<Override> = serde_yaml::from_str(&content)?;
+    for (index, entry) in entries.iter().enumerate() {
+        entry
+            .validate()
+            .map_err(|err| Error::Config(format!("entry {}: {}", index + 1, err)))?;
+    }
+    Ok(entries)
+}
+
+// Case-insensitive wildcard match, "*" is any text and "?" is one character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Position after the last "*" and the text position it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            p += 1;
+            backtrack = Some((p, t));
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|char| *char == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track() -> Track {
+        Track {
+            title: String::from("Paranoid Android"),
+            artist: String::from("Radiohead"),
+            album: String::from("OK Computer OKNOTOK 1997 2017"),
+            album_artist: String::from("Radiohead"),
+            artists: vec![String::from("Radiohead")],
+            ..Track::default()
+        }
+    }
+
+    fn overrides(yaml: &str) -> Overrides {
+        Overrides {
+            path: None,
+            modified: None,
+            entries: serde_yaml::from_str(yaml).unwrap(),
+        }
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(glob_match("radiohead", "Radiohead"));
+        assert!(glob_match("OK Computer*", "OK Computer OKNOTOK 1997 2017"));
+        assert!(glob_match("*android", "Paranoid Android"));
+        assert!(glob_match("Paran?id*", "Paranoid Android"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("OK Computer", "OK Computer OKNOTOK 1997 2017"));
+        assert!(!glob_match("?", ""));
+    }
+
+    #[test]
+    fn corrections() {
+        let overrides = overrides(
+            r#"
+- match:
+    artist: "Radiohead"
+    album: "OK Computer*"
+  cover: "https://example.com/ok-computer.jpg"
+  set:
+    album: "OK Computer"
+- match:
+    title: "Paranoid Android"
+  player_icon: "vlc"
+- match:
+    artist: "Muse"
+  cover: "https://example.com/muse.jpg"
+"#,
+        );
+
+        let mut track = track();
+        let applied = overrides.apply(&mut track);
+        assert_eq!(track.album, "OK Computer");
+        assert_eq!(track.artist, "Radiohead");
+        assert_eq!(
+            applied,
+            Applied {
+                cover: Some(String::from("https://example.com/ok-computer.jpg")),
+                player_icon: Some(String::from("vlc")),
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_entries() {
+        let entry = |yaml: &str| serde_yaml::from_str::<Override>(yaml);
+        assert!(entry("match: {}\ncover: \"https://example.com\"")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(entry("match: {album: \"x\"}\ncover: \"file:///cover.jpg\"")
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!(entry("match: {album: \"x\"}\ncolor: red").is_err());
+    }
+}
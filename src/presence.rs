@@ -0,0 +1,882 @@
+use discord_rich_presence::activity;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::settings::{CustomButtonConfig, PlayerProfile};
+use crate::template::{Template, Templates};
+use crate::utils;
+
+// Discord rejects activity texts shorter or longer than this
+const MIN_TEXT_LEN: usize = 2;
+const MAX_TEXT_LEN: usize = 128;
+const MAX_BUTTON_LABEL_LEN: usize = 32;
+const MAX_BUTTON_URL_LEN: usize = 512;
+
+// Button defined by the user, both fields are templates
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustomButton {
+    pub label: Template,
+    pub url: Template,
+}
+
+impl CustomButton {
+    // Only limits that every track would break are checked here, the rest is checked when the
+    // button is rendered. Errors start with the name of the invalid field.
+    pub fn parse(config: &CustomButtonConfig) -> Result<Self, Error> {
+        let parse = |field: &str, template: &str| {
+            Template::parse(template).map_err(|err| match err {
+                Error::Config(msg) => Error::Config(format!("{}: {}", field, msg)),
+                err => err,
+            })
+        };
+        let label = parse("label", &config.label)?;
+        let url = parse("url", &config.url)?;
+
+        if label.static_text().trim().chars().count() > MAX_BUTTON_LABEL_LEN {
+            return Err(Error::Config(format!(
+                "label: \"{}\" is longer than {} characters",
+                config.label, MAX_BUTTON_LABEL_LEN
+            )));
+        }
+
+        // A link from the player is checked when it's rendered
+        let prefix = url.literal_prefix();
+        let prefix = prefix.trim_start();
+        let is_link = prefix.starts_with("http://")
+            || prefix.starts_with("https://")
+            || (prefix.is_empty() && url.starts_with_field("url"));
+        if !is_link {
+            return Err(Error::Config(format!(
+                "url: \"{}\" must start with \"https://\", \"http://\" or {{url}}",
+                config.url
+            )));
+        }
+
+        Ok(CustomButton { label, url })
+    }
+}
+
+// Track info parsed from MPRIS metadata, with placeholders for missing values
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Track {
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub album_artist: String,
+    pub art_url: Option<String>,
+    pub url: Option<String>,
+    /// Track length in seconds, 0 if not supported by player
+    pub duration: u64,
+    /// All artists, the first one is also in `artist`
+    pub artists: Vec<String>,
+    pub track_number: Option<i32>,
+    pub disc_number: Option<i32>,
+    pub genres: Vec<String>,
+    pub year: Option<String>,
+    /// MusicBrainz IDs from file tags, only some players provide them
+    pub recording_mbid: Option<String>,
+    pub release_mbid: Option<String>,
+    pub artist_mbids: Vec<String>,
+}
+
+impl Track {
+    pub fn from_metadata(metadata: &mpris::Metadata) -> Self {
+        let title = metadata.title().unwrap_or("Unknown Title");
+        let mut album = metadata.album_name().unwrap_or("Unknown Album");
+        if album.is_empty() {
+            album = "Unknown Album";
+        }
+        let artist = match metadata.artists() {
+            Some(artists) => {
+                if artists.is_empty() {
+                    "Unknown Artist"
+                } else {
+                    artists[0]
+                }
+            }
+            None => "Unknown Artist",
+        };
+        let mut album_artist = match metadata.album_artists() {
+            Some(artists) => {
+                if artists.is_empty() {
+                    "Unknown Artist"
+                } else {
+                    artists[0]
+                }
+            }
+            None => "Unknown Artist",
+        };
+        if album_artist.is_empty() || album_artist == "Unknown Artist" {
+            album_artist = artist;
+        }
+
+        Track {
+            title: title.to_string(),
+            artist: artist.to_string(),
+            album: album.to_string(),
+            album_artist: album_artist.to_string(),
+            art_url: metadata.art_url().map(|url| url.to_string()),
+            url: metadata.url().map(|url| url.to_string()),
+            duration: metadata.length().unwrap_or(Duration::new(0, 0)).as_secs(),
+            artists: metadata
+                .artists()
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|artist| !artist.is_empty())
+                .map(|artist| artist.to_string())
+                .collect(),
+            track_number: metadata.track_number().filter(|number| *number > 0),
+            disc_number: metadata.disc_number().filter(|number| *number > 0),
+            genres: metadata
+                .get("xesam:genre")
+                .and_then(|genre| genre.as_str_array())
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|genre| !genre.is_empty())
+                .map(|genre| genre.to_string())
+                .collect(),
+            // Release date in ISO 8601, only the year is used
+            year: metadata
+                .get("xesam:contentCreated")
+                .and_then(|date| date.as_str())
+                .and_then(|date| date.get(..4))
+                .filter(|year| year.chars().all(|char| char.is_ascii_digit()))
+                .map(|year| year.to_string()),
+            recording_mbid: mbids(metadata, &["xesam:musicBrainzTrackID", "mb:trackId"])
+                .into_iter()
+                .next(),
+            release_mbid: mbids(metadata, &["xesam:musicBrainzAlbumID", "mb:albumId"])
+                .into_iter()
+                .next(),
+            artist_mbids: mbids(metadata, &["xesam:musicBrainzArtistID", "mb:artistId"]),
+        }
+    }
+
+    // Used as cache key for album covers
+    pub fn album_id(&self) -> String {
+        format!("{} - {}", self.album_artist, self.album)
+    }
+}
+
+// Players don't agree on the MusicBrainz metadata keys, and some send a list instead of a string
+fn mbids(metadata: &mpris::Metadata, keys: &[&str]) -> Vec<String> {
+    for key in keys {
+        let ids: Vec<String> = match metadata.get(key) {
+            Some(mpris::MetadataValue::String(id)) => vec![id.to_string()],
+            Some(value) => value
+                .as_str_array()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|id| id.to_string())
+                .collect(),
+            None => continue,
+        };
+        let ids: Vec<String> = ids
+            .into_iter()
+            .map(|id| id.trim().to_string())
+            .filter(|id| !id.is_empty())
+            .collect();
+        if !ids.is_empty() {
+            return ids;
+        }
+    }
+    Vec::new()
+}
+
+// User settings that affect how the activity looks
+#[derive(Debug, Clone, Default)]
+pub struct PresenceSettings {
+    pub small_image: String,
+    pub buttons: Vec<String>,
+    pub lastfm_name: String,
+    pub listenbrainz_name: String,
+    pub lastfm_avatar: String,
+    pub force_player_id: String,
+    pub force_player_name: String,
+    pub hide_album_name: bool,
+    pub disable_mpris_art_url: bool,
+    pub templates: Templates,
+    pub custom_buttons: HashMap<String, CustomButton>,
+}
+
+impl PresenceSettings {
+    // Templates are checked here, an invalid one would break every activity update
+    pub fn from_profile(
+        profile: &PlayerProfile,
+        custom_buttons: &HashMap<String, CustomButtonConfig>,
+        lastfm_name: &str,
+        listenbrainz_name: &str,
+        lastfm_avatar: &str,
+    ) -> Result<Self, Error> {
+        let mut parsed_buttons = HashMap::new();
+        for (name, button) in custom_buttons {
+            let button = CustomButton::parse(button).map_err(|err| match err {
+                Error::Config(msg) => Error::Config(format!("custom_buttons.{}.{}", name, msg)),
+                err => err,
+            })?;
+            parsed_buttons.insert(name.clone(), button);
+        }
+
+        Ok(PresenceSettings {
+            small_image: profile
+                .small_image
+                .clone()
+                .unwrap_or(String::from("playPause")),
+            buttons: profile.button.clone().unwrap_or_default(),
+            lastfm_name: lastfm_name.to_string(),
+            listenbrainz_name: listenbrainz_name.to_string(),
+            lastfm_avatar: lastfm_avatar.to_string(),
+            force_player_id: profile.force_player_id.clone().unwrap_or_default(),
+            force_player_name: profile.force_player_name.clone().unwrap_or_default(),
+            hide_album_name: profile.hide_album_name.unwrap_or_default(),
+            disable_mpris_art_url: profile.disable_mpris_art_url.unwrap_or_default(),
+            templates: Templates::parse(
+                &profile.details_template,
+                &profile.state_template,
+                &profile.large_text_template,
+                &profile.small_text_template,
+            )?,
+            custom_buttons: parsed_buttons,
+        })
+    }
+
+    pub fn player_name(&self, identity: &str) -> String {
+        if self.force_player_name.is_empty() {
+            identity.to_string()
+        } else {
+            self.force_player_name.to_string()
+        }
+    }
+
+    pub fn player_id(&self, identity: &str) -> String {
+        if self.force_player_id.is_empty() {
+            utils::sanitize_name(&self.player_name(identity))
+        } else {
+            self.force_player_id.to_string()
+        }
+    }
+}
+
+// Everything needed to render the activity at a given moment
+#[derive(Debug, Clone)]
+pub struct PlayerSnapshot<'a> {
+    pub track: Track,
+    pub is_playing: bool,
+    /// Track position in seconds, None if not supported by player
+    pub position: Option<u64>,
+    /// MPRIS identity of the player
+    pub player_identity: String,
+    pub is_video_player: bool,
+    /// Album cover from the first provider that found one, empty or "missing-cover" if not found
+    pub cover_url: String,
+    /// Current unix time in seconds
+    pub now: u64,
+    pub settings: &'a PresenceSettings,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActivityKind {
+    Listening,
+    Watching,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ButtonDescription {
+    pub label: String,
+    pub url: String,
+}
+
+// Owned description of the Discord activity, converted to the IPC payload right before sending
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ActivityDescription {
+    pub kind: ActivityKind,
+    pub details: String,
+    pub state: Option<String>,
+    pub large_image: String,
+    pub large_text: Option<String>,
+    pub small_image: Option<String>,
+    pub small_text: Option<String>,
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+    pub buttons: Vec<ButtonDescription>,
+}
+
+impl ActivityDescription {
+    pub fn to_activity(&self) -> activity::Activity<'_> {
+        let mut assets = activity::Assets::new().large_image(&self.large_image);
+        if let Some(large_text) = &self.large_text {
+            assets = assets.large_text(large_text);
+        }
+        if let Some(small_image) = &self.small_image {
+            assets = assets.small_image(small_image);
+        }
+        if let Some(small_text) = &self.small_text {
+            assets = assets.small_text(small_text);
+        }
+
+        let mut timestamps = activity::Timestamps::new();
+        if let Some(start) = self.start {
+            timestamps = timestamps.start(start);
+        }
+        if let Some(end) = self.end {
+            timestamps = timestamps.end(end);
+        }
+
+        let mut payload = activity::Activity::new()
+            .details(&self.details)
+            .assets(assets)
+            .timestamps(timestamps)
+            .activity_type(match self.kind {
+                ActivityKind::Listening => activity::ActivityType::Listening,
+                ActivityKind::Watching => activity::ActivityType::Watching,
+            });
+
+        if let Some(state) = &self.state {
+            payload = payload.state(state);
+        }
+
+        if !self.buttons.is_empty() {
+            payload = payload.buttons(
+                self.buttons
+                    .iter()
+                    .map(|button| activity::Button::new(&button.label, &button.url))
+                    .collect(),
+            );
+        }
+
+        payload
+    }
+}
+
+pub fn status_text(is_playing: bool) -> &'static str {
+    if is_playing {
+        "playing"
+    } else {
+        "paused"
+    }
+}
+
+// Build the activity from a snapshot of the player state
+pub fn render(snapshot: &PlayerSnapshot) -> ActivityDescription {
+    let settings = snapshot.settings;
+    let track = &snapshot.track;
+
+    // MPRIS artUrl is one of the cover providers, it's already used here if enabled
+    let image: String = if snapshot.cover_url.is_empty() {
+        "missing-cover".to_string()
+    } else {
+        snapshot.cover_url.clone()
+    };
+
+    let status_text = status_text(snapshot.is_playing).to_string();
+    let player_name = settings.player_name(&snapshot.player_identity);
+    let player_id = settings.player_id(&snapshot.player_identity);
+
+    let values = template_values(snapshot, &player_name, &status_text);
+    let render_template = |template: &Option<crate::template::Template>| {
+        template
+            .as_ref()
+            .map(|template| fit_text(&template.render(&values)))
+    };
+
+    let large_text = match render_template(&settings.templates.large_text) {
+        Some(large_text) => large_text,
+        None if settings.hide_album_name => None,
+        None => Some(format!("album: {}", track.album)),
+    };
+
+    let mut small_image: Option<String> = None;
+    let mut small_text: Option<String> = None;
+    match settings.small_image.as_str() {
+        "player" => {
+            if !settings.disable_mpris_art_url && image.contains("ytimg.com/") {
+                small_image = Some("youtube".to_string());
+                small_text = Some("YouTube".to_string());
+            } else {
+                small_image = Some(player_id);
+                small_text = Some(player_name);
+            }
+        }
+        "lastfmAvatar" => {
+            if !settings.lastfm_avatar.is_empty() {
+                small_image = Some(settings.lastfm_avatar.clone());
+                small_text = Some(format!("{} on Last.fm", settings.lastfm_name));
+            }
+        }
+        "none" => {}
+        _ => {
+            small_image = Some(status_text.clone());
+            small_text = Some(status_text.clone());
+        }
+    }
+
+    // Display paused icon anyway if playpack is paused or stopped
+    if !snapshot.is_playing {
+        small_image = Some(status_text.clone());
+        small_text = Some(status_text.clone());
+    }
+
+    if small_text.is_some() {
+        if let Some(text) = render_template(&settings.templates.small_text) {
+            small_text = text;
+        }
+    }
+
+    // Don't display Unknown Artist for videos
+    let state = match render_template(&settings.templates.state) {
+        Some(state) => state,
+        None if snapshot.is_video_player && track.artist == "Unknown Artist" => None,
+        None => Some(format!("by: {}", track.artist)),
+    };
+
+    let details = match render_template(&settings.templates.details) {
+        Some(Some(details)) => details,
+        _ => format!("{} ", track.title), // Discord activity min 2 char len bug fix
+    };
+
+    // Get unix time of track start if supported, else use time now
+    // Players sometimes report a position larger than the track length or the clock, never go below 0
+    let position = snapshot.position.unwrap_or(0);
+    let time_start = i64::try_from(snapshot.now.saturating_sub(position)).unwrap_or(0);
+    let (start, end) = if snapshot.position.is_some() && track.duration > 0 {
+        let time_end = time_start.saturating_add(i64::try_from(track.duration).unwrap_or(0));
+        if snapshot.is_playing {
+            (Some(time_start), Some(time_end))
+        } else {
+            (Some(time_start), None)
+        }
+    } else {
+        (None, Some(time_start))
+    };
+
+    ActivityDescription {
+        kind: if snapshot.is_video_player {
+            ActivityKind::Watching
+        } else {
+            ActivityKind::Listening
+        },
+        details,
+        state,
+        large_image: image,
+        large_text,
+        small_image,
+        small_text,
+        start,
+        end,
+        buttons: render_buttons(snapshot, &values),
+    }
+}
+
+// Values available in templates, placeholders like "Unknown Artist" count as missing
+fn template_values(
+    snapshot: &PlayerSnapshot,
+    player_name: &str,
+    status_text: &str,
+) -> HashMap<&'static str, String> {
+    let track = &snapshot.track;
+    let mut values = HashMap::new();
+    let mut insert = |field: &'static str, value: &str| {
+        if !value.is_empty() {
+            values.insert(field, value.to_string());
+        }
+    };
+
+    if track.title != "Unknown Title" {
+        insert("title", &track.title);
+    }
+    if track.artist != "Unknown Artist" {
+        insert("artist", &track.artist);
+        insert("artists", &track.artists.join(", "));
+    }
+    if track.album != "Unknown Album" {
+        insert("album", &track.album);
+    }
+    if track.album_artist != "Unknown Artist" {
+        insert("album_artist", &track.album_artist);
+    }
+    if let Some(track_number) = track.track_number {
+        insert("track_number", &track_number.to_string());
+    }
+    if let Some(disc_number) = track.disc_number {
+        insert("disc_number", &disc_number.to_string());
+    }
+    insert("genre", &track.genres.join(", "));
+    if let Some(year) = &track.year {
+        insert("year", year);
+    }
+    if let Some(url) = &track.url {
+        if url.starts_with("http://") || url.starts_with("https://") {
+            insert("url", url);
+        }
+    }
+    insert("player", player_name);
+    insert("status", status_text);
+    if let Some(position) = snapshot.position {
+        insert("position", &format_time(position));
+    }
+    if track.duration > 0 {
+        insert("duration", &format_time(track.duration));
+    }
+
+    values
+}
+
+// 3:07 or 1:02:07
+fn format_time(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+// Make the rendered template acceptable for Discord, None if there is nothing to show
+fn fit_text(text: &str) -> Option<String> {
+    let len = text.chars().count();
+    if len == 0 {
+        None
+    } else if len < MIN_TEXT_LEN {
+        Some(format!("{} ", text))
+    } else if len > MAX_TEXT_LEN {
+        let text: String = text.chars().take(MAX_TEXT_LEN - 1).collect();
+        Some(format!("{}…", text))
+    } else {
+        Some(text.to_string())
+    }
+}
+
+fn render_buttons(
+    snapshot: &PlayerSnapshot,
+    values: &HashMap<&'static str, String>,
+) -> Vec<ButtonDescription> {
+    let settings = snapshot.settings;
+    let track = &snapshot.track;
+
+    // Create urls for activity links
+    let song_name: String = format!("{} - {}", track.artist, track.title);
+    let yt_url: String = format!(
+        "https://www.youtube.com/results?search_query={}",
+        url_escape::encode_component(&song_name)
+    );
+    let lastfm_url: String = format!(
+        "https://www.last.fm/user/{}",
+        url_escape::encode_component(&settings.lastfm_name)
+    );
+    let listenbrainz_url: String = format!(
+        "https://listenbrainz.org/user/{}/",
+        url_escape::encode_component(&settings.listenbrainz_name)
+    );
+    let mpris_url = match &track.url {
+        Some(url) => {
+            if url.starts_with("http://") || url.starts_with("https://") {
+                url.to_string()
+            } else {
+                String::new()
+            }
+        }
+        _ => String::new(),
+    };
+
+    let button = |label: &str, url: &str| ButtonDescription {
+        label: label.to_string(),
+        url: url.to_string(),
+    };
+
+    // Add activity buttons
+    let mut buttons = Vec::new();
+    let mut first_button = "";
+    for name in &settings.buttons {
+        let initial_len = buttons.len();
+        if initial_len == 2 {
+            break;
+        }
+
+        // Make sure buttons wont repeat
+        if initial_len > 0 && first_button == name {
+            continue;
+        }
+
+        match name.as_str() {
+            "yt" => {
+                buttons.push(button("Search this song on YouTube", &yt_url));
+            }
+            "lastfm" => {
+                if !settings.lastfm_name.is_empty() {
+                    buttons.push(button("Last.fm profile", &lastfm_url));
+                }
+            }
+            "listenbrainz" => {
+                if !settings.listenbrainz_name.is_empty() {
+                    buttons.push(button("Listenbrainz profile", &listenbrainz_url));
+                }
+            }
+            "mprisUrl" => {
+                if mpris_url.is_empty() {
+                    // if mpris url is empty or not set convert button to yt button
+                    buttons.push(button("Search this song on YouTube", &yt_url));
+                } else if snapshot.is_video_player {
+                    buttons.push(button("Watch Now", &mpris_url));
+                } else {
+                    buttons.push(button("Play Now", &mpris_url));
+                }
+            }
+            "shamelessAd" => {
+                buttons.push(button(
+                    "Get This RPC",
+                    "https://github.com/patryk-ku/mpris-discord-rpc",
+                ));
+            }
+            _ => match settings.custom_buttons.get(name) {
+                Some(custom_button) => {
+                    if let Some(custom_button) = render_custom_button(name, custom_button, values) {
+                        buttons.push(custom_button);
+                    }
+                }
+                None => continue,
+            },
+        }
+
+        // Make sure buttons wont repeat
+        if initial_len < buttons.len() {
+            first_button = name;
+        }
+    }
+
+    buttons
+}
+
+// Discord rejects the whole activity if a button is invalid, so skip the button instead
+fn render_custom_button(
+    name: &str,
+    custom_button: &CustomButton,
+    values: &HashMap<&'static str, String>,
+) -> Option<ButtonDescription> {
+    let label = custom_button.label.render(values);
+    let label = if label.chars().count() > MAX_BUTTON_LABEL_LEN {
+        println!(
+            "[button] Label of \"{}\" is longer than {} characters, shortening it: {}",
+            name, MAX_BUTTON_LABEL_LEN, label
+        );
+        let label: String = label.chars().take(MAX_BUTTON_LABEL_LEN - 1).collect();
+        format!("{}…", label)
+    } else {
+        label
+    };
+
+    // Link from the player is already a URL, everything else has to be encoded
+    let url_values: HashMap<&str, String> = values
+        .iter()
+        .map(|(field, value)| match *field {
+            "url" => (*field, value.clone()),
+            _ => (*field, url_escape::encode_component(value).to_string()),
+        })
+        .collect();
+    // Spaces from the template text itself are allowed for readability
+    let url = custom_button.url.render(&url_values).replace(' ', "%20");
+
+    let problem = if label.is_empty() {
+        String::from("the label is empty")
+    } else if !(url.starts_with("http://") || url.starts_with("https://")) {
+        format!("\"{}\" is not a http(s) link", url)
+    } else if url.len() > MAX_BUTTON_URL_LEN {
+        format!("the url is longer than {} characters", MAX_BUTTON_URL_LEN)
+    } else {
+        return Some(ButtonDescription { label, url });
+    };
+    println!("[button] Skipping \"{}\", {}.", name, problem);
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn track() -> Track {
+        Track {
+            title: "Paranoid Android".to_string(),
+            artist: "Radiohead".to_string(),
+            album: "OK Computer".to_string(),
+            album_artist: "Radiohead".to_string(),
+            art_url: Some("file:///tmp/cover.jpg".to_string()),
+            url: Some("file:///music/paranoid_android.flac".to_string()),
+            duration: 387,
+            ..Default::default()
+        }
+    }
+
+    fn settings() -> PresenceSettings {
+        PresenceSettings {
+            small_image: "playPause".to_string(),
+            buttons: vec!["yt".to_string(), "lastfm".to_string()],
+            lastfm_name: "nickname".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn snapshot(settings: &PresenceSettings) -> PlayerSnapshot<'_> {
+        PlayerSnapshot {
+            track: track(),
+            is_playing: true,
+            position: Some(60),
+            player_identity: "Strawberry".to_string(),
+            is_video_player: false,
+            cover_url: "https://lastfm.freetls.fastly.net/i/u/300x300/cover.png".to_string(),
+            now: NOW,
+            settings,
+        }
+    }
+
+    #[test]
+    fn audio() {
+        let settings = settings();
+        insta::assert_json_snapshot!(render(&snapshot(&settings)));
+    }
+
+    #[test]
+    fn video() {
+        let settings = PresenceSettings {
+            small_image: "player".to_string(),
+            buttons: vec!["mprisUrl".to_string(), "shamelessAd".to_string()],
+            ..Default::default()
+        };
+        let mut snapshot = snapshot(&settings);
+        snapshot.track = Track {
+            title: "Some video".to_string(),
+            artist: "Unknown Artist".to_string(),
+            album: "Unknown Album".to_string(),
+            album_artist: "Unknown Artist".to_string(),
+            art_url: Some("https://i.ytimg.com/vi/abcdef/hqdefault.jpg".to_string()),
+            url: Some("https://www.youtube.com/watch?v=abcdef".to_string()),
+            duration: 600,
+            ..Default::default()
+        };
+        snapshot.player_identity = "Mozilla Firefox".to_string();
+        snapshot.is_video_player = true;
+        // Thumbnail found by the MPRIS cover provider
+        snapshot.cover_url = "https://i.ytimg.com/vi/abcdef/hqdefault.jpg".to_string();
+        insta::assert_json_snapshot!(render(&snapshot));
+    }
+
+    #[test]
+    fn paused() {
+        let settings = PresenceSettings {
+            small_image: "player".to_string(),
+            ..settings()
+        };
+        let mut snapshot = snapshot(&settings);
+        snapshot.is_playing = false;
+        insta::assert_json_snapshot!(render(&snapshot));
+    }
+
+    #[test]
+    fn no_duration() {
+        let settings = PresenceSettings {
+            hide_album_name: true,
+            ..settings()
+        };
+        let mut snapshot = snapshot(&settings);
+        snapshot.track.duration = 0;
+        snapshot.position = None;
+        insta::assert_json_snapshot!(render(&snapshot));
+    }
+
+    #[test]
+    fn missing_cover() {
+        let settings = PresenceSettings {
+            buttons: vec![
+                "mprisUrl".to_string(),
+                "mprisUrl".to_string(),
+                "listenbrainz".to_string(),
+            ],
+            ..settings()
+        };
+        let mut snapshot = snapshot(&settings);
+        snapshot.cover_url = "missing-cover".to_string();
+        insta::assert_json_snapshot!(render(&snapshot));
+    }
+
+    #[test]
+    fn templates() {
+        let settings = PresenceSettings {
+            small_image: "player".to_string(),
+            templates: Templates::parse(
+                &Some("{artist} — {album}[ ({year})]".to_string()),
+                &Some("{title}[ #{track_number}]".to_string()),
+                &Some("{genre|\"no genre\"}".to_string()),
+                &Some("{player} [{position}/{duration}]".to_string()),
+            )
+            .unwrap(),
+            ..settings()
+        };
+        let mut snapshot = snapshot(&settings);
+        snapshot.track.year = Some("1997".to_string());
+        insta::assert_json_snapshot!(render(&snapshot));
+    }
+
+    #[test]
+    fn custom_buttons() {
+        let button = |label: &str, url: &str| CustomButtonConfig {
+            label: label.to_string(),
+            url: url.to_string(),
+        };
+        let mut custom_buttons = HashMap::new();
+        custom_buttons.insert(
+            "discogs".to_string(),
+            button(
+                "{album} on Discogs",
+                "https://www.discogs.com/search?q={artist} {album}",
+            ),
+        );
+        // Link from the player is a local file
+        custom_buttons.insert("broken".to_string(), button("Broken", "{url}"));
+        let profile = PlayerProfile {
+            button: Some(vec![
+                "broken".to_string(),
+                "discogs".to_string(),
+                "discogs".to_string(),
+                "yt".to_string(),
+            ]),
+            ..Default::default()
+        };
+        let settings =
+            PresenceSettings::from_profile(&profile, &custom_buttons, "", "", "").unwrap();
+        insta::assert_json_snapshot!(render(&snapshot(&settings)).buttons);
+    }
+
+    #[test]
+    fn invalid_custom_buttons() {
+        let parse = |label: &str, url: &str| {
+            CustomButton::parse(&CustomButtonConfig {
+                label: label.to_string(),
+                url: url.to_string(),
+            })
+            .map_err(|err| err.to_string())
+        };
+
+        assert!(parse(
+            "{album} on Discogs",
+            "https://www.discogs.com/search?q={album}"
+        )
+        .is_ok());
+        assert!(parse("Open", " {url|\"https://example.com\"}").is_ok());
+        assert!(parse("Open", "{title}")
+            .unwrap_err()
+            .contains("url: \"{title}\" must start with"));
+        assert!(parse("Open", "example.com/{artist}").is_err());
+        assert!(parse("Open", "{artist}https://example.com").is_err());
+        // Only the static text counts, placeholders can be empty
+        assert!(parse("{title} [on a very long label text]", "https://example.com").is_ok());
+        assert!(parse(
+            "Search {title} on a site with a long name",
+            "https://example.com"
+        )
+        .unwrap_err()
+        .starts_with("Config error: label:"));
+    }
+}
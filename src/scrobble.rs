@@ -0,0 +1,516 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use crate::error::Error;
+use crate::presence::Track;
+
+// Tracks shorter than this are never scrobbled
+const MIN_TRACK_DURATION: u64 = 30;
+// Track is scrobbled after playing half of it or this many seconds, whichever comes first
+const MAX_PLAY_TIME: u64 = 4 * 60;
+// Jumping back below this position after a scrobble counts as playing the track again
+const REPLAY_POSITION: u64 = 10;
+
+// Single play of a track, as submitted to scrobbling services
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Listen {
+    pub artist: String,
+    pub title: String,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    /// Track length in seconds
+    pub duration: Option<u64>,
+    /// Unix time when the track started playing
+    pub timestamp: u64,
+    #[serde(default)]
+    pub recording_mbid: Option<String>,
+    #[serde(default)]
+    pub release_mbid: Option<String>,
+    #[serde(default)]
+    pub artist_mbids: Vec<String>,
+}
+
+impl Listen {
+    fn from_track(track: &Track, timestamp: u64) -> Self {
+        let album = if track.album == "Unknown Album" {
+            None
+        } else {
+            Some(track.album.clone())
+        };
+        Listen {
+            artist: track.artist.clone(),
+            title: track.title.clone(),
+            album_artist: album.as_ref().map(|_| track.album_artist.clone()),
+            album,
+            duration: if track.duration > 0 {
+                Some(track.duration)
+            } else {
+                None
+            },
+            timestamp,
+            recording_mbid: track.recording_mbid.clone(),
+            release_mbid: track.release_mbid.clone(),
+            artist_mbids: track.artist_mbids.clone(),
+        }
+    }
+
+    fn is_same_track(&self, track: &Track) -> bool {
+        self.artist == track.artist
+            && self.title == track.title
+            && self.album.as_deref().unwrap_or("Unknown Album") == track.album
+    }
+
+    // Seconds of playback needed before the track can be scrobbled, None if it never can
+    fn required_play_time(&self) -> Option<u64> {
+        match self.duration {
+            Some(duration) if duration <= MIN_TRACK_DURATION => None,
+            Some(duration) => Some((duration / 2).min(MAX_PLAY_TIME)),
+            None => Some(MAX_PLAY_TIME),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenEvent {
+    /// Track started playing
+    NowPlaying(Listen),
+    /// Track was played long enough to be scrobbled
+    Scrobble(Listen),
+}
+
+#[derive(Debug)]
+struct CurrentPlay {
+    listen: Listen,
+    /// Seconds actually played, without skipped parts
+    played: u64,
+    is_playing: bool,
+    last_position: Option<u64>,
+    last_update: u64,
+    now_playing_sent: bool,
+    scrobbled: bool,
+}
+
+// Follows the player state on every refresh and decides when to send "now playing" and scrobbles
+#[derive(Debug, Default)]
+pub struct ListenTracker {
+    current: Option<CurrentPlay>,
+}
+
+impl ListenTracker {
+    pub fn new() -> Self {
+        ListenTracker { current: None }
+    }
+
+    pub fn update(
+        &mut self,
+        track: &Track,
+        is_playing: bool,
+        position: Option<u64>,
+        now: u64,
+    ) -> Vec<ListenEvent> {
+        let mut events = Vec::new();
+
+        // Nothing to submit without artist and title
+        if track.artist == "Unknown Artist" || track.title == "Unknown Title" {
+            self.current = None;
+            return events;
+        }
+
+        let is_new_play = match &self.current {
+            None => true,
+            Some(current) => {
+                !current.listen.is_same_track(track)
+                    || (current.scrobbled
+                        && position.unwrap_or(u64::MAX) < REPLAY_POSITION
+                        && position < current.last_position)
+            }
+        };
+
+        if is_new_play {
+            self.current = Some(CurrentPlay {
+                listen: Listen::from_track(track, now.saturating_sub(position.unwrap_or(0))),
+                played: 0,
+                is_playing,
+                last_position: position,
+                last_update: now,
+                now_playing_sent: false,
+                scrobbled: false,
+            });
+        }
+
+        let current = match &mut self.current {
+            Some(current) => current,
+            None => return events,
+        };
+
+        if !is_new_play {
+            // Count only the time the player was playing since the last update, seeking forward
+            // does not count as listening
+            if current.is_playing {
+                let elapsed = now.saturating_sub(current.last_update);
+                current.played += match (position, current.last_position) {
+                    (Some(position), Some(last_position)) if position >= last_position => {
+                        (position - last_position).min(elapsed + 1)
+                    }
+                    (Some(_), Some(_)) => 0,
+                    _ => elapsed,
+                };
+            }
+            current.is_playing = is_playing;
+            current.last_position = position;
+            current.last_update = now;
+        }
+
+        if is_playing && !current.now_playing_sent {
+            current.now_playing_sent = true;
+            events.push(ListenEvent::NowPlaying(current.listen.clone()));
+        }
+
+        if !current.scrobbled {
+            if let Some(required) = current.listen.required_play_time() {
+                if current.played >= required {
+                    current.scrobbled = true;
+                    events.push(ListenEvent::Scrobble(current.listen.clone()));
+                }
+            }
+        }
+
+        events
+    }
+}
+
+// Service that accepts "now playing" updates and scrobbles
+pub trait ScrobbleService: Send {
+    fn name(&self) -> &str;
+
+    fn max_batch_size(&self) -> usize;
+
+    fn now_playing(&mut self, listen: &Listen) -> Result<(), Error>;
+
+    /// Submit listens in one request. Error::Api means the listens were rejected and should be
+    /// dropped, any other error means they should be retried later.
+    fn submit(&mut self, listens: &[Listen]) -> Result<(), Error>;
+}
+
+// Sends listens to a service, keeping the ones that failed in a file to retry later
+pub struct Scrobbler {
+    service: Box<dyn ScrobbleService>,
+    queue_path: Option<PathBuf>,
+    queue: Vec<Listen>,
+}
+
+impl Scrobbler {
+    pub fn new(service: Box<dyn ScrobbleService>, queue_path: Option<PathBuf>) -> Self {
+        let queue: Vec<Listen> = match &queue_path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(json) => serde_json::from_str(&json).unwrap_or_else(|err| {
+                    println!(
+                        "[{}] Could not parse scrobble queue {}: {}",
+                        service.name(),
+                        path.display(),
+                        err
+                    );
+                    Vec::new()
+                }),
+                Err(_) => Vec::new(),
+            },
+            None => Vec::new(),
+        };
+
+        if !queue.is_empty() {
+            println!(
+                "[{}] Loaded {} queued scrobble(s).",
+                service.name(),
+                queue.len()
+            );
+        }
+
+        Scrobbler {
+            service,
+            queue_path,
+            queue,
+        }
+    }
+
+    pub fn handle(&mut self, event: &ListenEvent) {
+        match event {
+            ListenEvent::NowPlaying(listen) => match self.service.now_playing(listen) {
+                Ok(_) => {
+                    println!(
+                        "[{}] Now playing: {} - {}",
+                        self.service.name(),
+                        listen.artist,
+                        listen.title
+                    );
+                    // Service is reachable again, good moment to send queued scrobbles
+                    if !self.queue.is_empty() {
+                        self.flush();
+                    }
+                }
+                Err(err) => println!(
+                    "[{}] Could not update now playing: {}",
+                    self.service.name(),
+                    err
+                ),
+            },
+            ListenEvent::Scrobble(listen) => {
+                self.queue.push(listen.clone());
+                self.flush();
+            }
+        }
+    }
+
+    // Submit queued listens in batches, stop at the first batch that should be retried
+    pub fn flush(&mut self) {
+        while !self.queue.is_empty() {
+            let batch_size = self.queue.len().min(self.service.max_batch_size());
+            match self.service.submit(&self.queue[..batch_size]) {
+                Ok(_) => {
+                    for listen in &self.queue[..batch_size] {
+                        println!(
+                            "[{}] Scrobbled: {} - {}",
+                            self.service.name(),
+                            listen.artist,
+                            listen.title
+                        );
+                    }
+                    self.queue.drain(..batch_size);
+                }
+                Err(Error::Api(err)) => {
+                    println!(
+                        "[{}] Dropping {} rejected scrobble(s): {}",
+                        self.service.name(),
+                        batch_size,
+                        err
+                    );
+                    self.queue.drain(..batch_size);
+                }
+                Err(err) => {
+                    println!(
+                        "[{}] Could not scrobble, {} scrobble(s) queued for later: {}",
+                        self.service.name(),
+                        self.queue.len(),
+                        err
+                    );
+                    break;
+                }
+            }
+        }
+        self.save_queue();
+    }
+
+    fn save_queue(&self) {
+        let path = match &self.queue_path {
+            Some(path) => path,
+            None => return,
+        };
+
+        if self.queue.is_empty() {
+            if path.exists() {
+                let _ = fs::remove_file(path);
+            }
+            return;
+        }
+
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let result = serde_json::to_string(&self.queue)
+            .map_err(|err| err.to_string())
+            .and_then(|json| fs::write(path, json).map_err(|err| err.to_string()));
+        if let Err(err) = result {
+            println!(
+                "[{}] Could not save scrobble queue: {}",
+                self.service.name(),
+                err
+            );
+        }
+    }
+}
+
+// Submits listens on a separate thread, so a slow or unreachable service doesn't delay the
+// activity. Listens queued in the last run are sent first.
+pub struct ScrobbleWorker {
+    events: Sender<ListenEvent>,
+}
+
+impl ScrobbleWorker {
+    pub fn spawn(mut scrobblers: Vec<Scrobbler>) -> Self {
+        let (events, receiver) = mpsc::channel::<ListenEvent>();
+
+        thread::spawn(move || {
+            for scrobbler in &mut scrobblers {
+                scrobbler.flush();
+            }
+
+            while let Ok(event) = receiver.recv() {
+                let mut pending = vec![event];
+                pending.extend(receiver.try_iter());
+                for (index, event) in pending.iter().enumerate() {
+                    // Tracks skipped while a service was slow aren't playing anymore
+                    let is_outdated = matches!(event, ListenEvent::NowPlaying(_))
+                        && pending[index + 1..]
+                            .iter()
+                            .any(|later| matches!(later, ListenEvent::NowPlaying(_)));
+                    if is_outdated {
+                        continue;
+                    }
+                    for scrobbler in &mut scrobblers {
+                        scrobbler.handle(event);
+                    }
+                }
+            }
+        });
+
+        ScrobbleWorker { events }
+    }
+
+    pub fn handle(&self, event: ListenEvent) {
+        if self.events.send(event).is_err() {
+            println!("[scrobble] Scrobble worker stopped, listens are not submitted.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn track(duration: u64) -> Track {
+        Track {
+            title: "Paranoid Android".to_string(),
+            artist: "Radiohead".to_string(),
+            album: "OK Computer".to_string(),
+            album_artist: "Radiohead".to_string(),
+            art_url: None,
+            url: None,
+            duration,
+            ..Default::default()
+        }
+    }
+
+    // Play the track from the start, refreshing every 10 seconds
+    fn play(tracker: &mut ListenTracker, track: &Track, seconds: u64) -> Vec<ListenEvent> {
+        let mut events = Vec::new();
+        for position in (0..=seconds).step_by(10) {
+            events.extend(tracker.update(track, true, Some(position), NOW + position));
+        }
+        events
+    }
+
+    fn scrobbles(events: &[ListenEvent]) -> usize {
+        events
+            .iter()
+            .filter(|event| matches!(event, ListenEvent::Scrobble(_)))
+            .count()
+    }
+
+    #[test]
+    fn now_playing_sent_once() {
+        let mut tracker = ListenTracker::new();
+        let events = play(&mut tracker, &track(387), 30);
+        let now_playing = events
+            .iter()
+            .filter(|event| matches!(event, ListenEvent::NowPlaying(_)))
+            .count();
+        assert_eq!(now_playing, 1);
+    }
+
+    #[test]
+    fn scrobbled_after_half() {
+        let mut tracker = ListenTracker::new();
+        assert_eq!(scrobbles(&play(&mut tracker, &track(200), 90)), 0);
+
+        let mut tracker = ListenTracker::new();
+        let events = play(&mut tracker, &track(200), 100);
+        assert_eq!(scrobbles(&events), 1);
+        assert!(events.contains(&ListenEvent::Scrobble(Listen::from_track(&track(200), NOW))));
+    }
+
+    #[test]
+    fn scrobbled_after_four_minutes() {
+        let mut tracker = ListenTracker::new();
+        assert_eq!(scrobbles(&play(&mut tracker, &track(900), 230)), 0);
+
+        let mut tracker = ListenTracker::new();
+        assert_eq!(scrobbles(&play(&mut tracker, &track(900), 240)), 1);
+    }
+
+    #[test]
+    fn short_track_not_scrobbled() {
+        let mut tracker = ListenTracker::new();
+        assert_eq!(scrobbles(&play(&mut tracker, &track(30), 30)), 0);
+    }
+
+    #[test]
+    fn seek_forward_not_counted() {
+        let mut tracker = ListenTracker::new();
+        let track = track(200);
+        let mut events = tracker.update(&track, true, Some(0), NOW);
+        events.extend(tracker.update(&track, true, Some(150), NOW + 10));
+        events.extend(tracker.update(&track, true, Some(160), NOW + 20));
+        assert_eq!(scrobbles(&events), 0);
+    }
+
+    #[test]
+    fn paused_time_not_counted() {
+        let mut tracker = ListenTracker::new();
+        let track = track(200);
+        let mut events = tracker.update(&track, false, Some(20), NOW);
+        events.extend(tracker.update(&track, false, Some(20), NOW + 300));
+        assert_eq!(scrobbles(&events), 0);
+    }
+
+    #[test]
+    fn repeat_scrobbled_again() {
+        let mut tracker = ListenTracker::new();
+        let track = track(100);
+        let mut events = play(&mut tracker, &track, 100);
+        events.extend(tracker.update(&track, true, Some(0), NOW + 110));
+        for position in (10..=60).step_by(10) {
+            events.extend(tracker.update(&track, true, Some(position), NOW + 110 + position));
+        }
+        assert_eq!(scrobbles(&events), 2);
+    }
+
+    // Reports the calls to the test instead of sending them
+    struct FakeService(mpsc::Sender<String>);
+
+    impl ScrobbleService for FakeService {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        fn max_batch_size(&self) -> usize {
+            50
+        }
+
+        fn now_playing(&mut self, listen: &Listen) -> Result<(), Error> {
+            let _ = self.0.send(format!("now playing {}", listen.title));
+            Ok(())
+        }
+
+        fn submit(&mut self, listens: &[Listen]) -> Result<(), Error> {
+            let _ = self.0.send(format!("submit {}", listens.len()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn worker_submits_in_background() {
+        let (sender, calls) = mpsc::channel();
+        let scrobbler = Scrobbler::new(Box::new(FakeService(sender)), None);
+        let worker = ScrobbleWorker::spawn(vec![scrobbler]);
+
+        let listen = Listen::from_track(&track(200), NOW);
+        worker.handle(ListenEvent::NowPlaying(listen.clone()));
+        worker.handle(ListenEvent::Scrobble(listen));
+        assert_eq!(calls.recv().unwrap(), "now playing Paranoid Android");
+        assert_eq!(calls.recv().unwrap(), "submit 1");
+    }
+}
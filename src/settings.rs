@@ -3,12 +3,21 @@ use clap_serde_derive::{
     serde::Serialize,
     ClapSerde,
 };
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::process;
 
+use crate::cover::{normalize, size, upload};
 use crate::debug_log;
+use crate::discord;
+use crate::error::Error;
+use crate::presence;
+use crate::utils;
 
 #[derive(Parser, ClapSerde, Serialize, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -17,10 +26,14 @@ pub struct Cli {
     #[arg(short, long, value_name = "seconds", value_parser = clap::value_parser!(u64).range(5..))]
     pub interval: Option<u64>,
 
-    /// Select visible buttons
-    #[arg(short, long, value_name = "name", value_parser = ["yt", "lastfm", "listenbrainz", "mprisUrl", "shamelessAd"])]
+    /// Select visible buttons [possible values: yt, lastfm, listenbrainz, mprisUrl, shamelessAd or name of a custom button]
+    #[arg(short, long, value_name = "name", value_parser = clap::value_parser!(String))]
     pub button: Vec<String>,
 
+    /// Buttons defined by the user, only available in the config file
+    #[arg(skip)]
+    pub custom_buttons: HashMap<String, CustomButtonConfig>,
+
     /// Your Last.fm nickname
     #[arg(long, value_name = "nickname", value_parser = clap::value_parser!(String))]
     pub lastfm_name: Option<String>,
@@ -29,6 +42,34 @@ pub struct Cli {
     #[arg(long, value_name = "nickname", value_parser = clap::value_parser!(String))]
     pub listenbrainz_name: Option<String>,
 
+    /// Your own Last.fm API key, only available in the config file
+    #[arg(skip)]
+    pub lastfm_api_key: Option<Secret>,
+
+    /// Read the Last.fm API key from this file
+    #[arg(long, value_name = "path", value_parser = clap::value_parser!(String))]
+    pub lastfm_api_key_file: Option<String>,
+
+    /// Your own Last.fm shared secret, needed for scrobbling, only available in the config file
+    #[arg(skip)]
+    pub lastfm_api_secret: Option<Secret>,
+
+    /// Scrobble played tracks to Last.fm (log in first with "lastfm login")
+    #[arg(long)]
+    pub lastfm_scrobble: bool,
+
+    /// Your ListenBrainz user token, only available in the config file
+    #[arg(skip)]
+    pub listenbrainz_token: Option<Secret>,
+
+    /// Submit played tracks to ListenBrainz (requires user token)
+    #[arg(long)]
+    pub listenbrainz_scrobble: bool,
+
+    /// ListenBrainz API URL, change for self-hosted compatible servers (default https://api.listenbrainz.org)
+    #[arg(long, value_name = "url", value_parser = clap::value_parser!(String))]
+    pub listenbrainz_url: Option<String>,
+
     /// Select the icon displayed next to the album cover (default playPause)
     #[arg(short, long, value_name = "name", value_parser = ["playPause", "player", "lastfmAvatar", "none"])]
     pub small_image: Option<String>,
@@ -41,10 +82,58 @@ pub struct Cli {
     #[arg(long, value_name = "player name", value_parser = clap::value_parser!(String))]
     pub force_player_name: Option<String>,
 
+    /// Template of the first line (default "{title}")
+    #[arg(long, value_name = "template", value_parser = clap::value_parser!(String))]
+    pub details_template: Option<String>,
+
+    /// Template of the second line (default "by: {artist}")
+    #[arg(long, value_name = "template", value_parser = clap::value_parser!(String))]
+    pub state_template: Option<String>,
+
+    /// Template of the text shown while hovering over the album cover (default "album: {album}")
+    #[arg(long, value_name = "template", value_parser = clap::value_parser!(String))]
+    pub large_text_template: Option<String>,
+
+    /// Template of the text shown while hovering over the small icon
+    #[arg(long, value_name = "template", value_parser = clap::value_parser!(String))]
+    pub small_text_template: Option<String>,
+
+    /// Where to look for album covers, in order. Use multiple times to add several providers (default lastfm, mpris, musicbrainz, upload)
+    #[arg(long = "cover-provider", value_name = "name", value_parser = ["lastfm", "mpris", "musicbrainz", "upload"])]
+    pub cover_providers: Vec<String>,
+
+    /// How album names are normalized for the cache and cover lookups. Use multiple times to add several rules (default editions, case, unicode)
+    #[arg(long = "cover-normalization", value_name = "rule", value_parser = ["editions", "case", "unicode", "none"])]
+    pub cover_normalization: Vec<String>,
+
+    /// Preferred resolution of album covers and the Last.fm avatar, smaller images are used if it's not available (default medium)
+    #[arg(long, value_name = "size", value_parser = ["small", "medium", "large", "original"])]
+    pub cover_size: Option<String>,
+
+    /// Upload local album covers (file:// links and art embedded in the track file) to this image host
+    #[arg(long, value_name = "service", value_parser = ["0x0", "catbox", "put"])]
+    pub cover_upload: Option<String>,
+
+    /// Upload endpoint, required for "put" (default https://0x0.st or https://catbox.moe/user/api.php)
+    #[arg(long, value_name = "url", value_parser = clap::value_parser!(String))]
+    pub cover_upload_url: Option<String>,
+
+    /// Base of the public cover links for "put", if different from the upload endpoint
+    #[arg(long, value_name = "url", value_parser = clap::value_parser!(String))]
+    pub cover_upload_public_url: Option<String>,
+
+    /// Authorization header sent with uploads, only available in the config file
+    #[arg(skip)]
+    pub cover_upload_authorization: Option<Secret>,
+
     /// Prevent MPRIS artUrl to be used as album cover if cover is not available on Last.fm
     #[arg(long)]
     pub disable_mpris_art_url: bool,
 
+    /// File with fixed covers and corrected track info (default ~/.config/mpris-discord-rpc/overrides.yaml)
+    #[arg(long, value_name = "path", value_parser = clap::value_parser!(String))]
+    pub overrides_file: Option<String>,
+
     /// Displays all available music player names and exits. Use to get your player name for -a argument
     #[arg(short, long)]
     #[serde(skip_deserializing)]
@@ -62,10 +151,66 @@ pub struct Cli {
     #[arg(long)]
     pub hide_album_name: bool,
 
+    /// Settings of single players, only available in the config file
+    #[arg(skip)]
+    pub players: HashMap<String, PlayerProfile>,
+
+    /// Discord application id used for every player, its name is shown in the activity header (default built-in music and video applications)
+    #[arg(long, value_name = "id", value_parser = clap::value_parser!(String))]
+    pub app_id: Option<String>,
+
+    /// Discord application ids of single players, only available in the config file
+    #[arg(skip)]
+    pub app_ids: HashMap<String, String>,
+
+    /// Discord IPC socket to connect to, e.g. $XDG_RUNTIME_DIR/discord-ipc-0 (default first socket found)
+    #[arg(long, value_name = "path", value_parser = clap::value_parser!(String))]
+    pub discord_socket: Option<String>,
+
+    /// Show the activity in every running Discord client, e.g. Stable, Canary and Vesktop at the same time
+    #[arg(long)]
+    pub discord_broadcast: bool,
+
+    /// How to talk to Discord, "websocket" for RPC servers like arRPC used with the Discord web client (default ipc)
+    #[arg(long, value_name = "transport", value_parser = ["ipc", "websocket"])]
+    pub discord_transport: Option<String>,
+
+    /// Host of the WebSocket RPC server (default 127.0.0.1)
+    #[arg(long, value_name = "host", value_parser = clap::value_parser!(String))]
+    pub discord_websocket_host: Option<String>,
+
+    /// Port of the WebSocket RPC server (default first open port from 6463 to 6472)
+    #[arg(long, value_name = "port", value_parser = clap::value_parser!(u16))]
+    pub discord_websocket_port: Option<u16>,
+
     /// Disable cache (not recommended)
     #[arg(short, long)]
     pub disable_cache: bool,
 
+    /// Maximum number of albums kept in the cache, the least recently used are removed first (default 10000)
+    #[arg(long, value_name = "number", value_parser = clap::value_parser!(usize))]
+    pub cache_max_entries: Option<usize>,
+
+    /// Hours after which albums without a cover are looked up again (default 24)
+    #[arg(long, value_name = "hours", value_parser = clap::value_parser!(u64))]
+    pub cache_miss_ttl: Option<u64>,
+
+    /// Hours after which found covers are looked up again to get changed artwork (default never)
+    #[arg(long, value_name = "hours", value_parser = clap::value_parser!(u64))]
+    pub cache_hit_ttl: Option<u64>,
+
+    /// Hours after which the link of a cached cover is checked again when it's used, 0 disables the checks (default 168)
+    #[arg(long, value_name = "hours", value_parser = clap::value_parser!(u64))]
+    pub cache_check_interval: Option<u64>,
+
+    /// Seconds after which album cover and avatar requests are abandoned (default 10)
+    #[arg(long, value_name = "seconds", value_parser = clap::value_parser!(u64))]
+    pub http_timeout: Option<u64>,
+
+    /// Proxy for album cover and avatar requests, e.g. http://127.0.0.1:8080 (default HTTP_PROXY/HTTPS_PROXY)
+    #[arg(long, value_name = "url", value_parser = clap::value_parser!(String))]
+    pub http_proxy: Option<String>,
+
     /// Show debug log
     #[arg(long)]
     #[serde(skip_deserializing)]
@@ -96,11 +241,255 @@ pub enum Commands {
     Disable {},
     /// Use to restart the service and reload the changed configuration file.
     Restart {},
+    /// Manage Last.fm account used for scrobbling
+    Lastfm {
+        #[command(subcommand)]
+        command: LastfmCommands,
+    },
+    /// Inspect and manage the album cover cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+}
+
+#[derive(Subcommand, Debug, Serialize)]
+pub enum LastfmCommands {
+    /// Authorize scrobbling to your Last.fm account
+    Login {},
+    /// Remove the saved Last.fm session
+    Logout {},
+}
+
+// Albums are named "<album artist> - <album>", as shown by "cache list"
+#[derive(Subcommand, Debug, Serialize)]
+pub enum CacheCommands {
+    /// Show all cached albums
+    List {},
+    /// Show the cached cover of an album
+    Get {
+        /// "<album artist> - <album>"
+        album: String,
+    },
+    /// Set the cover of an album, it's used instead of the cover providers and never expires
+    Set {
+        /// "<album artist> - <album>"
+        album: String,
+        /// Link to the image
+        url: String,
+    },
+    /// Remove an album from the cache, so its cover is looked up again
+    Delete {
+        /// "<album artist> - <album>"
+        album: String,
+    },
+    /// Remove albums without a cover and/or old entries, manual covers are kept
+    #[command(group(clap::ArgGroup::new("filter").required(true).multiple(true)))]
+    Purge {
+        /// Remove albums without a cover
+        #[arg(long, group = "filter")]
+        missing: bool,
+        /// Remove entries fetched more than this many days ago
+        #[arg(long, value_name = "days", group = "filter")]
+        older_than: Option<u64>,
+    },
+    /// Save the cache to a JSON file
+    Export { file: String },
+    /// Add the entries from a JSON file created by "cache export", use "-" for the standard input
+    Import { file: String },
+    /// Show the number of cached albums and the cache settings
+    Stats {},
+    /// Check the links of all cached covers and remove the ones that no longer work
+    Verify {
+        /// Only show the broken links
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+const SMALL_IMAGES: &[&str] = &["playPause", "player", "lastfmAvatar", "none"];
+const BUTTONS: &[&str] = &["yt", "lastfm", "listenbrainz", "mprisUrl", "shamelessAd"];
+const ACTIVITY_TYPES: &[&str] = &["listening", "watching"];
+
+// API keys, tokens and passwords from the config file, hidden in the debug log
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Deref for Secret {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+// Button with label and url templates from the "custom_buttons" map in the config file
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CustomButtonConfig {
+    pub label: String,
+    pub url: String,
+}
+
+// Presentation settings of a single player from the "players" map in the config file.
+// After loading, every field not set by the user is filled with the global value.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PlayerProfile {
+    pub small_image: Option<String>,
+    pub button: Option<Vec<String>>,
+    pub force_player_id: Option<String>,
+    pub force_player_name: Option<String>,
+    pub hide_album_name: Option<bool>,
+    pub disable_mpris_art_url: Option<bool>,
+    pub details_template: Option<String>,
+    pub state_template: Option<String>,
+    pub large_text_template: Option<String>,
+    pub small_text_template: Option<String>,
+    /// "listening" or "watching", by default taken from video_players
+    pub activity_type: Option<String>,
+    /// Discord application id, its name is shown in the activity header
+    pub app_id: Option<String>,
+}
+
+impl PlayerProfile {
+    // Values that can't be checked by clap like the global arguments
+    fn validate(&self) -> Result<(), Error> {
+        if let Some(small_image) = &self.small_image {
+            if !SMALL_IMAGES.contains(&small_image.as_str()) {
+                return Err(Error::Config(format!(
+                    "invalid small_image \"{}\" [possible values: {}]",
+                    small_image,
+                    SMALL_IMAGES.join(", ")
+                )));
+            }
+        }
+
+        if let Some(activity_type) = &self.activity_type {
+            if !ACTIVITY_TYPES.contains(&activity_type.as_str()) {
+                return Err(Error::Config(format!(
+                    "invalid activity_type \"{}\" [possible values: {}]",
+                    activity_type,
+                    ACTIVITY_TYPES.join(", ")
+                )));
+            }
+        }
+
+        if let Some(app_id) = &self.app_id {
+            if !valid_app_id(app_id) {
+                return Err(Error::Config(format!(
+                    "invalid app_id \"{}\", it should be a number",
+                    app_id
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn inherit(&mut self, global: &PlayerProfile) {
+        fn inherit<T: Clone>(value: &mut Option<T>, global: &Option<T>) {
+            if value.is_none() {
+                *value = global.clone();
+            }
+        }
+
+        inherit(&mut self.small_image, &global.small_image);
+        inherit(&mut self.button, &global.button);
+        inherit(&mut self.force_player_id, &global.force_player_id);
+        inherit(&mut self.force_player_name, &global.force_player_name);
+        inherit(&mut self.hide_album_name, &global.hide_album_name);
+        inherit(
+            &mut self.disable_mpris_art_url,
+            &global.disable_mpris_art_url,
+        );
+        inherit(&mut self.details_template, &global.details_template);
+        inherit(&mut self.state_template, &global.state_template);
+        inherit(&mut self.large_text_template, &global.large_text_template);
+        inherit(&mut self.small_text_template, &global.small_text_template);
+        inherit(&mut self.activity_type, &global.activity_type);
+        inherit(&mut self.app_id, &global.app_id);
+    }
+}
+
+impl Cli {
+    // Presentation settings used for players without their own profile
+    pub fn global_profile(&self) -> PlayerProfile {
+        PlayerProfile {
+            small_image: Some(
+                self.small_image
+                    .clone()
+                    .unwrap_or(String::from("playPause")),
+            ),
+            button: Some(self.button.clone()),
+            force_player_id: self.force_player_id.clone(),
+            force_player_name: self.force_player_name.clone(),
+            hide_album_name: Some(self.hide_album_name),
+            disable_mpris_art_url: Some(self.disable_mpris_art_url),
+            details_template: self.details_template.clone(),
+            state_template: self.state_template.clone(),
+            large_text_template: self.large_text_template.clone(),
+            small_text_template: self.small_text_template.clone(),
+            activity_type: None,
+            app_id: None,
+        }
+    }
+
+    pub fn player_profile(
+        &self,
+        identity: &str,
+        bus_name: &str,
+    ) -> Option<(&String, &PlayerProfile)> {
+        find_player(&self.players, identity, bus_name)
+    }
+
+    // Discord application of the player from its profile, the "app_ids" map or the global
+    // "app_id", None uses the default application for music or video
+    pub fn player_app_id(&self, identity: &str, bus_name: &str) -> Option<&str> {
+        self.player_profile(identity, bus_name)
+            .and_then(|(_, profile)| profile.app_id.as_deref())
+            .or_else(|| {
+                find_player(&self.app_ids, identity, bus_name).map(|(_, app_id)| app_id.as_str())
+            })
+            .or(self.app_id.as_deref())
+    }
+}
+
+// Players are matched by MPRIS identity ("VLC media player") or bus name ("vlc" or
+// "org.mpris.MediaPlayer2.vlc")
+fn find_player<'a, T>(
+    players: &'a HashMap<String, T>,
+    identity: &str,
+    bus_name: &str,
+) -> Option<(&'a String, &'a T)> {
+    let short_bus_name = bus_name
+        .strip_prefix("org.mpris.MediaPlayer2.")
+        .unwrap_or(bus_name);
+    players
+        .get_key_value(identity)
+        .or_else(|| players.get_key_value(bus_name))
+        .or_else(|| players.get_key_value(short_bus_name))
+}
+
+// Discord application ids are snowflakes, only digits
+fn valid_app_id(app_id: &str) -> bool {
+    !app_id.is_empty() && app_id.chars().all(|char| char.is_ascii_digit())
+}
+
+pub fn config_dir(home_dir: &Path) -> PathBuf {
+    home_dir.join(".config/mpris-discord-rpc")
 }
 
 // Use to get config path, create new config or reset existing
 fn create_config_file(home_dir: &PathBuf, force: bool) -> (bool, PathBuf) {
-    let config_dir = home_dir.join(".config/mpris-discord-rpc");
+    let config_dir = config_dir(home_dir);
     let config_file = config_dir.join("config.yaml");
 
     if config_file.exists() && !force {
@@ -115,17 +504,45 @@ fn create_config_file(home_dir: &PathBuf, force: bool) -> (bool, PathBuf) {
 # https://github.com/patryk-ku/mpris-discord-rpc/blob/main/config.yaml
 
 # Activity refresh rate in seconds (min 5)
+# Player changes are detected instantly using MPRIS signals, this is only used as a periodic resync.
 interval: 10
 
-# Select visible activity buttons (max 2) [possible values: yt, lastfm, listenbrainz, mprisUrl, shamelessAd]
+# Select visible activity buttons (max 2) [possible values: yt, lastfm, listenbrainz, mprisUrl, shamelessAd or name of a custom button]
 # button:
 #   - yt
 #   - lastfm
 
+# Define your own buttons and add them by name to "button" (globally or in a player profile).
+# "label" and "url" are templates like above, in "url" all placeholders except {url} (link from the player) are URL-encoded.
+# Discord limits the label to 32 characters and the url to 512 characters, the url has to start with "https://", "http://" or {url}.
+# custom_buttons:
+#   discogs:
+#     label: "{album} on Discogs"
+#     url: "https://www.discogs.com/search?q={artist} {album}"
+
 # Uncomment and enter your nicknames for activity buttons
 # lastfm_name: "nickname"
 # listenbrainz_name: "nickname"
 
+# Your own Last.fm API key, used to fetch album covers. You can get one here: https://www.last.fm/api/account/create
+# If not set, the key is taken from the LASTFM_API_KEY environment variable, the "lastfm_api_key" file in this directory
+# (or the file set with "lastfm_api_key_file"), the keyring or finally the key built into the release binary.
+# lastfm_api_key: "key"
+# lastfm_api_key_file: "/path/to/lastfm_api_key"
+
+# Scrobble played tracks to Last.fm. Requires your own API key and shared secret, log in first using:
+# mpris-discord-rpc lastfm login
+# The secret can also be set with the LASTFM_API_SECRET environment variable, the "lastfm_api_secret" file in this directory or the keyring.
+# lastfm_api_secret: "secret"
+lastfm_scrobble: false
+
+# Submit played tracks to ListenBrainz. Get your user token here: https://listenbrainz.org/settings/
+# The token can also be set with the LISTENBRAINZ_TOKEN environment variable or the keyring.
+# For self-hosted ListenBrainz compatible servers (Maloja, Koito) change the API URL, e.g. "https://maloja.example.com/apis/listenbrainz"
+# listenbrainz_token: "token"
+# listenbrainz_url: "https://api.listenbrainz.org"
+listenbrainz_scrobble: false
+
 # Select the icon displayed next to the album cover (default playPause) [possible values: playPause, player, lastfmAvatar, none]
 small_image: playPause
 
@@ -134,10 +551,61 @@ small_image: playPause
 # force_player_id: "custom_player_id"
 # force_player_name: "Custom Player Name"
 
+# Templates of the activity texts. Placeholders in braces are replaced with the track info:
+# {title}, {artist}, {artists}, {album}, {album_artist}, {track_number}, {disc_number}, {genre}, {year},
+# {player}, {url}, {status}, {position}, {duration}
+# Use {a|b} to show "b" if "a" is not available, or {a|"text"} to show a fixed text instead.
+# Text in square brackets is only shown if all placeholders inside have a value, e.g. "{album}[ ({year})]".
+# Use a backslash to show the special characters literally, with single quotes in YAML: '\[live\] {title}'
+# Comment out a template to use the default text.
+# details_template: "{title}"
+# state_template: "by: {artist}"
+# large_text_template: "album: {album}"
+# small_text_template: "{player}"
+
+# Where to look for album covers, the first cover found is used [possible values: lastfm, mpris, musicbrainz, upload]
+# lastfm - Last.fm album info, mpris - cover link sent by the player (only http links work),
+# musicbrainz - Cover Art Archive, searched by MusicBrainz IDs from the file tags or by album artist and album name,
+# upload - local cover uploaded to the image host below
+# cover_providers:
+#   - lastfm
+#   - mpris
+#   - musicbrainz
+#   - upload
+
+# Different spellings of an album share one cache entry and the cover lookups retry with a cleaned album name
+# [possible values: editions, case, unicode, none]
+# editions - ignore suffixes like "(Deluxe Edition)" or "[2011 Remaster]", case - ignore letter case,
+# unicode - treat compatibility characters (e.g. full-width letters) as the plain ones, none - disable all rules
+# cover_normalization:
+#   - editions
+#   - case
+#   - unicode
+
+# Preferred resolution of album covers and the Last.fm avatar, used by lastfm and musicbrainz [possible values: small, medium, large, original]
+# small - about 200 px, medium - about 300-500 px, large - about 800-1200 px, original - image as uploaded to the service.
+# Smaller images are used if a cover isn't available in this size. Already cached covers keep their size, use "cache purge" to fetch them again.
+# cover_size: "medium"
+
+# Upload local covers (file:// links from the player or art embedded in MP3 and FLAC files) so Discord can show them.
+# Each cover is uploaded only once. Keep in mind that the uploaded images are public.
+# 0x0 - https://0x0.st, catbox - https://catbox.moe, put - HTTP PUT of "<hash>.<ext>" to your own server
+# cover_upload: "0x0"
+# Upload endpoint, required for "put". For "0x0" and "catbox" it changes the instance.
+# cover_upload_url: "https://example.com/covers"
+# Base of the public links for "put" if the files are served from a different address than the upload endpoint
+# cover_upload_public_url: "https://cdn.example.com/covers"
+# Authorization header sent with the uploads, e.g. for "put" or a private 0x0 instance
+# cover_upload_authorization: "Bearer token"
+
 # Prevent MPRIS artUrl to be used as album cover if cover is not available on Last.fm. Mainly for working with thumbnails from YouTube and other video sites.
 # Additionally, it also disables icon and player name replacement on YouTube if it detects a YouTube thumbnail link.
 disable_mpris_art_url: false
 
+# File with fixed covers, corrected track info and player icons for chosen artists, albums or tracks.
+# By default "overrides.yaml" in this directory, changes are applied without a restart. See the README for the format.
+# overrides_file: "/path/to/overrides.yaml"
+
 # Only use the status from the following music players
 # Use -l, --list-players to get player exact name to use with this option
 # The order matters and the first is the most important.
@@ -152,11 +620,68 @@ disable_mpris_art_url: false
 #   - "VLC Media Player"
 #   - "Chrome"
 
+# Override the settings above for single players. Use the player name from -l, --list-players or its D-Bus name.
+# Available settings: small_image, button, force_player_id, force_player_name, hide_album_name, disable_mpris_art_url,
+# details_template, state_template, large_text_template, small_text_template,
+# activity_type (listening or watching) and app_id (Discord application id shown in the activity header).
+# players:
+#   "VLC media player":
+#     activity_type: watching
+#     small_image: player
+#   "Spotify":
+#     button:
+#       - mprisUrl
+#     state_template: "{artist} — {album}"
+
+# Discord application used for every player, its name is shown in the activity header ("Listening to <name>").
+# Create your own at https://discord.com/developers/applications, the id has to be quoted.
+# By default built-in applications for music and video players are used.
+# app_id: "1129859263741837373"
+
+# Discord applications of single players, e.g. one named after the player. Use the player name from -l, --list-players
+# or its D-Bus name. The app_id of a player profile wins over this list.
+# app_ids:
+#   "Spotify": "123456789012345678"
+#   "mpv": "234567890123456789"
+
+# Discord IPC socket to connect to. By default the sockets of the regular, Flatpak, Snap and Vesktop clients
+# are searched in $XDG_RUNTIME_DIR, $TMPDIR and /tmp and the first one found is used.
+# discord_socket: "/run/user/1000/app/com.discordapp.Discord/discord-ipc-0"
+
+# Show the activity in every running Discord client instead of the first one found, e.g. Stable, Canary and Vesktop
+# at the same time. Clients started later are connected too. Ignored if discord_socket is set.
+discord_broadcast: false
+
+# How to talk to Discord [possible values: ipc, websocket]
+# ipc - Unix socket of the desktop client, websocket - RPC server like arRPC, used with Discord in the browser
+# discord_transport: "ipc"
+# Address of the WebSocket RPC server, by default the first open port from 6463 to 6472 on 127.0.0.1 is used
+# discord_websocket_host: "127.0.0.1"
+# discord_websocket_port: 6463
+
 # Hide the album name to decrease activity height
 hide_album_name: false
 
 # Disable cache (not recommended)
 disable_cache: false
+
+# Maximum number of albums kept in the cache, the least recently used are removed first
+cache_max_entries: 10000
+
+# Hours after which albums without a cover are looked up again
+cache_miss_ttl: 24
+
+# Hours after which found covers are looked up again, so changed artwork is eventually refreshed. Never by default.
+# cache_hit_ttl: 720
+
+# Hours after which the link of a cached cover is checked again when it's used, broken links are looked up again. 0 disables the checks.
+cache_check_interval: 168
+
+# Seconds after which album cover and avatar requests are abandoned, the activity is set without waiting for them
+http_timeout: 10
+
+# Proxy for album cover and avatar requests. By default the HTTP_PROXY and HTTPS_PROXY environment variables are used.
+# http_proxy: "http://127.0.0.1:8080"
 "#;
 
     match fs::create_dir_all(&config_dir) {
@@ -179,8 +704,136 @@ disable_cache: false
     return (true, config_file);
 }
 
+fn read_config_file(config_file: &Path) -> Result<Cli, Error> {
+    let yaml_str = fs::read_to_string(config_file)
+        .map_err(|err| Error::Config(format!("Failed to read config file: {}", err)))?;
+    let yaml_args = serde_yaml::from_str::<<Cli as ClapSerde>::Opt>(&yaml_str)?;
+    Ok(Cli::from(yaml_args))
+}
+
 // Used to get settings merged from args and config file
 pub fn load_settings() -> Cli {
+    let settings = merge_settings();
+
+    // Button names can't be checked by clap because of custom buttons
+    let profiles = settings.players.iter().map(|(name, profile)| {
+        (
+            format!("players.{}.button", name),
+            profile.button.clone().unwrap_or_default(),
+        )
+    });
+    for (option, buttons) in
+        std::iter::once((String::from("button"), settings.button.clone())).chain(profiles)
+    {
+        for button in &buttons {
+            if !BUTTONS.contains(&button.as_str()) && !settings.custom_buttons.contains_key(button)
+            {
+                println!(
+                    "[config] {}: invalid button \"{}\" [possible values: {} or name of a custom button]",
+                    option,
+                    button,
+                    BUTTONS.join(", ")
+                );
+                process::exit(utils::EXIT_CONFIG_ERROR);
+            }
+        }
+    }
+
+    // Templates of custom buttons that can never render a valid button, e.g. an url without http
+    for (name, button) in &settings.custom_buttons {
+        if let Err(err) = presence::CustomButton::parse(button) {
+            match err {
+                Error::Config(msg) => println!("[config] custom_buttons.{}.{}", name, msg),
+                err => println!("[config] custom_buttons.{}: {}", name, err),
+            }
+            process::exit(utils::EXIT_CONFIG_ERROR);
+        }
+    }
+
+    // Config file values are not checked by clap
+    for rule in &settings.cover_normalization {
+        if !normalize::RULES.contains(&rule.as_str()) {
+            println!(
+                "[config] cover_normalization: invalid rule \"{}\" [possible values: {}]",
+                rule,
+                normalize::RULES.join(", ")
+            );
+            process::exit(utils::EXIT_CONFIG_ERROR);
+        }
+    }
+
+    if let Some(cover_size) = &settings.cover_size {
+        if !size::SIZES.contains(&cover_size.as_str()) {
+            println!(
+                "[config] cover_size: invalid size \"{}\" [possible values: {}]",
+                cover_size,
+                size::SIZES.join(", ")
+            );
+            process::exit(utils::EXIT_CONFIG_ERROR);
+        }
+    }
+
+    if let Some(service) = &settings.cover_upload {
+        if !upload::SERVICES.contains(&service.as_str()) {
+            println!(
+                "[config] cover_upload: invalid service \"{}\" [possible values: {}]",
+                service,
+                upload::SERVICES.join(", ")
+            );
+            process::exit(utils::EXIT_CONFIG_ERROR);
+        }
+        if service == "put" && settings.cover_upload_url.is_none() {
+            println!("[config] cover_upload: \"put\" requires \"cover_upload_url\"");
+            process::exit(utils::EXIT_CONFIG_ERROR);
+        }
+    }
+
+    let app_ids = settings
+        .app_ids
+        .iter()
+        .map(|(name, app_id)| (format!("app_ids.{}", name), app_id));
+    for (option, app_id) in settings
+        .app_id
+        .iter()
+        .map(|app_id| (String::from("app_id"), app_id))
+        .chain(app_ids)
+    {
+        if !valid_app_id(app_id) {
+            println!(
+                "[config] {}: invalid app_id \"{}\", it should be a number",
+                option, app_id
+            );
+            process::exit(utils::EXIT_CONFIG_ERROR);
+        }
+    }
+
+    if let Some(transport) = &settings.discord_transport {
+        if !discord::TRANSPORTS.contains(&transport.as_str()) {
+            println!(
+                "[config] discord_transport: invalid transport \"{}\" [possible values: {}]",
+                transport,
+                discord::TRANSPORTS.join(", ")
+            );
+            process::exit(utils::EXIT_CONFIG_ERROR);
+        }
+    }
+
+    if settings.http_timeout == Some(0) {
+        println!("[config] http_timeout: must be at least 1 second");
+        process::exit(utils::EXIT_CONFIG_ERROR);
+    }
+
+    if let Some(proxy) = &settings.http_proxy {
+        if let Err(err) = reqwest::Proxy::all(proxy) {
+            println!("[config] http_proxy: invalid proxy \"{}\": {}", proxy, err);
+            process::exit(utils::EXIT_CONFIG_ERROR);
+        }
+    }
+
+    settings
+}
+
+fn merge_settings() -> Cli {
     let (home_exists, home_dir) = match env::var("HOME") {
         Ok(val) => (true, PathBuf::from(val)),
         Err(_) => (false, PathBuf::from("/")),
@@ -192,8 +845,11 @@ pub fn load_settings() -> Cli {
 
     // Reset config file is user used --reset-config and exit
     if args.reset_config {
-        create_config_file(&home_dir, true);
-        process::exit(0);
+        let (config_created, _) = create_config_file(&home_dir, true);
+        if !config_created {
+            process::exit(utils::EXIT_CONFIG_ERROR);
+        }
+        process::exit(utils::EXIT_SUCCESS);
     }
 
     if !home_exists {
@@ -206,17 +862,10 @@ pub fn load_settings() -> Cli {
     }
 
     // Read user config file
-    let mut config = match fs::read_to_string(&config_file) {
-        Ok(yaml_str) => match serde_yaml::from_str::<<Cli as ClapSerde>::Opt>(&yaml_str) {
-            Ok(yaml_args) => Cli::from(yaml_args),
-            Err(error) => {
-                println!("Failed to parse config file: {}", error);
-                config_exists = false;
-                Cli::from_clap()
-            }
-        },
-        Err(_) => {
-            println!("Failed to read config file.");
+    let mut config = match read_config_file(&config_file) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Failed to load config file. {}", err);
             config_exists = false;
             Cli::from_clap()
         }
@@ -245,6 +894,23 @@ pub fn load_settings() -> Cli {
         config.listenbrainz_name = args.listenbrainz_name;
     }
 
+    if args.lastfm_api_key_file != config.lastfm_api_key_file && args.lastfm_api_key_file.is_some()
+    {
+        config.lastfm_api_key_file = args.lastfm_api_key_file;
+    }
+
+    if args.lastfm_scrobble {
+        config.lastfm_scrobble = args.lastfm_scrobble;
+    }
+
+    if args.listenbrainz_scrobble {
+        config.listenbrainz_scrobble = args.listenbrainz_scrobble;
+    }
+
+    if args.listenbrainz_url != config.listenbrainz_url && args.listenbrainz_url.is_some() {
+        config.listenbrainz_url = args.listenbrainz_url;
+    }
+
     if args.small_image != config.small_image && args.small_image.is_some() {
         config.small_image = args.small_image;
     }
@@ -257,10 +923,32 @@ pub fn load_settings() -> Cli {
         config.force_player_name = args.force_player_name;
     }
 
+    if args.details_template != config.details_template && args.details_template.is_some() {
+        config.details_template = args.details_template;
+    }
+
+    if args.state_template != config.state_template && args.state_template.is_some() {
+        config.state_template = args.state_template;
+    }
+
+    if args.large_text_template != config.large_text_template && args.large_text_template.is_some()
+    {
+        config.large_text_template = args.large_text_template;
+    }
+
+    if args.small_text_template != config.small_text_template && args.small_text_template.is_some()
+    {
+        config.small_text_template = args.small_text_template;
+    }
+
     if args.disable_mpris_art_url {
         config.disable_mpris_art_url = args.disable_mpris_art_url;
     }
 
+    if args.overrides_file != config.overrides_file && args.overrides_file.is_some() {
+        config.overrides_file = args.overrides_file;
+    }
+
     if args.hide_album_name {
         config.hide_album_name = args.hide_album_name;
     }
@@ -269,14 +957,96 @@ pub fn load_settings() -> Cli {
         config.disable_cache = args.disable_cache;
     }
 
+    if args.cache_max_entries != config.cache_max_entries && args.cache_max_entries.is_some() {
+        config.cache_max_entries = args.cache_max_entries;
+    }
+
+    if args.cache_miss_ttl != config.cache_miss_ttl && args.cache_miss_ttl.is_some() {
+        config.cache_miss_ttl = args.cache_miss_ttl;
+    }
+
+    if args.cache_hit_ttl != config.cache_hit_ttl && args.cache_hit_ttl.is_some() {
+        config.cache_hit_ttl = args.cache_hit_ttl;
+    }
+
+    if args.cache_check_interval != config.cache_check_interval
+        && args.cache_check_interval.is_some()
+    {
+        config.cache_check_interval = args.cache_check_interval;
+    }
+
+    if args.http_timeout != config.http_timeout && args.http_timeout.is_some() {
+        config.http_timeout = args.http_timeout;
+    }
+
+    if args.http_proxy != config.http_proxy && args.http_proxy.is_some() {
+        config.http_proxy = args.http_proxy;
+    }
+
     if args.list_players {
         config.list_players = args.list_players;
     }
 
+    if args.cover_providers != config.cover_providers && !args.cover_providers.is_empty() {
+        config.cover_providers = args.cover_providers;
+    }
+
+    if args.cover_normalization != config.cover_normalization
+        && !args.cover_normalization.is_empty()
+    {
+        config.cover_normalization = args.cover_normalization;
+    }
+
+    if args.cover_size != config.cover_size && args.cover_size.is_some() {
+        config.cover_size = args.cover_size;
+    }
+
+    if args.cover_upload != config.cover_upload && args.cover_upload.is_some() {
+        config.cover_upload = args.cover_upload;
+    }
+
+    if args.cover_upload_url != config.cover_upload_url && args.cover_upload_url.is_some() {
+        config.cover_upload_url = args.cover_upload_url;
+    }
+
+    if args.cover_upload_public_url != config.cover_upload_public_url
+        && args.cover_upload_public_url.is_some()
+    {
+        config.cover_upload_public_url = args.cover_upload_public_url;
+    }
+
     if args.allowlist != config.allowlist && args.allowlist.len() > 0 {
         config.allowlist = args.allowlist;
     }
 
+    if args.app_id != config.app_id && args.app_id.is_some() {
+        config.app_id = args.app_id;
+    }
+
+    if args.discord_socket != config.discord_socket && args.discord_socket.is_some() {
+        config.discord_socket = args.discord_socket;
+    }
+
+    if args.discord_broadcast {
+        config.discord_broadcast = args.discord_broadcast;
+    }
+
+    if args.discord_transport != config.discord_transport && args.discord_transport.is_some() {
+        config.discord_transport = args.discord_transport;
+    }
+
+    if args.discord_websocket_host != config.discord_websocket_host
+        && args.discord_websocket_host.is_some()
+    {
+        config.discord_websocket_host = args.discord_websocket_host;
+    }
+
+    if args.discord_websocket_port != config.discord_websocket_port
+        && args.discord_websocket_port.is_some()
+    {
+        config.discord_websocket_port = args.discord_websocket_port;
+    }
+
     if args.video_players != config.video_players && args.video_players.len() > 0 {
         config.video_players = args.video_players;
     }
@@ -291,5 +1061,18 @@ pub fn load_settings() -> Cli {
 
     config.suboptions = args.suboptions;
 
+    // Fill player profiles with the final global settings
+    let global_profile = config.global_profile();
+    for (name, profile) in config.players.iter_mut() {
+        if let Err(err) = profile.validate() {
+            println!("[config] players.{}: {}", name, err);
+            process::exit(utils::EXIT_CONFIG_ERROR);
+        }
+        profile.inherit(&global_profile);
+        if profile.activity_type.is_none() && config.video_players.contains(name) {
+            profile.activity_type = Some(String::from("watching"));
+        }
+    }
+
     return config;
 }
@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::str::Chars;
+
+use crate::error::Error;
+
+// Names that can be used in {placeholders}
+pub const FIELDS: &[&str] = &[
+    "title",
+    "artist",
+    "artists",
+    "album",
+    "album_artist",
+    "track_number",
+    "disc_number",
+    "genre",
+    "year",
+    "player",
+    "url",
+    "status",
+    "position",
+    "duration",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    Field(String),
+    Literal(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    /// {field|other_field|"literal"}, first available value is used
+    Placeholder(Vec<Source>),
+    /// [text {field}], rendered only if every placeholder inside has a value
+    Section(Vec<Node>),
+}
+
+// Activity text defined by the user, e.g. "{artist} — {album}[ ({year})]"
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, Error> {
+        let mut chars = template.chars();
+        let nodes = parse_nodes(&mut chars, false)?;
+        Ok(Template { nodes })
+    }
+
+    // Text before the first placeholder or section, the start of every rendered value
+    pub fn literal_prefix(&self) -> String {
+        self.nodes
+            .iter()
+            .map_while(|node| match node {
+                Node::Text(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // True if the first placeholder (after leading whitespace) tries this field first
+    pub fn starts_with_field(&self, field: &str) -> bool {
+        let first = self
+            .nodes
+            .iter()
+            .find(|node| !matches!(node, Node::Text(text) if text.trim().is_empty()));
+        match first {
+            Some(Node::Placeholder(sources)) => {
+                matches!(sources.first(), Some(Source::Field(name)) if name == field)
+            }
+            _ => false,
+        }
+    }
+
+    // Text outside of placeholders and sections, it's part of every rendered value
+    pub fn static_text(&self) -> String {
+        self.nodes
+            .iter()
+            .filter_map(|node| match node {
+                Node::Text(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Missing values are not in the map, placeholders without a value render as empty text
+    pub fn render(&self, values: &HashMap<&str, String>) -> String {
+        let mut output = String::new();
+        render_nodes(&self.nodes, values, &mut output);
+        output.trim().to_string()
+    }
+}
+
+fn parse_nodes(chars: &mut Chars, in_section: bool) -> Result<Vec<Node>, Error> {
+    let mut nodes = Vec::new();
+    let mut text = String::new();
+
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' => match chars.next() {
+                Some(escaped) => text.push(escaped),
+                None => return Err(template_error("trailing \"\\\"")),
+            },
+            '{' => {
+                if !text.is_empty() {
+                    nodes.push(Node::Text(std::mem::take(&mut text)));
+                }
+                nodes.push(parse_placeholder(chars)?);
+            }
+            '[' => {
+                if !text.is_empty() {
+                    nodes.push(Node::Text(std::mem::take(&mut text)));
+                }
+                nodes.push(Node::Section(parse_nodes(chars, true)?));
+            }
+            ']' if in_section => {
+                if !text.is_empty() {
+                    nodes.push(Node::Text(text));
+                }
+                return Ok(nodes);
+            }
+            ']' => return Err(template_error("unexpected \"]\"")),
+            '}' => return Err(template_error("unexpected \"}\"")),
+            _ => text.push(char),
+        }
+    }
+
+    if in_section {
+        return Err(template_error("missing \"]\""));
+    }
+    if !text.is_empty() {
+        nodes.push(Node::Text(text));
+    }
+    Ok(nodes)
+}
+
+fn parse_placeholder(chars: &mut Chars) -> Result<Node, Error> {
+    let mut content = String::new();
+    let mut in_literal = false;
+    loop {
+        match chars.next() {
+            Some('"') => {
+                in_literal = !in_literal;
+                content.push('"');
+            }
+            Some('}') if !in_literal => break,
+            Some(char) => content.push(char),
+            None => return Err(template_error("missing \"}\"")),
+        }
+    }
+
+    let mut sources = Vec::new();
+    for source in split_alternatives(&content) {
+        let source = source.trim();
+        if source.len() >= 2 && source.starts_with('"') && source.ends_with('"') {
+            sources.push(Source::Literal(source[1..source.len() - 1].to_string()));
+        } else if FIELDS.contains(&source) {
+            sources.push(Source::Field(source.to_string()));
+        } else if source.is_empty() {
+            return Err(template_error("empty placeholder"));
+        } else {
+            return Err(template_error(&format!(
+                "unknown field \"{}\", available fields: {}",
+                source,
+                FIELDS.join(", ")
+            )));
+        }
+    }
+    Ok(Node::Placeholder(sources))
+}
+
+// Split on "|" outside of quoted literals
+fn split_alternatives(content: &str) -> Vec<String> {
+    let mut alternatives = vec![String::new()];
+    let mut in_literal = false;
+    for char in content.chars() {
+        match char {
+            '"' => {
+                in_literal = !in_literal;
+                alternatives.last_mut().unwrap().push(char);
+            }
+            '|' if !in_literal => alternatives.push(String::new()),
+            _ => alternatives.last_mut().unwrap().push(char),
+        }
+    }
+    alternatives
+}
+
+fn template_error(message: &str) -> Error {
+    Error::Config(format!("Invalid template: {}", message))
+}
+
+// Returns false if any placeholder had no value
+fn render_nodes(nodes: &[Node], values: &HashMap<&str, String>, output: &mut String) -> bool {
+    let mut complete = true;
+    for node in nodes {
+        match node {
+            Node::Text(text) => output.push_str(text),
+            Node::Placeholder(sources) => {
+                let value = sources.iter().find_map(|source| match source {
+                    Source::Field(field) => values.get(field.as_str()).cloned(),
+                    Source::Literal(literal) => Some(literal.clone()),
+                });
+                match value {
+                    Some(value) => output.push_str(&value),
+                    None => complete = false,
+                }
+            }
+            Node::Section(nodes) => {
+                let mut section = String::new();
+                if render_nodes(nodes, values, &mut section) {
+                    output.push_str(&section);
+                }
+            }
+        }
+    }
+    complete
+}
+
+// Templates for every text field of the activity, None means the built-in format is used
+#[derive(Debug, Clone, Default)]
+pub struct Templates {
+    pub details: Option<Template>,
+    pub state: Option<Template>,
+    pub large_text: Option<Template>,
+    pub small_text: Option<Template>,
+}
+
+impl Templates {
+    pub fn parse(
+        details: &Option<String>,
+        state: &Option<String>,
+        large_text: &Option<String>,
+        small_text: &Option<String>,
+    ) -> Result<Self, Error> {
+        let parse = |name: &str, template: &Option<String>| match template {
+            Some(template) => Template::parse(template)
+                .map(Some)
+                .map_err(|err| match err {
+                    Error::Config(msg) => Error::Config(format!("{}_template: {}", name, msg)),
+                    err => err,
+                }),
+            None => Ok(None),
+        };
+
+        Ok(Templates {
+            details: parse("details", details)?,
+            state: parse("state", state)?,
+            large_text: parse("large_text", large_text)?,
+            small_text: parse("small_text", small_text)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> HashMap<&'static str, String> {
+        let mut values = HashMap::new();
+        values.insert("title", "Paranoid Android".to_string());
+        values.insert("artist", "Radiohead".to_string());
+        values.insert("album", "OK Computer".to_string());
+        values
+    }
+
+    fn render(template: &str) -> String {
+        Template::parse(template).unwrap().render(&values())
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(render("{artist} — {title}"), "Radiohead — Paranoid Android");
+    }
+
+    #[test]
+    fn fallbacks() {
+        assert_eq!(render("{album_artist|artist}"), "Radiohead");
+        assert_eq!(render("{year|\"unknown year\"}"), "unknown year");
+        assert_eq!(render("by: {genre}"), "by:");
+    }
+
+    #[test]
+    fn sections() {
+        assert_eq!(render("{album}[ ({year})]"), "OK Computer");
+        assert_eq!(
+            render("[{artist} — ]{title}"),
+            "Radiohead — Paranoid Android"
+        );
+        assert_eq!(render("[{album}[ ({year})]]"), "OK Computer");
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(render("\\[{artist}\\] \\{x\\}"), "[Radiohead] {x}");
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Template::parse("{tilte}").is_err());
+        assert!(Template::parse("{artist").is_err());
+        assert!(Template::parse("[{artist}").is_err());
+        assert!(Template::parse("{artist}]").is_err());
+        assert!(Template::parse("{}").is_err());
+    }
+}
@@ -1,9 +1,8 @@
-use discord_rich_presence::{DiscordIpc, DiscordIpcClient};
-use pickledb::PickleDb;
-use reqwest;
-use serde_json;
 use std::process;
 
+use crate::cover::{self, size::CoverSize};
+use crate::error::Error;
+
 // Use to print debug log if enabled with argument
 #[macro_export]
 macro_rules! debug_log {
@@ -14,207 +13,125 @@ macro_rules! debug_log {
     };
 }
 
-pub fn enable_service() {
+// Process exit codes of the subcommands (enable, disable, restart, lastfm, cache, --reset-config):
+// 0 - finished successfully
+// 1 - systemctl could not be executed (not installed or not in PATH)
+// 2 - systemctl ran but reported a failure, see its output above
+// 3 - config file could not be written
+// 4 - Last.fm login failed or API key and secret are missing
+// 5 - cache could not be opened, read or written, or the import file is invalid
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_SYSTEMCTL_NOT_FOUND: i32 = 1;
+pub const EXIT_SYSTEMCTL_FAILED: i32 = 2;
+pub const EXIT_CONFIG_ERROR: i32 = 3;
+pub const EXIT_LOGIN_FAILED: i32 = 4;
+pub const EXIT_CACHE_ERROR: i32 = 5;
+
+// Run "systemctl --user" with given args and exit with a documented code on failure
+fn systemctl(args: &[&str], success_msg: &str, failure_msg: &str) {
     match process::Command::new("systemctl")
         .arg("--user")
-        .arg("daemon-reload")
+        .args(args)
         .status()
     {
-        Ok(_) => println!("Reloaded user systemd services."),
-        Err(_) => {
-            println!("Failed to reload user systemd services.");
-            process::exit(1);
+        Ok(status) if status.success() => println!("{}", success_msg),
+        Ok(_) => {
+            println!("{}", failure_msg);
+            process::exit(EXIT_SYSTEMCTL_FAILED);
         }
-    }
-
-    match process::Command::new("systemctl")
-        .arg("--user")
-        .arg("enable")
-        .arg("mpris-discord-rpc.service")
-        .status()
-    {
-        Ok(_) => println!("Enabled user systemd service."),
-        Err(_) => {
-            println!("Failed to enable user systemd service.");
-            process::exit(1);
-        }
-    }
-
-    match process::Command::new("systemctl")
-        .arg("--user")
-        .arg("start")
-        .arg("mpris-discord-rpc.service")
-        .status()
-    {
-        Ok(_) => println!("Started user systemd service."),
-        Err(_) => {
-            println!("Failed to start user systemd service.");
-            process::exit(1);
+        Err(err) => {
+            println!("{} Could not run systemctl: {}", failure_msg, err);
+            process::exit(EXIT_SYSTEMCTL_NOT_FOUND);
         }
     }
+}
 
-    process::exit(0);
+pub fn enable_service() {
+    systemctl(
+        &["daemon-reload"],
+        "Reloaded user systemd services.",
+        "Failed to reload user systemd services.",
+    );
+    systemctl(
+        &["enable", "mpris-discord-rpc.service"],
+        "Enabled user systemd service.",
+        "Failed to enable user systemd service.",
+    );
+    systemctl(
+        &["start", "mpris-discord-rpc.service"],
+        "Started user systemd service.",
+        "Failed to start user systemd service.",
+    );
+    process::exit(EXIT_SUCCESS);
 }
 
 pub fn disable_service() {
-    match process::Command::new("systemctl")
-        .arg("--user")
-        .arg("stop")
-        .arg("mpris-discord-rpc.service")
-        .status()
-    {
-        Ok(_) => println!("Stopped user systemd service."),
-        Err(_) => {
-            println!("Failed to stop user systemd service.");
-            process::exit(1);
-        }
-    }
-
-    match process::Command::new("systemctl")
-        .arg("--user")
-        .arg("disable")
-        .arg("mpris-discord-rpc.service")
-        .status()
-    {
-        Ok(_) => println!("Disabled user systemd service."),
-        Err(_) => {
-            println!("Failed to disable user systemd service.");
-            process::exit(1);
-        }
-    }
-
-    process::exit(0);
+    systemctl(
+        &["stop", "mpris-discord-rpc.service"],
+        "Stopped user systemd service.",
+        "Failed to stop user systemd service.",
+    );
+    systemctl(
+        &["disable", "mpris-discord-rpc.service"],
+        "Disabled user systemd service.",
+        "Failed to disable user systemd service.",
+    );
+    process::exit(EXIT_SUCCESS);
 }
 
 pub fn restart_service() {
-    match process::Command::new("systemctl")
-        .arg("--user")
-        .arg("restart")
-        .arg("mpris-discord-rpc.service")
-        .status()
-    {
-        Ok(_) => println!("Restarted user systemd service."),
-        Err(_) => {
-            println!("Failed to restart user systemd service.");
-            process::exit(1);
-        }
-    }
-    process::exit(0);
-}
-
-pub fn clear_activity(is_activity_set: &mut bool, client: &mut DiscordIpcClient) {
-    if *is_activity_set {
-        let is_activity_cleared = client.clear_activity().is_ok();
-
-        if is_activity_cleared {
-            *is_activity_set = false;
-            return;
-        }
-
-        let is_reconnected = client.reconnect().is_ok();
-        if !is_reconnected {
-            return;
-        }
-
-        if client.clear_activity().is_ok() {
-            *is_activity_set = false;
-        }
-    }
+    systemctl(
+        &["restart", "mpris-discord-rpc.service"],
+        "Restarted user systemd service.",
+        "Failed to restart user systemd service.",
+    );
+    process::exit(EXIT_SUCCESS);
 }
 
-pub fn get_cover_url(
-    album_id: &str,
-    last_album_id: &str,
-    album: &str,
-    mut _cover_url: String,
-    cache_enabled: bool,
-    album_cache: &mut PickleDb,
-    artist: &str,
-    lastfm_api_key: &str,
+pub fn get_lastfm_avatar(
+    client: &reqwest::blocking::Client,
+    username: &str,
+    lastfm_api_key: Option<&str>,
+    size: CoverSize,
 ) -> String {
-    if album_id == last_album_id {
-        return _cover_url;
-    }
-
-    // If no album or Unknown Album
-    if album.eq("Unknown Album") {
-        println!("Missing album name or Unknown Album.");
-
-        return String::from("missing-cover");
-    }
-
-    // Load from cache if enabled
-    if cache_enabled {
-        let cache_url = if album_cache.exists(&album_id) {
-            match album_cache.get(&album_id) {
-                Some(url) => url,
-                None => String::new(),
-            }
-        } else {
-            String::new()
-        };
-
-        if (!cache_url.is_empty()) & (cache_url.len() > 5) {
-            return String::from(cache_url);
+    let lastfm_api_key = match lastfm_api_key {
+        Some(key) => key,
+        None => {
+            println!("[last.fm] No API key available, cannot fetch avatar.");
+            return String::new();
         }
-    }
-
-    let request_url = format!("http://ws.audioscrobbler.com/2.0/?method=album.getinfo&api_key={}&artist={}&album={}&autocorrect=0&format=json", lastfm_api_key, url_escape::encode_component(artist), url_escape::encode_component(album));
-
-    let mut url: String = match reqwest::blocking::get(request_url) {
-        Ok(res) => match res.json::<serde_json::Value>() {
-            Ok(data) => data["album"]["image"][3]["#text"].to_string(),
-            Err(_) => String::new(),
-        },
-        Err(_) => String::new(),
     };
 
-    if !url.is_empty() && (url.len() > 5) {
-        url.pop();
-        url.remove(0);
-        println!("[last.fm] fetched image link: {}", url);
-
-        // Save cover url to cache
-        if cache_enabled {
-            match album_cache.set(&album_id, &url) {
-                Ok(_) => {
-                    println!("[cache] saved image url for: {}.", album_id)
-                }
-                Err(_) => {
-                    println!("[cache] error, unable to write to cache file.")
-                }
-            }
+    match fetch_lastfm_avatar(client, username, lastfm_api_key, size) {
+        Ok(Some(url)) => {
+            println!("[last.fm] fetched avatar link: {}", url);
+            url
+        }
+        Ok(None) => String::new(),
+        Err(err) => {
+            println!("[last.fm] could not fetch avatar: {}", err);
+            String::new()
         }
-
-        return url;
     }
-
-    return String::from("missing-cover");
 }
 
-pub fn get_lastfm_avatar(username: &str, lastfm_api_key: &str) -> String {
+fn fetch_lastfm_avatar(
+    client: &reqwest::blocking::Client,
+    username: &str,
+    lastfm_api_key: &str,
+    size: CoverSize,
+) -> Result<Option<String>, Error> {
     let request_url = format!(
         "http://ws.audioscrobbler.com/2.0/?method=user.getinfo&api_key={}&user={}&format=json",
         lastfm_api_key,
         url_escape::encode_component(username)
     );
 
-    let mut url: String = match reqwest::blocking::get(request_url) {
-        Ok(res) => match res.json::<serde_json::Value>() {
-            Ok(data) => data["user"]["image"][3]["#text"].to_string(),
-            Err(_) => String::new(),
-        },
-        Err(_) => String::new(),
-    };
-
-    if !url.is_empty() && (url.len() > 15) {
-        url.pop();
-        url.remove(0);
-        println!("[last.fm] fetched avatar link: {}", url);
-        return url;
-    }
-
-    return String::new();
+    let data = client
+        .get(request_url)
+        .send()?
+        .json::<serde_json::Value>()?;
+    Ok(cover::lastfm::image(&data["user"], size))
 }
 
 pub fn sanitize_name(input: &str) -> String {